@@ -0,0 +1,854 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! A blocking version of the API client.
+//!
+//! The types in this module wrap their async counterparts and drive them on an
+//! internal single-threaded Tokio runtime, so requests are encoded and decoded
+//! exactly as in the async API. Because of that, the blocking clients must not
+//! be used from within an async runtime: doing so panics.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use openmls::{
+    framing::{MlsMessageIn, MlsMessageOut},
+    key_packages::{KeyPackage, KeyPackageIn},
+    prelude::{tls_codec, OpenMlsProvider, Signer},
+    treesync::{RatchetTree, RatchetTreeIn},
+};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{
+    errors::{
+        AddMemberError, CapabilitiesError, CreateGroupError, DeleteClientError, DeleteGroupError,
+        DeliveryReceiptError, DistributeGroupMessageError, DistributeWelcomeError,
+        DrainMessagesError, FetchGroupInfoError, FetchKeyPackageError, FetchMessagesError,
+        ImportStateError, ListClientsError, ListGroupsError, LogoutError, MyGroupsError,
+        ProvisionTokenError, PurgeQueueError, QueueDepthError, RegisterClientError,
+        RestoreGroupError, SendMessageError, UpdateGroupInfoError, UploadKeyPackagesError,
+        WhoAmIError,
+    },
+    session::{Session, SessionStore, SessionStoreError},
+    stream::AdaptivePollConfig,
+    teardown::{TeardownOptions, TeardownReport},
+    AssistedMessageOut, AuthToken, ClaimedKeyPackage, ClientInfo, ClientState, ConnectionState,
+    DeliveryReceipt, DistributionReport, DsCapabilities, DsClientId, DsGroupId, DsUrl,
+    GroupedMessages, KeyPackageUploadSummary, LastResortKeyPackage, ListClientsPage,
+    MessagePriority, MessageRef, MlsMessageKind, NonEmpty, OneTimeKeyPackage, QueuedMessage,
+    UploadHandle, UploadProgress,
+};
+
+fn new_runtime() -> std::io::Result<Runtime> {
+    Builder::new_current_thread().enable_all().build()
+}
+
+/// Blocking version of [`crate::UnregisteredApiClient`].
+pub struct UnregisteredApiClient {
+    inner: crate::UnregisteredApiClient,
+    runtime: Arc<Runtime>,
+}
+
+impl UnregisteredApiClient {
+    /// Create a new API client that is not yet registered with the DS.
+    pub fn new(ds_url: DsUrl) -> std::io::Result<Self> {
+        Self::from_async(crate::UnregisteredApiClient::new(ds_url))
+    }
+
+    /// Wrap an async [`crate::UnregisteredApiClient`], e.g. one created via
+    /// [`crate::UnregisteredApiClient::builder`].
+    pub fn from_async(inner: crate::UnregisteredApiClient) -> std::io::Result<Self> {
+        let runtime = Arc::new(new_runtime()?);
+        Ok(Self { inner, runtime })
+    }
+
+    /// See [`crate::UnregisteredApiClient::warmup`].
+    pub fn warmup(&self) -> Result<(), SendMessageError> {
+        self.runtime.block_on(self.inner.warmup())
+    }
+
+    /// See [`crate::UnregisteredApiClient::capabilities`].
+    pub fn capabilities(&self) -> Result<DsCapabilities, CapabilitiesError> {
+        self.runtime.block_on(self.inner.capabilities())
+    }
+
+    /// See [`crate::UnregisteredApiClient::register`].
+    pub fn register(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<ApiClient, RegisterClientError> {
+        let inner = self
+            .runtime
+            .block_on(self.inner.register(key_packages, last_resort_key_package))?;
+        Ok(ApiClient {
+            inner,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// See [`crate::UnregisteredApiClient::register_last_resort_only`].
+    pub fn register_last_resort_only(
+        &self,
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<ApiClient, RegisterClientError> {
+        let inner = self.runtime.block_on(
+            self.inner
+                .register_last_resort_only(last_resort_key_package),
+        )?;
+        Ok(ApiClient {
+            inner,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// See [`crate::UnregisteredApiClient::derive_client_id`].
+    pub fn derive_client_id(
+        &self,
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<DsClientId, RegisterClientError> {
+        self.inner.derive_client_id(last_resort_key_package)
+    }
+
+    /// See [`crate::UnregisteredApiClient::register_or_rotate`].
+    pub fn register_or_rotate(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        signer: &impl Signer,
+    ) -> Result<ApiClient, RegisterClientError> {
+        let inner = self.runtime.block_on(self.inner.register_or_rotate(
+            key_packages,
+            last_resort_key_package,
+            signer,
+        ))?;
+        Ok(ApiClient {
+            inner,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// See [`crate::UnregisteredApiClient::register_verified`].
+    pub fn register_verified(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<ApiClient, RegisterClientError> {
+        let inner = self.runtime.block_on(
+            self.inner
+                .register_verified(key_packages, last_resort_key_package),
+        )?;
+        Ok(ApiClient {
+            inner,
+            runtime: self.runtime.clone(),
+        })
+    }
+
+    /// See [`crate::UnregisteredApiClient::register_many`].
+    pub fn register_many(
+        &self,
+        registrations: Vec<(Vec<OneTimeKeyPackage>, LastResortKeyPackage)>,
+    ) -> Vec<Result<ApiClient, RegisterClientError>> {
+        self.runtime
+            .block_on(self.inner.register_many(registrations))
+            .into_iter()
+            .map(|result| {
+                result.map(|inner| ApiClient {
+                    inner,
+                    runtime: self.runtime.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// See [`crate::UnregisteredApiClient::with_session`].
+    pub fn with_session(&self, session: Session) -> ApiClient {
+        ApiClient {
+            inner: self.inner.with_session(session),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// See [`crate::UnregisteredApiClient::resume`].
+    pub fn resume(
+        &self,
+        store: Arc<dyn SessionStore>,
+    ) -> Result<Option<ApiClient>, SessionStoreError> {
+        Ok(self.inner.resume(store)?.map(|inner| ApiClient {
+            inner,
+            runtime: self.runtime.clone(),
+        }))
+    }
+}
+
+/// Blocking version of [`crate::ApiClient`].
+pub struct ApiClient {
+    inner: crate::ApiClient,
+    runtime: Arc<Runtime>,
+}
+
+impl ApiClient {
+    /// See [`crate::ApiClient::upload_key_packages`].
+    pub fn upload_key_packages(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        self.runtime.block_on(
+            self.inner
+                .upload_key_packages(key_packages, last_resort_key_package),
+        )
+    }
+
+    /// See [`crate::ApiClient::upload_key_packages_with_progress`].
+    pub fn upload_key_packages_with_progress(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        progress: UploadProgress,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        self.runtime
+            .block_on(self.inner.upload_key_packages_with_progress(
+                key_packages,
+                last_resort_key_package,
+                progress,
+            ))
+    }
+
+    /// See [`crate::ApiClient::start_upload`].
+    pub fn start_upload(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> UploadHandle {
+        self.inner
+            .start_upload(key_packages, last_resort_key_package)
+    }
+
+    /// See [`crate::ApiClient::resume_upload`].
+    pub fn resume_upload(
+        &mut self,
+        handle: &mut UploadHandle,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        self.runtime.block_on(self.inner.resume_upload(handle))
+    }
+
+    /// See [`crate::ApiClient::upload_key_packages_durable`].
+    pub fn upload_key_packages_durable(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        self.runtime.block_on(
+            self.inner
+                .upload_key_packages_durable(key_packages, last_resort_key_package),
+        )
+    }
+
+    /// See [`crate::ApiClient::reset_key_packages`].
+    pub fn reset_key_packages(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<u32, UploadKeyPackagesError> {
+        self.runtime.block_on(
+            self.inner
+                .reset_key_packages(key_packages, last_resort_key_package),
+        )
+    }
+
+    /// See [`crate::ApiClient::list_clients`].
+    pub fn list_clients(&self) -> Result<Vec<DsClientId>, ListClientsError> {
+        self.runtime.block_on(self.inner.list_clients())
+    }
+
+    /// See [`crate::ApiClient::list_clients_page`].
+    pub fn list_clients_page(
+        &self,
+        offset: u32,
+        limit: u32,
+        prefix: Option<&[u8]>,
+    ) -> Result<ListClientsPage, ListClientsError> {
+        self.runtime
+            .block_on(self.inner.list_clients_page(offset, limit, prefix))
+    }
+
+    /// See [`crate::ApiClient::list_groups`].
+    pub fn list_groups(
+        &self,
+        offset: u64,
+        limit: u32,
+    ) -> Result<(Vec<DsGroupId>, u64), ListGroupsError> {
+        self.runtime.block_on(self.inner.list_groups(offset, limit))
+    }
+
+    /// See [`crate::ApiClient::create_group`].
+    pub fn create_group(
+        &self,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+    ) -> Result<(), CreateGroupError> {
+        self.runtime
+            .block_on(self.inner.create_group(group_info, ratchet_tree))
+    }
+
+    /// See [`crate::ApiClient::create_group_with_progress`].
+    pub fn create_group_with_progress(
+        &self,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        progress: UploadProgress,
+    ) -> Result<(), CreateGroupError> {
+        self.runtime.block_on(self.inner.create_group_with_progress(
+            group_info,
+            ratchet_tree,
+            progress,
+        ))
+    }
+
+    /// See [`crate::ApiClient::create_group_checked`].
+    pub fn create_group_checked(
+        &self,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+    ) -> Result<(), CreateGroupError> {
+        self.runtime
+            .block_on(self.inner.create_group_checked(group_info, ratchet_tree))
+    }
+
+    /// See [`crate::ApiClient::update_group_info`].
+    pub fn update_group_info(
+        &self,
+        group_id: DsGroupId,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+    ) -> Result<(), UpdateGroupInfoError> {
+        self.runtime.block_on(
+            self.inner
+                .update_group_info(group_id, group_info, ratchet_tree),
+        )
+    }
+
+    /// See [`crate::ApiClient::update_group_info_if_epoch`].
+    pub fn update_group_info_if_epoch(
+        &self,
+        group_id: DsGroupId,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        expected_epoch: u64,
+    ) -> Result<(), UpdateGroupInfoError> {
+        self.runtime.block_on(self.inner.update_group_info_if_epoch(
+            group_id,
+            group_info,
+            ratchet_tree,
+            expected_epoch,
+        ))
+    }
+
+    /// See [`crate::ApiClient::update_group_info_delta`].
+    pub fn update_group_info_delta(
+        &self,
+        group_id: DsGroupId,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        base_epoch: u64,
+        base_tree: &RatchetTree,
+    ) -> Result<(), UpdateGroupInfoError> {
+        self.runtime.block_on(self.inner.update_group_info_delta(
+            group_id,
+            group_info,
+            ratchet_tree,
+            base_epoch,
+            base_tree,
+        ))
+    }
+
+    /// See [`crate::ApiClient::fetch_group_info`].
+    pub fn fetch_group_info(
+        &self,
+        group_id: DsGroupId,
+    ) -> Result<Option<(MlsMessageIn, RatchetTreeIn)>, FetchGroupInfoError> {
+        self.runtime.block_on(self.inner.fetch_group_info(group_id))
+    }
+
+    /// See [`crate::ApiClient::fetch_key_package`].
+    pub fn fetch_key_package(
+        &self,
+        client_id: DsClientId,
+    ) -> Result<Option<KeyPackageIn>, FetchKeyPackageError> {
+        self.runtime
+            .block_on(self.inner.fetch_key_package(client_id))
+    }
+
+    /// See [`crate::ApiClient::claim_key_package`].
+    pub fn claim_key_package(
+        &self,
+        client_id: DsClientId,
+    ) -> Result<Option<ClaimedKeyPackage>, FetchKeyPackageError> {
+        self.runtime
+            .block_on(self.inner.claim_key_package(client_id))
+    }
+
+    /// See [`crate::ApiClient::fetch_key_package_fresh`].
+    pub fn fetch_key_package_fresh(
+        &self,
+        client_id: DsClientId,
+        min_remaining: Duration,
+    ) -> Result<Option<KeyPackageIn>, FetchKeyPackageError> {
+        self.runtime
+            .block_on(self.inner.fetch_key_package_fresh(client_id, min_remaining))
+    }
+
+    /// See [`crate::ApiClient::fetch_key_packages`].
+    pub fn fetch_key_packages(
+        &self,
+        client_ids: &NonEmpty<DsClientId>,
+    ) -> Vec<(
+        DsClientId,
+        Result<Option<KeyPackageIn>, FetchKeyPackageError>,
+    )> {
+        self.runtime
+            .block_on(self.inner.fetch_key_packages(client_ids))
+    }
+
+    /// See [`crate::ApiClient::fetch_key_package_validated`].
+    pub fn fetch_key_package_validated(
+        &self,
+        client_id: DsClientId,
+        provider: &impl OpenMlsProvider,
+    ) -> Result<Option<KeyPackage>, FetchKeyPackageError> {
+        self.runtime
+            .block_on(self.inner.fetch_key_package_validated(client_id, provider))
+    }
+
+    /// See [`crate::ApiClient::distribute_group_message`].
+    pub fn distribute_group_message(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.runtime.block_on(
+            self.inner
+                .distribute_group_message(message, group_info_option),
+        )
+    }
+
+    /// See [`crate::ApiClient::distribute_group_messages`].
+    pub fn distribute_group_messages(
+        &self,
+        items: &[(MlsMessageOut, Option<MlsMessageOut>)],
+    ) -> Result<Vec<Result<(), DistributeGroupMessageError>>, DistributeGroupMessageError> {
+        self.runtime
+            .block_on(self.inner.distribute_group_messages(items))
+    }
+
+    /// See [`crate::ApiClient::distribute_group_message_if_epoch`].
+    pub fn distribute_group_message_if_epoch(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        expected_epoch: u64,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.runtime
+            .block_on(self.inner.distribute_group_message_if_epoch(
+                message,
+                group_info_option,
+                expected_epoch,
+            ))
+    }
+
+    /// See [`crate::ApiClient::distribute_group_message_with_metadata`].
+    pub fn distribute_group_message_with_metadata(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        metadata: &[u8],
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.runtime
+            .block_on(self.inner.distribute_group_message_with_metadata(
+                message,
+                group_info_option,
+                metadata,
+            ))
+    }
+
+    /// See [`crate::ApiClient::distribute_group_message_with_priority`].
+    pub fn distribute_group_message_with_priority(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        priority: MessagePriority,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.runtime
+            .block_on(self.inner.distribute_group_message_with_priority(
+                message,
+                group_info_option,
+                priority,
+            ))
+    }
+
+    /// See [`crate::ApiClient::distribute_group_message_to`].
+    pub fn distribute_group_message_to(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        recipients: &NonEmpty<DsClientId>,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.runtime
+            .block_on(self.inner.distribute_group_message_to(
+                message,
+                group_info_option,
+                recipients,
+            ))
+    }
+
+    /// See [`crate::ApiClient::distribute_assisted_message`].
+    pub fn distribute_assisted_message(
+        &self,
+        message: &AssistedMessageOut,
+        priority: MessagePriority,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.runtime
+            .block_on(self.inner.distribute_assisted_message(message, priority))
+    }
+
+    /// See [`crate::ApiClient::distribute_welcome`].
+    pub fn distribute_welcome(
+        &self,
+        message: &MlsMessageOut,
+    ) -> Result<(), DistributeWelcomeError> {
+        self.runtime
+            .block_on(self.inner.distribute_welcome(message))
+    }
+
+    /// See [`crate::ApiClient::distribute_welcome_to`].
+    pub fn distribute_welcome_to(
+        &self,
+        message: &MlsMessageOut,
+        recipients: &NonEmpty<DsClientId>,
+    ) -> Result<(), DistributeWelcomeError> {
+        self.runtime
+            .block_on(self.inner.distribute_welcome_to(message, recipients))
+    }
+
+    /// See [`crate::ApiClient::add_member`].
+    pub fn add_member(
+        &self,
+        group_id: DsGroupId,
+        member: DsClientId,
+        commit: &MlsMessageOut,
+        group_info: &MlsMessageOut,
+        welcome: &MlsMessageOut,
+    ) -> Result<DistributionReport, AddMemberError> {
+        self.runtime.block_on(
+            self.inner
+                .add_member(group_id, member, commit, group_info, welcome),
+        )
+    }
+
+    /// See [`crate::ApiClient::rotate_auth_token`].
+    pub fn rotate_auth_token(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<(), RegisterClientError> {
+        self.runtime.block_on(
+            self.inner
+                .rotate_auth_token(key_packages, last_resort_key_package),
+        )
+    }
+
+    /// See [`crate::ApiClient::rotate_auth_token_signed`].
+    pub fn rotate_auth_token_signed(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        signer: &impl Signer,
+    ) -> Result<(), RegisterClientError> {
+        self.runtime.block_on(self.inner.rotate_auth_token_signed(
+            key_packages,
+            last_resort_key_package,
+            signer,
+        ))
+    }
+
+    /// See [`crate::ApiClient::fetch_messages`].
+    pub fn fetch_messages(&mut self) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        self.runtime.block_on(self.inner.fetch_messages())
+    }
+
+    /// See [`crate::ApiClient::poll_with_adaptive_backoff`].
+    pub fn poll_with_adaptive_backoff(
+        &mut self,
+        config: &AdaptivePollConfig,
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.poll_with_adaptive_backoff(config))
+    }
+
+    /// See [`crate::ApiClient::fetch_messages_blocking`].
+    pub fn fetch_messages_blocking(
+        &mut self,
+        max_wait: Duration,
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_messages_blocking(max_wait))
+    }
+
+    /// See [`crate::ApiClient::fetch_messages_for_group`].
+    pub fn fetch_messages_for_group(
+        &mut self,
+        group_id: DsGroupId,
+        limit: u32,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_messages_for_group(group_id, limit))
+    }
+
+    /// See [`crate::ApiClient::fetch_messages_grouped`].
+    pub fn fetch_messages_grouped(
+        &mut self,
+        limit: u32,
+    ) -> Result<GroupedMessages, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_messages_grouped(limit))
+    }
+
+    /// See [`crate::ApiClient::group_cursor`].
+    pub fn group_cursor(&self, group_id: &DsGroupId) -> u64 {
+        self.inner.group_cursor(group_id)
+    }
+
+    /// See [`crate::ApiClient::fetch_messages_filtered`].
+    pub fn fetch_messages_filtered(
+        &mut self,
+        kinds: &[MlsMessageKind],
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_messages_filtered(kinds))
+    }
+
+    /// See [`crate::ApiClient::fetch_messages_excluding_own`].
+    pub fn fetch_messages_excluding_own(
+        &mut self,
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_messages_excluding_own())
+    }
+
+    /// See [`crate::ApiClient::fetch_messages_lenient`].
+    pub fn fetch_messages_lenient(
+        &mut self,
+    ) -> Result<Vec<Result<MlsMessageIn, tls_codec::Error>>, FetchMessagesError> {
+        self.runtime.block_on(self.inner.fetch_messages_lenient())
+    }
+
+    /// See [`crate::ApiClient::fetch_queued_messages`].
+    pub fn fetch_queued_messages(&mut self) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        self.runtime.block_on(self.inner.fetch_queued_messages())
+    }
+
+    /// See [`crate::ApiClient::fetch_messages_since`].
+    pub fn fetch_messages_since(
+        &mut self,
+        since: SystemTime,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_messages_since(since))
+    }
+
+    /// See [`crate::ApiClient::drain_messages_with_checkpoint`].
+    pub fn drain_messages_with_checkpoint<E>(
+        &mut self,
+        checkpoint: impl FnMut(u64, &[MlsMessageIn]) -> Result<(), E>,
+    ) -> Result<usize, DrainMessagesError<E>> {
+        self.runtime
+            .block_on(self.inner.drain_messages_with_checkpoint(checkpoint))
+    }
+
+    /// See [`crate::ApiClient::queue_depth`].
+    pub fn queue_depth(&self) -> Result<u64, QueueDepthError> {
+        self.runtime.block_on(self.inner.queue_depth())
+    }
+
+    /// See [`crate::ApiClient::purge_queue`].
+    pub fn purge_queue(&mut self) -> Result<u64, PurgeQueueError> {
+        self.runtime.block_on(self.inner.purge_queue())
+    }
+
+    /// See [`crate::ApiClient::fetch_message_at`].
+    pub fn fetch_message_at(
+        &self,
+        sequence_number: u64,
+    ) -> Result<Option<MlsMessageIn>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_message_at(sequence_number))
+    }
+
+    /// See [`crate::ApiClient::fetch_message_range`].
+    pub fn fetch_message_range(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        self.runtime
+            .block_on(self.inner.fetch_message_range(from, to))
+    }
+
+    /// See [`crate::ApiClient::delete_group`].
+    pub fn delete_group(&self, group_id: DsGroupId) -> Result<(), DeleteGroupError> {
+        self.runtime.block_on(self.inner.delete_group(group_id))
+    }
+
+    /// See [`crate::ApiClient::delete_group_idempotent`].
+    pub fn delete_group_idempotent(&self, group_id: DsGroupId) -> Result<(), DeleteGroupError> {
+        self.runtime
+            .block_on(self.inner.delete_group_idempotent(group_id))
+    }
+
+    /// See [`crate::ApiClient::delete_group_soft`].
+    pub fn delete_group_soft(
+        &self,
+        group_id: DsGroupId,
+        retain: Duration,
+    ) -> Result<(), DeleteGroupError> {
+        self.runtime
+            .block_on(self.inner.delete_group_soft(group_id, retain))
+    }
+
+    /// See [`crate::ApiClient::restore_group`].
+    pub fn restore_group(&self, group_id: DsGroupId) -> Result<(), RestoreGroupError> {
+        self.runtime.block_on(self.inner.restore_group(group_id))
+    }
+
+    /// See [`crate::ApiClient::delete_client`].
+    pub fn delete_client(&self, client_id: DsClientId) -> Result<(), DeleteClientError> {
+        self.runtime.block_on(self.inner.delete_client(client_id))
+    }
+
+    /// See [`crate::ApiClient::provision_additional_token`].
+    pub fn provision_additional_token(&self) -> Result<AuthToken, ProvisionTokenError> {
+        self.runtime
+            .block_on(self.inner.provision_additional_token())
+    }
+
+    /// See [`crate::ApiClient::logout`].
+    pub fn logout(self) -> Result<(), LogoutError> {
+        self.runtime.block_on(self.inner.logout())
+    }
+
+    /// See [`crate::ApiClient::teardown`].
+    pub fn teardown(self, options: TeardownOptions) -> TeardownReport {
+        self.runtime.block_on(self.inner.teardown(options))
+    }
+
+    /// See [`crate::ApiClient::delete_groups`].
+    pub fn delete_groups(
+        &self,
+        group_ids: &NonEmpty<DsGroupId>,
+    ) -> Result<Vec<Result<(), DeleteGroupError>>, DeleteGroupError> {
+        self.runtime.block_on(self.inner.delete_groups(group_ids))
+    }
+
+    /// See [`crate::ApiClient::delete_clients`].
+    pub fn delete_clients(
+        &self,
+        client_ids: &NonEmpty<DsClientId>,
+    ) -> Result<Vec<Result<(), DeleteClientError>>, DeleteClientError> {
+        self.runtime.block_on(self.inner.delete_clients(client_ids))
+    }
+
+    /// See [`crate::ApiClient::whoami`].
+    pub fn whoami(&self) -> Result<ClientInfo, WhoAmIError> {
+        self.runtime.block_on(self.inner.whoami())
+    }
+
+    /// See [`crate::ApiClient::validate_credentials`].
+    pub fn validate_credentials(&self) -> Result<bool, WhoAmIError> {
+        self.runtime.block_on(self.inner.validate_credentials())
+    }
+
+    /// See [`crate::ApiClient::my_groups`].
+    pub fn my_groups(&self) -> Result<Vec<DsGroupId>, MyGroupsError> {
+        self.runtime.block_on(self.inner.my_groups())
+    }
+
+    /// See [`crate::ApiClient::send_delivery_receipt`].
+    pub fn send_delivery_receipt(
+        &self,
+        group_id: DsGroupId,
+        sequence_number: u64,
+    ) -> Result<(), DeliveryReceiptError> {
+        self.runtime
+            .block_on(self.inner.send_delivery_receipt(group_id, sequence_number))
+    }
+
+    /// See [`crate::ApiClient::fetch_delivery_receipts`].
+    pub fn fetch_delivery_receipts(
+        &self,
+        group_id: DsGroupId,
+        message_ref: MessageRef,
+    ) -> Result<Vec<DeliveryReceipt>, DeliveryReceiptError> {
+        self.runtime
+            .block_on(self.inner.fetch_delivery_receipts(group_id, message_ref))
+    }
+
+    /// See [`crate::ApiClient::fetch_stats`].
+    #[cfg(feature = "stats")]
+    pub fn fetch_stats(&self) -> crate::stats::FetchStats {
+        self.inner.fetch_stats()
+    }
+
+    /// See [`crate::ApiClient::reset_fetch_stats`].
+    #[cfg(feature = "stats")]
+    pub fn reset_fetch_stats(&self) {
+        self.inner.reset_fetch_stats()
+    }
+
+    /// See [`crate::ApiClient::export_state`].
+    pub fn export_state(&self) -> ClientState {
+        self.inner.export_state()
+    }
+
+    /// See [`crate::ApiClient::import_state`].
+    pub fn import_state(
+        client: &UnregisteredApiClient,
+        state: ClientState,
+    ) -> Result<ApiClient, ImportStateError> {
+        Ok(ApiClient {
+            inner: crate::ApiClient::import_state(&client.inner, state)?,
+            runtime: client.runtime.clone(),
+        })
+    }
+
+    /// See [`crate::ApiClient::set_session_store`].
+    pub fn set_session_store(
+        &mut self,
+        store: Arc<dyn SessionStore>,
+    ) -> Result<(), SessionStoreError> {
+        self.inner.set_session_store(store)
+    }
+
+    /// See [`crate::ApiClient::reset_connection`].
+    pub fn reset_connection(&mut self) -> Result<(), reqwest::Error> {
+        self.inner.reset_connection()
+    }
+
+    /// Get the client ID of this client.
+    pub fn client_id(&self) -> DsClientId {
+        self.inner.client_id()
+    }
+
+    /// See [`crate::ApiClient::ds_url`].
+    pub fn ds_url(&self) -> reqwest::Url {
+        self.inner.ds_url()
+    }
+
+    /// See [`crate::ApiClient::connection_state`].
+    pub fn connection_state(&self) -> tokio::sync::watch::Receiver<ConnectionState> {
+        self.inner.connection_state()
+    }
+}
@@ -0,0 +1,390 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! A builder for configuring the HTTP client used to talk to the DS.
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    redirect::Policy,
+    tls::Version,
+    Url,
+};
+use tokio::sync::Semaphore;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    connection::{ConnectionOptions, DsConnection, ResponseValidationError},
+    rate_limit::RateLimiter,
+    requests::{DsResponse, Operation},
+    transport::Transport,
+    UnregisteredApiClient,
+};
+
+/// Builder for an [`UnregisteredApiClient`]. Obtain one via
+/// [`UnregisteredApiClient::builder`].
+pub struct ApiClientBuilder {
+    ds_url: Url,
+    options: ConnectionOptions,
+    config: ClientConfig,
+}
+
+/// Client-side settings that don't concern the HTTP client.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientConfig {
+    pub(crate) strict_uuid: bool,
+    pub(crate) max_key_packages_per_request: usize,
+    pub(crate) allow_last_resort_only: bool,
+    pub(crate) batch_concurrency: usize,
+    pub(crate) verify_sender: bool,
+    pub(crate) max_buffer_bytes: Option<usize>,
+    pub(crate) server_assigned_client_ids: bool,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            strict_uuid: false,
+            max_key_packages_per_request: DEFAULT_MAX_KEY_PACKAGES_PER_REQUEST,
+            allow_last_resort_only: false,
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+            verify_sender: true,
+            max_buffer_bytes: None,
+            server_assigned_client_ids: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// Default for [`ApiClientBuilder::max_key_packages_per_request`].
+pub const DEFAULT_MAX_KEY_PACKAGES_PER_REQUEST: usize = 256;
+
+/// Default for [`ApiClientBuilder::batch_concurrency`].
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+
+/// Default for [`ApiClientBuilder::user_agent`].
+pub const DEFAULT_USER_AGENT: &str = concat!("minimal-ds-client/", env!("CARGO_PKG_VERSION"));
+
+impl ApiClientBuilder {
+    pub(crate) fn new(ds_url: Url) -> Self {
+        Self {
+            ds_url,
+            options: ConnectionOptions::default(),
+            config: ClientConfig::default(),
+        }
+    }
+
+    /// Send requests for `operation` to `path`, appended to the path of the DS
+    /// URL, instead of to the DS URL itself. By default, all operations are
+    /// sent to the DS URL.
+    pub fn route(mut self, operation: Operation, path: &str) -> Self {
+        let mut url = self.ds_url.clone();
+        let path = format!(
+            "{}/{}",
+            url.path().trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        url.set_path(&path);
+        self.options.routes.insert(operation, url);
+        self
+    }
+
+    /// Add a header that is included in every request sent to the DS, e.g.
+    /// an `Authorization` header required by a gateway in front of the DS.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.options.http.headers.insert(name, value);
+        self
+    }
+
+    /// Add a set of headers that are included in every request sent to the
+    /// DS.
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.options.http.headers.extend(headers);
+        self
+    }
+
+    /// Set a callback that is called before every request and whose headers
+    /// are added to the request. Headers returned by the callback override
+    /// static headers of the same name.
+    pub fn header_provider(
+        mut self,
+        header_provider: impl Fn() -> HeaderMap + Send + Sync + 'static,
+    ) -> Self {
+        self.options.header_provider = Some(Arc::new(header_provider));
+        self
+    }
+
+    /// Set a callback that is called with the round-trip time of each request
+    /// to the DS. The time is measured from sending the serialized request
+    /// until the response headers were received, so it excludes local
+    /// serialization and deserialization.
+    pub fn response_time_observer(
+        mut self,
+        response_time_observer: impl Fn(Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.options.response_time_observer = Some(Arc::new(response_time_observer));
+        self
+    }
+
+    /// Set a callback that checks every response of the DS after it was
+    /// deserialized and before it is returned, and may reject it, see
+    /// [`ResponseValidator`](crate::ResponseValidator).
+    pub fn response_validator(
+        mut self,
+        response_validator: impl Fn(Operation, &DsResponse) -> Result<(), ResponseValidationError>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.options.response_validator = Some(Arc::new(response_validator));
+        self
+    }
+
+    /// Set the maximum number of idle connections per host kept in the
+    /// connection pool. Defaults to no limit. When managing many clients
+    /// against the same DS, a limit of a few dozen avoids holding on to
+    /// sockets that are rarely reused.
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.options.http.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Set how long idle connections are kept in the connection pool. Pass
+    /// `None` to keep them indefinitely. Defaults to 90 seconds.
+    ///
+    /// Expired connections are closed proactively by a background task of the
+    /// connection pool, not only when the pool is next used, so a short
+    /// timeout frees the sockets of a shared client during quiet periods
+    /// without further action.
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.http.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the interval of TCP keep-alive probes. Pass `None` to disable them,
+    /// which is the default. Enabling them helps to keep long-lived idle
+    /// connections through NATs and load balancers.
+    pub fn tcp_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.options.http.tcp_keepalive = interval;
+        self
+    }
+
+    /// Set the `User-Agent` header sent with every request, e.g.
+    /// `"myapp/1.2.3"`, so that the DS operator can identify the client
+    /// software. Defaults to [`DEFAULT_USER_AGENT`]. An invalid header value
+    /// makes [`Self::build`] fail.
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.options.http.user_agent = user_agent.to_owned();
+        self
+    }
+
+    /// Bind outgoing connections to the given local IPv4 or IPv6 address,
+    /// e.g. to choose the network interface on a multi-homed host. Defaults to
+    /// letting the operating system choose.
+    pub fn local_address(mut self, address: impl Into<Option<IpAddr>>) -> Self {
+        self.options.http.local_address = address.into();
+        self
+    }
+
+    /// Reject TLS connections to the DS that negotiate a protocol version
+    /// below `version`, e.g. [`Version::TLS_1_3`] to prevent downgrades to
+    /// TLS 1.2. By default, the minimum version of the TLS backend applies,
+    /// which depends on the backend and platform. The default native TLS
+    /// backend can't enforce TLS 1.3, so requiring it makes [`Self::build`]
+    /// fail unless `reqwest` is built with a `rustls` backend. Cipher suites
+    /// are always chosen by the TLS backend.
+    pub fn min_tls_version(mut self, version: Version) -> Self {
+        self.options.http.min_tls_version = Some(version);
+        self
+    }
+
+    /// Talk HTTP/2 to the DS right away instead of negotiating the protocol,
+    /// so that all requests of clients created from the resulting
+    /// [`UnregisteredApiClient`] are multiplexed over one connection. Only
+    /// use this if the DS supports HTTP/2, e.g. for a DS reached via `http`,
+    /// where protocol negotiation via TLS isn't available.
+    pub fn http2_prior_knowledge(mut self) -> Self {
+        self.options.http.http2_prior_knowledge = true;
+        self
+    }
+
+    /// Adapt the HTTP/2 flow control windows to the measured bandwidth and
+    /// latency, overriding the window sizes set via
+    /// [`Self::http2_initial_stream_window_size`] and
+    /// [`Self::http2_initial_connection_window_size`]. Disabled by default.
+    pub fn http2_adaptive_window(mut self, enabled: bool) -> Self {
+        self.options.http.http2_adaptive_window = Some(enabled);
+        self
+    }
+
+    /// Set the initial HTTP/2 flow control window of each stream, i.e. of
+    /// each request, in bytes. Defaults to the default of `reqwest`.
+    pub fn http2_initial_stream_window_size(mut self, size: u32) -> Self {
+        self.options.http.http2_initial_stream_window_size = Some(size);
+        self
+    }
+
+    /// Set the initial HTTP/2 flow control window of the connection shared
+    /// by all concurrent requests, in bytes. Defaults to the default of
+    /// `reqwest`. The number of concurrent streams is limited by the DS.
+    pub fn http2_initial_connection_window_size(mut self, size: u32) -> Self {
+        self.options.http.http2_initial_connection_window_size = Some(size);
+        self
+    }
+
+    /// Set the policy for following HTTP redirects. Defaults to
+    /// [`Policy::none`], such that a 3xx response of the DS fails the request
+    /// with
+    /// [`SendMessageError::NetworkError`](crate::errors::SendMessageError::NetworkError).
+    /// Following redirects means
+    /// re-sending requests, including the client credentials they contain, to
+    /// whichever host the redirect points to, so a compromised or
+    /// misconfigured endpoint could obtain them. Only relax this if the
+    /// redirect targets are trusted, e.g. using [`Policy::custom`] to restrict
+    /// them to known hosts.
+    pub fn redirect_policy(mut self, policy: Policy) -> Self {
+        self.options.http.redirect_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Follow responses of the DS asking the client to use a different,
+    /// canonical endpoint, e.g. the shard of its tenant. If enabled, the
+    /// request is sent once more to the canonical endpoint, which is then used
+    /// for all subsequent requests of clients created from the resulting
+    /// [`UnregisteredApiClient`]. Operations routed via [`Self::route`] keep
+    /// their URLs. The canonical endpoint must use `http` or `https`, and
+    /// mustn't downgrade `https` to `http`. Unlike HTTP redirects, see
+    /// [`Self::redirect_policy`], such responses are only sent by the DS
+    /// itself. Disabled by default, in which case such responses fail with
+    /// [`SendMessageError::RedirectedToCanonicalEndpoint`](crate::errors::SendMessageError::RedirectedToCanonicalEndpoint).
+    pub fn follow_ds_redirects(mut self, follow: bool) -> Self {
+        self.options.follow_ds_redirects = follow;
+        self
+    }
+
+    /// Limit the rate of requests sent to the DS to `requests_per_second` on
+    /// average, allowing bursts of up to `burst` requests. Requests exceeding
+    /// the limit are delayed rather than rejected. The limit is shared by all
+    /// clients created from the resulting [`UnregisteredApiClient`].
+    pub fn rate_limit(mut self, requests_per_second: f64, burst: u32) -> Self {
+        self.options.rate_limiter = Some(RateLimiter::new(requests_per_second, burst));
+        self
+    }
+
+    /// Allow at most `max` requests to be in flight at the same time, counted
+    /// across all clients created from the resulting
+    /// [`UnregisteredApiClient`]. Further requests wait until an earlier one
+    /// completes. A request counts as in flight until the DS responded with
+    /// its status, or until a [`Transport`] returned the response. Use
+    /// [`Self::concurrency_limit`] to share the limit with other builders.
+    pub fn max_concurrent_requests(self, max: usize) -> Self {
+        self.concurrency_limit(Arc::new(Semaphore::new(max.max(1))))
+    }
+
+    /// Like [`Self::max_concurrent_requests`], but acquire a permit from
+    /// `semaphore` for each request, so that the same limit can apply to
+    /// clients created from different builders, e.g. all tenants of a
+    /// server.
+    pub fn concurrency_limit(mut self, semaphore: Arc<Semaphore>) -> Self {
+        self.options.concurrency_limit = Some(semaphore);
+        self
+    }
+
+    /// Use `clock` to tell the time and to wait, e.g. to test token expiry
+    /// and polling without waiting. Defaults to [`SystemClock`].
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    /// Send requests via `transport` instead of HTTP. See [`Transport`] for
+    /// which other settings still apply.
+    pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.options.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Require client IDs to be UUIDs, i.e. exactly 16 bytes long, when
+    /// registering. Disabled by default.
+    pub fn strict_uuid(mut self, strict_uuid: bool) -> Self {
+        self.config.strict_uuid = strict_uuid;
+        self
+    }
+
+    /// Set the maximum number of key packages (not counting the last resort
+    /// key package) that may be sent in a single request. Requests exceeding
+    /// the limit fail before anything is sent. Defaults to
+    /// [`DEFAULT_MAX_KEY_PACKAGES_PER_REQUEST`].
+    pub fn max_key_packages_per_request(mut self, max: usize) -> Self {
+        self.config.max_key_packages_per_request = max;
+        self
+    }
+
+    /// Allow registering without any one-time key packages, i.e. only with a
+    /// last resort key package. Disabled by default, since other clients
+    /// would then immediately have to use the last resort key package.
+    pub fn allow_last_resort_only(mut self, allow: bool) -> Self {
+        self.config.allow_last_resort_only = allow;
+        self
+    }
+
+    /// Set how many requests helpers that send one request per item, such as
+    /// [`ApiClient::fetch_key_packages`](crate::ApiClient::fetch_key_packages)
+    /// and
+    /// [`UnregisteredApiClient::register_many`](crate::UnregisteredApiClient::register_many),
+    /// may have in flight at once. Defaults to [`DEFAULT_BATCH_CONCURRENCY`].
+    /// Values below 1 are treated as 1.
+    pub fn batch_concurrency(mut self, concurrency: usize) -> Self {
+        self.config.batch_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Check that this client is the sender of outgoing public messages in
+    /// groups for which its leaf index was set via
+    /// [`ApiClient::set_own_leaf_index`](crate::ApiClient::set_own_leaf_index).
+    /// Enabled by default. Disable it for relays and proxies that distribute
+    /// messages on behalf of other group members.
+    pub fn verify_sender(mut self, verify_sender: bool) -> Self {
+        self.config.verify_sender = verify_sender;
+        self
+    }
+
+    /// Limit the number of bytes buffered while parsing a response
+    /// incrementally, see
+    /// [`ApiClient::fetch_messages_streaming`](crate::ApiClient::fetch_messages_streaming).
+    /// If a message and the chunk of the response it arrives in don't fit,
+    /// fetching fails with
+    /// [`FetchMessagesError::BufferLimitExceeded`](crate::errors::FetchMessagesError::BufferLimitExceeded),
+    /// so the limit should comfortably exceed the largest expected message.
+    /// Unlimited by default. Doesn't apply to responses of a custom
+    /// [`Transport`], which are returned in full.
+    pub fn max_buffer_bytes(mut self, max_buffer_bytes: usize) -> Self {
+        self.config.max_buffer_bytes = Some(max_buffer_bytes);
+        self
+    }
+
+    /// Accept client IDs assigned by the DS during registration, for DS
+    /// backends that allocate client IDs themselves. The credential of the
+    /// key packages then doesn't need to contain a client ID. If it does, it
+    /// must match the assigned one. Disabled by default, in which case the
+    /// client ID is derived from the credential.
+    pub fn server_assigned_client_ids(mut self, enabled: bool) -> Self {
+        self.config.server_assigned_client_ids = enabled;
+        self
+    }
+
+    /// Build the [`UnregisteredApiClient`].
+    pub fn build(mut self) -> Result<UnregisteredApiClient, reqwest::Error> {
+        if let Some(rate_limiter) = &mut self.options.rate_limiter {
+            rate_limiter.set_clock(self.config.clock.clone());
+        }
+        let connection = DsConnection::with_options(self.ds_url, self.options)?;
+        Ok(UnregisteredApiClient {
+            connection,
+            config: self.config,
+        })
+    }
+}
@@ -0,0 +1,39 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! The source of time used by clients, replaceable to test time-dependent
+//! behavior without waiting.
+
+use std::{
+    fmt::Debug,
+    time::{Duration, SystemTime},
+};
+
+use futures::future::BoxFuture;
+
+/// The source of the current time and of timers used by clients, both to check
+/// whether the auth token expired and to wait between polls. Set via
+/// [`ApiClientBuilder::clock`](crate::builder::ApiClientBuilder::clock).
+/// Defaults to [`SystemClock`].
+pub trait Clock: Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+
+    /// Wait for `duration` to pass.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// A [`Clock`] using the system time and the timers of the tokio runtime.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
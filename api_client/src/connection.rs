@@ -0,0 +1,583 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use minimal_ds_types::requests::request_signature_content;
+use openmls::prelude::{
+    tls_codec::{Serialize, Size},
+    DeserializeBytes, Signer,
+};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    redirect::Policy,
+    tls::Version,
+    Body, Client, Response, Url,
+};
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+
+use crate::{
+    builder::DEFAULT_USER_AGENT,
+    clock::Clock,
+    errors::{DsUrlError, SendMessageError},
+    millis_since_epoch,
+    rate_limit::RateLimiter,
+    requests::{MinimalDsMessageOut, MinimalDsResponseIn, Operation},
+    transport::{RequestSignature, Transport, TransportRequest},
+    DsUrl,
+};
+
+/// A callback that returns headers to be added to each request. Used for
+/// headers whose values change over time, e.g. rotating gateway tokens.
+pub type HeaderProvider = Arc<dyn Fn() -> HeaderMap + Send + Sync>;
+
+/// A callback that is called with the round-trip time of each request, i.e.
+/// the time from sending the serialized request until the response headers
+/// were received.
+pub type ResponseTimeObserver = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// A callback that is called with the number of bytes of a request body sent
+/// so far and the total size of the body, e.g. to show the progress of large
+/// uploads. The body is handed to the HTTP client in chunks of
+/// [`UPLOAD_CHUNK_SIZE`] bytes, and the callback is called for each chunk.
+pub type UploadProgress = Arc<dyn Fn(u64, u64) + Send + Sync>;
+
+/// Error returned by a [`ResponseValidator`] to reject a response.
+pub type ResponseValidationError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A callback that checks every response of the DS once it was deserialized,
+/// e.g. to enforce a policy on responses or to keep an audit trail. A
+/// rejected response fails the request with
+/// [`SendMessageError::ResponseRejected`]. Fetches via
+/// [`ApiClient::fetch_messages_streaming`](crate::ApiClient::fetch_messages_streaming)
+/// parse their response incrementally and bypass the validator.
+pub type ResponseValidator = Arc<
+    dyn Fn(Operation, &MinimalDsResponseIn) -> Result<(), ResponseValidationError> + Send + Sync,
+>;
+
+/// Size of the chunks in which request bodies are handed to the HTTP client
+/// if an [`UploadProgress`] callback is given. The body is still serialized
+/// in full before the first chunk is sent.
+pub const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of consecutive requests that must fail to reach the DS before the
+/// connection counts as [`ConnectionState::Disconnected`].
+const DISCONNECTED_AFTER_FAILURES: u32 = 3;
+
+/// The connectivity to the DS, as observed through the outcome of recent
+/// requests, see [`ApiClient::connection_state`](crate::ApiClient::connection_state).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    /// The last request reached the DS, or no request was sent yet. Requests
+    /// the DS rejected, e.g. because of invalid credentials or rate limiting,
+    /// count as having reached it.
+    #[default]
+    Connected,
+    /// The last request failed to reach the DS, e.g. because of a timeout or
+    /// a gateway error, but the ones before it didn't.
+    Degraded,
+    /// The last three requests failed to reach the DS.
+    Disconnected,
+}
+
+/// Tracks the [`ConnectionState`] of the connections sharing the same
+/// [`ConnectionOptions`].
+pub(crate) struct ConnectionHealth {
+    state: watch::Sender<ConnectionState>,
+    consecutive_failures: AtomicU32,
+}
+
+impl Default for ConnectionHealth {
+    fn default() -> Self {
+        Self {
+            state: watch::channel(ConnectionState::Connected).0,
+            consecutive_failures: AtomicU32::new(0),
+        }
+    }
+}
+
+impl ConnectionHealth {
+    pub(crate) fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Update the state with the outcome of a request. Subscribers are only
+    /// notified if the state changes.
+    fn record<T>(&self, result: &Result<T, SendMessageError>) {
+        let failures = match result {
+            Err(e) if !reached_ds(e) => {
+                self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1
+            }
+            _ => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                0
+            }
+        };
+        let state = match failures {
+            0 => ConnectionState::Connected,
+            failures if failures < DISCONNECTED_AFTER_FAILURES => ConnectionState::Degraded,
+            _ => ConnectionState::Disconnected,
+        };
+        self.state.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+    }
+}
+
+/// Whether a request that failed with `error` reached the DS, as opposed to
+/// failing because the DS or the network in between is unavailable.
+fn reached_ds(error: &SendMessageError) -> bool {
+    match error {
+        SendMessageError::RateLimited { status, .. } => {
+            *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        error => !error.is_retryable(),
+    }
+}
+
+/// Settings of the HTTP client, kept so that the client can be rebuilt, see
+/// [`DsConnection::reset`].
+#[derive(Clone)]
+pub(crate) struct HttpSettings {
+    pub(crate) headers: HeaderMap,
+    pub(crate) user_agent: String,
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    pub(crate) pool_idle_timeout: Option<Option<Duration>>,
+    pub(crate) tcp_keepalive: Option<Duration>,
+    pub(crate) local_address: Option<IpAddr>,
+    pub(crate) min_tls_version: Option<Version>,
+    pub(crate) http2_prior_knowledge: bool,
+    pub(crate) http2_adaptive_window: Option<bool>,
+    pub(crate) http2_initial_stream_window_size: Option<u32>,
+    pub(crate) http2_initial_connection_window_size: Option<u32>,
+    // `Policy` isn't `Clone`, so it is shared and wrapped in a custom policy
+    // for each client built.
+    pub(crate) redirect_policy: Option<Arc<Policy>>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            headers: HeaderMap::new(),
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            tcp_keepalive: None,
+            local_address: None,
+            min_tls_version: None,
+            http2_prior_knowledge: false,
+            http2_adaptive_window: None,
+            http2_initial_stream_window_size: None,
+            http2_initial_connection_window_size: None,
+            redirect_policy: None,
+        }
+    }
+}
+
+impl HttpSettings {
+    pub(crate) fn build_client(&self) -> Result<Client, reqwest::Error> {
+        let redirect_policy = match &self.redirect_policy {
+            Some(policy) => {
+                let policy = policy.clone();
+                Policy::custom(move |attempt| policy.redirect(attempt))
+            }
+            None => Policy::none(),
+        };
+        let mut builder = Client::builder()
+            .redirect(redirect_policy)
+            .user_agent(self.user_agent.as_str())
+            .default_headers(self.headers.clone())
+            .tcp_keepalive(self.tcp_keepalive)
+            .local_address(self.local_address);
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(version) = self.min_tls_version {
+            builder = builder.min_tls_version(version);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(enabled) = self.http2_adaptive_window {
+            builder = builder.http2_adaptive_window(enabled);
+        }
+        if let Some(size) = self.http2_initial_stream_window_size {
+            builder = builder.http2_initial_stream_window_size(size);
+        }
+        if let Some(size) = self.http2_initial_connection_window_size {
+            builder = builder.http2_initial_connection_window_size(size);
+        }
+        builder.build()
+    }
+}
+
+/// Settings of the connection beyond the HTTP client itself.
+#[derive(Default)]
+pub(crate) struct ConnectionOptions {
+    pub(crate) http: HttpSettings,
+    // Operations that are sent to a different URL than the DS URL.
+    pub(crate) routes: HashMap<Operation, Url>,
+    pub(crate) header_provider: Option<HeaderProvider>,
+    pub(crate) response_time_observer: Option<ResponseTimeObserver>,
+    pub(crate) response_validator: Option<ResponseValidator>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    // Caps the number of requests in flight, possibly across several
+    // `UnregisteredApiClient`s.
+    pub(crate) concurrency_limit: Option<Arc<Semaphore>>,
+    // Replaces the HTTP client if set.
+    pub(crate) transport: Option<Arc<dyn Transport>>,
+    pub(crate) follow_ds_redirects: bool,
+    pub(crate) health: ConnectionHealth,
+}
+
+#[derive(Clone)]
+pub(crate) struct DsConnection {
+    client: Client,
+    // For now we assume there's only one DS we can connect to. Shared, since
+    // connections are cloned for every client, and so that all clients move
+    // to the canonical endpoint once the DS redirects one of them.
+    ds_url: Arc<RwLock<Url>>,
+    // Shared between all clones, so that e.g. the rate limit applies to all
+    // clients created from the same `UnregisteredApiClient`.
+    options: Arc<ConnectionOptions>,
+    // Not shared via `options`, since each client signs with its own key. The
+    // clock provides the timestamps of signatures.
+    signer: Option<(RequestSigner, Arc<dyn Clock>)>,
+}
+
+/// A signer used to sign each request, see
+/// [`ApiClient::enable_request_signing`](crate::ApiClient::enable_request_signing).
+pub type RequestSigner = Arc<dyn Signer + Send + Sync>;
+
+/// Name of the header carrying the hex-encoded request signature.
+pub const REQUEST_SIGNATURE_HEADER: &str = "x-ds-request-signature";
+
+/// Name of the header carrying the timestamp of the request signature, in
+/// decimal milliseconds since the UNIX epoch.
+pub const REQUEST_TIMESTAMP_HEADER: &str = "x-ds-request-timestamp";
+
+/// Number of bytes of a malformed response included in
+/// [`SendMessageError::MalformedResponse`] and
+/// [`SendMessageError::NotADsEndpoint`].
+const MALFORMED_RESPONSE_PREFIX_LEN: usize = 64;
+
+impl DsConnection {
+    pub(crate) fn new(ds_url: Url) -> Self {
+        // Like `Client::new`, this only fails if the TLS backend can't be
+        // initialized.
+        Self::with_options(ds_url, ConnectionOptions::default())
+            .expect("Failed to initialize the HTTP client.")
+    }
+
+    pub(crate) fn with_options(
+        ds_url: Url,
+        options: ConnectionOptions,
+    ) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            client: options.http.build_client()?,
+            ds_url: Arc::new(RwLock::new(ds_url)),
+            options: Arc::new(options),
+            signer: None,
+        })
+    }
+
+    /// Replace the HTTP client by a new one with the same settings, dropping
+    /// this connection's handle to the old connection pool.
+    pub(crate) fn reset(&mut self) -> Result<(), reqwest::Error> {
+        self.client = self.options.http.build_client()?;
+        Ok(())
+    }
+
+    /// The URL requests are sent to, unless routed elsewhere.
+    pub(crate) fn ds_url(&self) -> Url {
+        self.ds_url
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set_signer(&mut self, signer: RequestSigner, clock: Arc<dyn Clock>) {
+        self.signer = Some((signer, clock));
+    }
+
+    /// Open a connection to the DS by sending a `HEAD` request, so that DNS
+    /// resolution and the TLS handshake are done before the first real
+    /// request. The response status is ignored.
+    pub(crate) async fn warmup(&self) -> Result<(), SendMessageError> {
+        if self.options.transport.is_some() {
+            return Ok(());
+        }
+        self.client.head(self.ds_url()).send().await?;
+        Ok(())
+    }
+
+    pub(crate) async fn send_message(
+        &self,
+        message: MinimalDsMessageOut<'_>,
+    ) -> Result<MinimalDsResponseIn, SendMessageError> {
+        self.send_message_with_progress(message, None).await
+    }
+
+    /// Like [`Self::send_message`], but report the progress of sending the
+    /// request body to `progress`, if given.
+    pub(crate) async fn send_message_with_progress(
+        &self,
+        message: MinimalDsMessageOut<'_>,
+        progress: Option<&UploadProgress>,
+    ) -> Result<MinimalDsResponseIn, SendMessageError> {
+        let endpoint = match self.send_once(&message, progress).await? {
+            MinimalDsResponseIn::CanonicalEndpoint(endpoint) => endpoint,
+            response => return Ok(response),
+        };
+        self.follow_redirect(&endpoint)?;
+        // Only follow a single redirect, so that a misconfigured DS can't send
+        // the client in circles.
+        match self.send_once(&message, progress).await? {
+            MinimalDsResponseIn::CanonicalEndpoint(endpoint) => {
+                Err(SendMessageError::RedirectedToCanonicalEndpoint(
+                    String::from_utf8_lossy(&endpoint).into_owned(),
+                ))
+            }
+            response => Ok(response),
+        }
+    }
+
+    /// Handle a [`MinimalDsResponseIn::CanonicalEndpoint`] response
+    /// containing `endpoint`: if following redirects is enabled, use it as
+    /// the DS URL from now on, so that the request can be sent again.
+    pub(crate) fn follow_redirect(&self, endpoint: &[u8]) -> Result<(), SendMessageError> {
+        let endpoint = String::from_utf8_lossy(endpoint).into_owned();
+        if !self.options.follow_ds_redirects {
+            return Err(SendMessageError::RedirectedToCanonicalEndpoint(endpoint));
+        }
+        self.move_to(&endpoint)
+    }
+
+    /// Use `endpoint` as the DS URL from now on.
+    fn move_to(&self, endpoint: &str) -> Result<(), SendMessageError> {
+        let endpoint: DsUrl = endpoint
+            .parse()
+            .map_err(|e: DsUrlError| SendMessageError::InvalidCanonicalEndpoint(e.to_string()))?;
+        let mut ds_url = self.ds_url.write().unwrap_or_else(|e| e.into_inner());
+        if ds_url.scheme() == "https" && endpoint.is_cleartext() {
+            return Err(SendMessageError::InvalidCanonicalEndpoint(
+                "The endpoint downgrades https to http.".to_owned(),
+            ));
+        }
+        *ds_url = endpoint.into();
+        Ok(())
+    }
+
+    /// Send `message` to the DS and parse the response.
+    async fn send_once(
+        &self,
+        message: &MinimalDsMessageOut<'_>,
+        progress: Option<&UploadProgress>,
+    ) -> Result<MinimalDsResponseIn, SendMessageError> {
+        let response_bytes = match self.send_request(message, progress).await? {
+            RawResponse::Http(response) => response.bytes().await?.to_vec(),
+            RawResponse::Bytes(bytes) => bytes,
+        };
+        let prefix_hex = || {
+            hex_encode(&response_bytes[..response_bytes.len().min(MALFORMED_RESPONSE_PREFIX_LEN)])
+        };
+        // An empty body or an unknown tag usually means that the URL doesn't
+        // point to a DS at all, e.g. an HTML page starting with `<`.
+        if response_bytes
+            .first()
+            .map_or(true, |&tag| tag > MinimalDsResponseIn::MAX_TAG)
+        {
+            return Err(SendMessageError::NotADsEndpoint {
+                prefix_hex: prefix_hex(),
+            });
+        }
+        let (response, rest) = MinimalDsResponseIn::tls_deserialize_bytes(&response_bytes)
+            .map_err(|source| SendMessageError::MalformedResponse {
+                source,
+                prefix_hex: prefix_hex(),
+            })?;
+        if !rest.is_empty() {
+            return Err(SendMessageError::TrailingBytes {
+                consumed: response_bytes.len() - rest.len(),
+                total: response_bytes.len(),
+            });
+        }
+        if let Some(response_validator) = &self.options.response_validator {
+            response_validator(message.operation(), &response)
+                .map_err(SendMessageError::ResponseRejected)?;
+        }
+        Ok(response)
+    }
+
+    /// The connection state shared by all clones of this connection.
+    pub(crate) fn health(&self) -> &ConnectionHealth {
+        &self.options.health
+    }
+
+    /// Send `message` to the DS and return the response if its status is
+    /// `200 OK`, without reading the body. The outcome updates the
+    /// [`ConnectionState`].
+    pub(crate) async fn send_request(
+        &self,
+        message: &MinimalDsMessageOut<'_>,
+        progress: Option<&UploadProgress>,
+    ) -> Result<RawResponse, SendMessageError> {
+        let result = self.send_unobserved_request(message, progress).await;
+        self.options.health.record(&result);
+        result
+    }
+
+    async fn send_unobserved_request(
+        &self,
+        message: &MinimalDsMessageOut<'_>,
+        progress: Option<&UploadProgress>,
+    ) -> Result<RawResponse, SendMessageError> {
+        let operation = message.operation();
+        // The whole body is serialized into memory before sending it, since
+        // requests borrow their contents, e.g. the ratchet tree, while a
+        // streaming body must own them. Allocating the buffer exactly once at
+        // least avoids temporarily needing up to twice the memory for large
+        // ratchet trees.
+        let mut message_bytes = Vec::with_capacity(message.tls_serialized_len());
+        message.tls_serialize(&mut message_bytes)?;
+        let signature = self
+            .signer
+            .as_ref()
+            .map(|(signer, clock)| {
+                let timestamp_ms = millis_since_epoch(clock.now());
+                let content = request_signature_content(timestamp_ms, &message_bytes);
+                signer.sign(&content).map(|signature| RequestSignature {
+                    timestamp_ms,
+                    signature,
+                })
+            })
+            .transpose()
+            .map_err(SendMessageError::RequestSigningError)?;
+        if let Some(transport) = &self.options.transport {
+            if let Some(rate_limiter) = &self.options.rate_limiter {
+                rate_limiter.acquire().await;
+            }
+            let _permit = self.acquire_permit().await;
+            // A transport takes the body as a whole.
+            if let Some(progress) = progress {
+                let total = message_bytes.len() as u64;
+                progress(total, total);
+            }
+            let request = TransportRequest {
+                operation,
+                body: message_bytes,
+                signature,
+            };
+            let response = transport.send(request).await?;
+            return Ok(RawResponse::Bytes(response));
+        }
+        let url = self
+            .options
+            .routes
+            .get(&operation)
+            .cloned()
+            .unwrap_or_else(|| self.ds_url());
+        let body = match progress {
+            Some(progress) => progress_body(message_bytes, progress.clone()),
+            None => message_bytes.into(),
+        };
+        let mut request = self.client.post(url).body(body);
+        if let Some(signature) = signature {
+            request = request
+                .header(REQUEST_SIGNATURE_HEADER, hex_encode(&signature.signature))
+                .header(REQUEST_TIMESTAMP_HEADER, signature.timestamp_ms);
+        }
+        if let Some(rate_limiter) = &self.options.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        if let Some(header_provider) = &self.options.header_provider {
+            request = request.headers(header_provider());
+        }
+        let _permit = self.acquire_permit().await;
+        let start = Instant::now();
+        let response = request.send().await?;
+        if let Some(response_time_observer) = &self.options.response_time_observer {
+            response_time_observer(start.elapsed());
+        }
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(RawResponse::Http(response)),
+            reqwest::StatusCode::UNAUTHORIZED => Err(SendMessageError::Unauthorized),
+            status @ (reqwest::StatusCode::TOO_MANY_REQUESTS
+            | reqwest::StatusCode::SERVICE_UNAVAILABLE) => Err(rate_limited(status, &response)),
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR => {
+                let error_string = response.text().await?;
+                Err(SendMessageError::MinimalDsError(error_string))
+            }
+            other => Err(SendMessageError::NetworkError(other)),
+        }
+    }
+
+    /// Wait until the concurrency limit, if any, admits another request. The
+    /// request counts as in flight until the returned permit is dropped. A
+    /// closed semaphore no longer limits requests.
+    async fn acquire_permit(&self) -> Option<SemaphorePermit<'_>> {
+        let semaphore = self.options.concurrency_limit.as_ref()?;
+        semaphore.acquire().await.ok()
+    }
+}
+
+/// A successful response of the DS whose body has not been read yet.
+pub(crate) enum RawResponse {
+    Http(Response),
+    /// The complete body, as returned by a custom [`Transport`].
+    Bytes(Vec<u8>),
+}
+
+/// A body that streams `bytes` in chunks of [`UPLOAD_CHUNK_SIZE`] bytes,
+/// reporting each chunk to `progress` when the HTTP client takes it.
+fn progress_body(bytes: Vec<u8>, progress: UploadProgress) -> Body {
+    let total = bytes.len() as u64;
+    // Copy one chunk at a time, so that the body isn't held twice.
+    let chunks = (0..bytes.len())
+        .step_by(UPLOAD_CHUNK_SIZE)
+        .map(move |start| {
+            let end = (start + UPLOAD_CHUNK_SIZE).min(bytes.len());
+            progress(end as u64, total);
+            Ok::<_, Infallible>(bytes[start..end].to_vec())
+        });
+    Body::wrap_stream(futures::stream::iter(chunks))
+}
+
+/// Build a [`SendMessageError::RateLimited`] from the headers of `response`.
+fn rate_limited(status: reqwest::StatusCode, response: &Response) -> SendMessageError {
+    let headers = response.headers();
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let rate_limit_headers = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ratelimit-"))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    SendMessageError::RateLimited {
+        status,
+        retry_after,
+        rate_limit_headers,
+    }
+}
+
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,51 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::collections::{HashSet, VecDeque};
+
+/// Remembers the sequence numbers of the most recently fetched messages, so
+/// that messages returned more than once by the DS can be dropped.
+#[derive(Debug)]
+pub(crate) struct RecentSequenceNumbers {
+    window: usize,
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+impl RecentSequenceNumbers {
+    pub(crate) fn new(window: usize) -> Self {
+        Self {
+            window,
+            order: VecDeque::with_capacity(window),
+            seen: HashSet::with_capacity(window),
+        }
+    }
+
+    pub(crate) fn contains(&self, sequence_number: u64) -> bool {
+        self.seen.contains(&sequence_number)
+    }
+
+    pub(crate) fn window(&self) -> usize {
+        self.window
+    }
+
+    /// The remembered sequence numbers, oldest first.
+    pub(crate) fn sequence_numbers(&self) -> impl Iterator<Item = u64> + '_ {
+        self.order.iter().copied()
+    }
+
+    /// Remember `sequence_number`, forgetting the oldest one if the window is
+    /// full.
+    pub(crate) fn insert(&mut self, sequence_number: u64) {
+        if self.window == 0 || !self.seen.insert(sequence_number) {
+            return;
+        }
+        self.order.push_back(sequence_number);
+        if self.order.len() > self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+}
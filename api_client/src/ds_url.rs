@@ -0,0 +1,65 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::str::FromStr;
+
+use reqwest::Url;
+
+use crate::errors::DsUrlError;
+
+/// The URL of a DS. Unlike a plain [`Url`], it is guaranteed to use the `http`
+/// or `https` scheme and to have a host, so that misconfigurations are caught
+/// when creating a client instead of on its first request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DsUrl {
+    url: Url,
+}
+
+impl DsUrl {
+    /// Whether requests to this URL are sent in cleartext, i.e. without TLS.
+    /// Since requests carry the client's auth token, plain `http` should only
+    /// be used for local testing.
+    pub fn is_cleartext(&self) -> bool {
+        self.url.scheme() == "http"
+    }
+
+    pub fn as_url(&self) -> &Url {
+        &self.url
+    }
+}
+
+impl TryFrom<Url> for DsUrl {
+    type Error = DsUrlError;
+
+    fn try_from(url: Url) -> Result<Self, Self::Error> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(DsUrlError::UnsupportedScheme(url.scheme().to_owned()));
+        }
+        if url.host().is_none() {
+            return Err(DsUrlError::MissingHost);
+        }
+        Ok(Self { url })
+    }
+}
+
+impl FromStr for DsUrl {
+    type Err = DsUrlError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| DsUrlError::InvalidUrl(e.to_string()))?;
+        url.try_into()
+    }
+}
+
+impl From<DsUrl> for Url {
+    fn from(ds_url: DsUrl) -> Self {
+        ds_url.url
+    }
+}
+
+impl std::fmt::Display for DsUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.url.fmt(f)
+    }
+}
@@ -0,0 +1,133 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::{collections::HashMap, sync::Mutex};
+
+use minimal_ds_types::{DsGroupId, MessageRoute, MlsMessageHeader, MlsMessageKind, QueuedMessage};
+use openmls::{framing::MlsMessageOut, group::GroupId, prelude::tls_codec::Serialize};
+
+/// Keeps track of the current epoch of each group, as observed through
+/// commits sent and fetched by the client.
+#[derive(Debug, Default)]
+pub(crate) struct EpochTracker {
+    epochs: Mutex<HashMap<DsGroupId, u64>>,
+}
+
+impl EpochTracker {
+    pub(crate) fn epoch(&self, group_id: &DsGroupId) -> Option<u64> {
+        self.epochs.lock().unwrap().get(group_id).copied()
+    }
+
+    /// The last known epoch of each group.
+    pub(crate) fn snapshot(&self) -> Vec<(DsGroupId, u64)> {
+        self.epochs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(group_id, epoch)| (*group_id, *epoch))
+            .collect()
+    }
+
+    pub(crate) fn from_snapshot(epochs: impl IntoIterator<Item = (DsGroupId, u64)>) -> Self {
+        Self {
+            epochs: Mutex::new(epochs.into_iter().collect()),
+        }
+    }
+
+    /// Check that a commit described by `header` is for the last known epoch
+    /// of its group. Returns the expected epoch on mismatch.
+    pub(crate) fn check_commit(&self, header: &MlsMessageHeader) -> Result<(), u64> {
+        let Some((group_id, epoch)) = commit_epoch(header) else {
+            return Ok(());
+        };
+        match self.epoch(&group_id) {
+            Some(expected) if expected != epoch => Err(expected),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that the commit described by `header` was sent or received,
+    /// i.e. that its group moved on to the next epoch.
+    pub(crate) fn observe(&self, header: &MlsMessageHeader) {
+        if let Some((group_id, epoch)) = commit_epoch(header) {
+            self.epochs
+                .lock()
+                .unwrap()
+                .insert(group_id, epoch.saturating_add(1));
+        }
+    }
+}
+
+/// Group ID and epoch of a commit. Returns `None` for other messages and for
+/// groups whose ID is not a valid [`DsGroupId`].
+fn commit_epoch(header: &MlsMessageHeader) -> Option<(DsGroupId, u64)> {
+    if header.kind != MlsMessageKind::Commit {
+        return None;
+    }
+    Some((header_group_id(header)?, header.epoch?))
+}
+
+/// The group ID in `header`, if it is present and a valid [`DsGroupId`].
+pub(crate) fn header_group_id(header: &MlsMessageHeader) -> Option<DsGroupId> {
+    let group_id = header.group_id.as_deref()?;
+    DsGroupId::try_from(GroupId::from_slice(group_id)).ok()
+}
+
+/// Parse the header of an outgoing message.
+pub(crate) fn message_header(message: &MlsMessageOut) -> Option<MlsMessageHeader> {
+    let bytes = message.tls_serialize_detached().ok()?;
+    MlsMessageHeader::parse(&bytes).ok()
+}
+
+/// Reorder a batch of fetched messages so that each group's messages can be
+/// processed in order: messages of an epoch come before those of later
+/// epochs, and the commit of an epoch comes after the other messages of that
+/// epoch, i.e. right before the messages that depend on it.
+///
+/// Only messages of the same group are moved relative to each other, and
+/// each group's messages keep the positions the group occupied in the batch.
+/// Otherwise, messages keep their relative order. Welcomes and messages
+/// whose header can't be parsed aren't moved.
+///
+/// The batch should be in sequence order, as returned by
+/// [`crate::ApiClient::fetch_queued_messages`], which rejects batches that
+/// aren't.
+pub fn order_by_epoch(messages: Vec<QueuedMessage>) -> Vec<QueuedMessage> {
+    let keys: Vec<Option<(Vec<u8>, u64, bool)>> = messages
+        .iter()
+        .map(|message| match message.message.route() {
+            Ok(MessageRoute::Group {
+                kind,
+                group_id,
+                epoch,
+            }) => Some((group_id, epoch, kind == MlsMessageKind::Commit)),
+            _ => None,
+        })
+        .collect();
+    // The positions of each group's messages, in fetch order.
+    let mut groups: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (index, key) in keys.iter().enumerate() {
+        if let Some((group_id, _, _)) = key {
+            groups.entry(group_id.as_slice()).or_default().push(index);
+        }
+    }
+    let mut order: Vec<usize> = (0..messages.len()).collect();
+    for positions in groups.values() {
+        let mut sorted = positions.clone();
+        // Stable, so messages with the same key keep their fetch order.
+        sorted.sort_by_key(|&index| {
+            keys[index]
+                .as_ref()
+                .map(|(_, epoch, is_commit)| (*epoch, *is_commit))
+        });
+        for (&position, &index) in positions.iter().zip(&sorted) {
+            order[position] = index;
+        }
+    }
+    let mut messages: Vec<Option<QueuedMessage>> = messages.into_iter().map(Some).collect();
+    order
+        .into_iter()
+        .filter_map(|index| messages[index].take())
+        .collect()
+}
@@ -2,22 +2,142 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use minimal_ds_types::DsClientIdError;
-use openmls::prelude::tls_codec;
-use reqwest::StatusCode;
+use std::time::Duration;
+
+use minimal_ds_types::{DsClientId, DsClientIdError};
+use mls_assist::messages::AssistedMessageError;
+use openmls::prelude::{tls_codec, KeyPackageVerifyError, SignerError};
+use reqwest::{header::HeaderMap, StatusCode};
 use thiserror::Error;
 
+use crate::{connection::ResponseValidationError, session::SessionStoreError};
+
 /// Errors that can occur when sending a message to the DS.
 #[derive(Error, Debug)]
 pub enum SendMessageError {
-    #[error(transparent)]
+    /// The HTTP request failed, either while sending the request or while
+    /// receiving the response.
+    #[error("Failed to send request: {0}")]
     ReqwestError(#[from] reqwest::Error),
     #[error("Network error: {0}")]
     NetworkError(StatusCode),
+    /// The DS is rate limiting or temporarily unavailable (429 or 503).
+    /// `retry_after` is parsed from the `Retry-After` header if it is given in
+    /// seconds. `rate_limit_headers` contains all `X-RateLimit-*` headers.
+    #[error("Rate limited: {status}")]
+    RateLimited {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        rate_limit_headers: HeaderMap,
+    },
     #[error("DS error: {0}")]
     MinimalDsError(String),
-    #[error(transparent)]
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    /// The request couldn't be serialized, e.g. because an input exceeds the
+    /// size limits of the wire format. Nothing was sent.
+    #[error("Failed to serialize request: {0}")]
     PayloadSerializationError(#[from] tls_codec::Error),
+    #[error("Error signing request: {0:?}")]
+    RequestSigningError(SignerError),
+    /// The DS URL answered with `200 OK`, but the body doesn't start with the
+    /// tag of a DS response, e.g. because the URL points to a plain web
+    /// server. `prefix_hex` contains the first bytes of the body,
+    /// hex-encoded.
+    #[error(
+        "The DS URL doesn't look like a Minimal DS endpoint, check that it \
+        points to the DS. The response starts with {prefix_hex}"
+    )]
+    NotADsEndpoint { prefix_hex: String },
+    /// The response body could not be deserialized. `prefix_hex` contains the
+    /// first bytes of the body, hex-encoded, to help identify what the DS
+    /// returned instead, e.g. an HTML error page.
+    #[error("Malformed response ({source}), starting with {prefix_hex}")]
+    MalformedResponse {
+        source: tls_codec::Error,
+        prefix_hex: String,
+    },
+    /// The DS asked the client to use the given canonical endpoint instead,
+    /// but following such redirects is disabled, see
+    /// [`ApiClientBuilder::follow_ds_redirects`](crate::builder::ApiClientBuilder::follow_ds_redirects),
+    /// or the DS redirected again after following one redirect.
+    #[error("The DS redirected to its canonical endpoint {0}.")]
+    RedirectedToCanonicalEndpoint(String),
+    /// The canonical endpoint returned by the DS is not a valid URL, or it
+    /// would downgrade the connection from `https` to `http`.
+    #[error("Invalid canonical endpoint: {0}")]
+    InvalidCanonicalEndpoint(String),
+    /// The response body is a valid response followed by `total - consumed`
+    /// additional bytes, e.g. a newline appended by a proxy.
+    #[error("Response has trailing bytes: consumed {consumed} of {total} bytes")]
+    TrailingBytes { consumed: usize, total: usize },
+    /// The [`ResponseValidator`](crate::ResponseValidator) rejected the
+    /// response.
+    #[error("Response rejected: {0}")]
+    ResponseRejected(ResponseValidationError),
+}
+
+impl SendMessageError {
+    /// The time the DS asked the client to wait before retrying, if any.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Whether the error is likely transient, so that sending the same
+    /// request again may succeed: timeouts, connection errors, server errors
+    /// and rate limiting. Errors caused by the request itself, such as other
+    /// `4xx` statuses or serialization errors, are not retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::ReqwestError(e) => {
+                e.is_timeout() || e.is_connect() || e.is_request() || e.is_body()
+            }
+            Self::NetworkError(status) => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            Self::RateLimited { .. } => true,
+            Self::MinimalDsError(_)
+            | Self::Unauthorized
+            | Self::PayloadSerializationError(_)
+            | Self::RequestSigningError(_)
+            | Self::MalformedResponse { .. }
+            | Self::NotADsEndpoint { .. }
+            | Self::TrailingBytes { .. }
+            | Self::RedirectedToCanonicalEndpoint(_)
+            | Self::InvalidCanonicalEndpoint(_)
+            | Self::ResponseRejected(_) => false,
+        }
+    }
+}
+
+/// Errors that can occur when querying the capabilities of the DS.
+#[derive(Error, Debug)]
+pub enum CapabilitiesError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error(transparent)]
+    CapabilitiesError(#[from] SendMessageError),
+}
+
+/// Errors that can occur when wrapping a key package in a
+/// [`OneTimeKeyPackage`](crate::OneTimeKeyPackage) or
+/// [`LastResortKeyPackage`](crate::LastResortKeyPackage).
+#[derive(Error, Debug)]
+pub enum KeyPackageTypeError {
+    #[error("MlsMessageOut is not a KeyPackage.")]
+    NotKeyPackage,
+    #[error("The key package has the last resort extension.")]
+    LastResort,
+    #[error("The key package does not have the last resort extension.")]
+    NotLastResort,
 }
 
 /// Errors that can occur when registering a client with the DS.
@@ -27,8 +147,34 @@ pub enum RegisterClientError {
     InvalidInput(&'static str),
     #[error(transparent)]
     InvalidClientId(#[from] DsClientIdError),
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS did not accept the freshly issued auth token.")]
+    TokenVerificationFailed,
+    #[error("The client is already registered with the DS.")]
+    AlreadyRegistered,
+    #[error("Too many key packages: at most {max} allowed, got {got}.")]
+    TooManyKeyPackages { max: usize, got: usize },
+    /// The DS assigned a client ID that differs from the one in the
+    /// credential of the key packages.
+    #[error("The DS assigned client ID {assigned}, but the credential contains {derived}.")]
+    ClientIdMismatch {
+        derived: DsClientId,
+        assigned: DsClientId,
+    },
+    /// The DS did not assign a client ID, and none can be derived from the
+    /// credential.
+    #[error("The DS did not assign a client ID.")]
+    MissingClientId,
+    #[error("Failed to save the session: {0}")]
+    SessionStoreError(SessionStoreError),
+    /// The request to rotate the auth token of a client without one can't
+    /// be signed.
+    #[error("Error signing the token rotation: {0:?}")]
+    SigningError(SignerError),
     #[error(transparent)]
     RegisterClientError(#[from] SendMessageError),
 }
@@ -36,59 +182,362 @@ pub enum RegisterClientError {
 /// Errors that can occur when requesting a list of clients from the DS.
 #[derive(Error, Debug)]
 pub enum ListClientsError {
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
     #[error(transparent)]
-    RegisterClientError(#[from] SendMessageError),
+    RegisterClientError(SendMessageError),
+}
+
+impl From<SendMessageError> for ListClientsError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::RegisterClientError(e),
+        }
+    }
+}
+
+/// Errors that can occur when listing the groups stored on the DS.
+#[derive(Error, Debug)]
+pub enum ListGroupsError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The DS rejected the client credentials, e.g. because listing groups is
+    /// restricted to privileged clients.
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    ListGroupsError(SendMessageError),
+}
+
+impl From<SendMessageError> for ListGroupsError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::ListGroupsError(e),
+        }
+    }
 }
 
 /// Errors that can occur when fetching messages from the DS.
 #[derive(Error, Debug)]
 pub enum FetchMessagesError {
+    #[error("Invalid input : {0}")]
+    InvalidInput(&'static str),
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
     #[error(transparent)]
-    FetchMessagesError(#[from] SendMessageError),
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    FetchMessagesError(SendMessageError),
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
     #[error("Error deserializing response: {0}")]
     DeserializationError(#[from] tls_codec::Error),
+    /// The DS returned a message with a sequence number that isn't greater
+    /// than the last one seen. The cursor is left unchanged.
+    #[error("Received sequence number {received}, but already saw {stored}.")]
+    NonMonotonicSequence { stored: u64, received: u64 },
+    #[error("The DS did not respond in time.")]
+    Timeout,
+    /// A single message of a streamed response, together with the chunk of
+    /// the response it was received in, exceeds the configured buffer limit.
+    #[error("The response needs more than {limit} bytes of buffer.")]
+    BufferLimitExceeded { limit: usize },
+}
+
+impl From<SendMessageError> for FetchMessagesError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::FetchMessagesError(e),
+        }
+    }
+}
+
+/// Errors caused by an inconsistent
+/// [`MessageStreamConfig`](crate::stream::MessageStreamConfig).
+#[derive(Error, Debug)]
+pub enum MessageStreamConfigError {
+    #[error(
+        "Request timeout {request_timeout:?} does not exceed long poll wait {long_poll_wait:?}."
+    )]
+    TimeoutWithinLongPoll {
+        request_timeout: Duration,
+        long_poll_wait: Duration,
+    },
+}
+
+/// Errors that can occur when draining messages using
+/// [`ApiClient::drain_messages_with_checkpoint`](crate::ApiClient::drain_messages_with_checkpoint).
+#[derive(Error, Debug)]
+pub enum DrainMessagesError<E> {
+    #[error(transparent)]
+    Fetch(#[from] FetchMessagesError),
+    /// The checkpoint callback failed.
+    #[error("Checkpoint failed: {0}")]
+    Checkpoint(E),
 }
 
 /// Errors that can occur when uploading key packages to the DS.
 #[derive(Error, Debug)]
 pub enum UploadKeyPackagesError {
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("Too many key packages: at most {max} allowed, got {got}.")]
+    TooManyKeyPackages { max: usize, got: usize },
+    #[error("The DS accepted the key packages but did not confirm their persistence.")]
+    NotDurable,
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
     #[error(transparent)]
-    UploadKeyPackageError(#[from] SendMessageError),
+    UploadKeyPackageError(SendMessageError),
+}
+
+impl From<SendMessageError> for UploadKeyPackagesError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::UploadKeyPackageError(e),
+        }
+    }
 }
 
 /// Errors that can occur when creating a group on the DS.
 #[derive(Error, Debug)]
 pub enum CreateGroupError {
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Invalid input : {0}")]
+    InvalidInput(&'static str),
+    #[error(transparent)]
+    InvalidGroupInfo(#[from] GroupInfoError),
+    #[error("A group with this ID already exists in epoch {current_epoch}.")]
+    GroupExists {
+        owner_is_self: bool,
+        current_epoch: u64,
+    },
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    CreateGroupError(SendMessageError),
+}
+
+impl From<SendMessageError> for CreateGroupError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::CreateGroupError(e),
+        }
+    }
+}
+
+/// Errors that can occur when verifying a group info, see
+/// [`verify_group_info`](crate::verify_group_info).
+#[derive(Error, Debug)]
+pub enum GroupInfoError {
+    #[error("MlsMessageOut is not a GroupInfo.")]
+    NotGroupInfo,
+    #[error("Could not (de)serialize the GroupInfo or RatchetTree: {0}")]
+    Codec(#[from] tls_codec::Error),
+    /// The signature of the group info doesn't verify against the credential
+    /// of its signer in the ratchet tree, or the ratchet tree doesn't match
+    /// the tree hash in the group context.
+    #[error("The GroupInfo does not verify against the RatchetTree.")]
+    VerificationFailed,
+}
+
+/// Errors that can occur when updating the group info of a group on the DS.
+#[derive(Error, Debug)]
+pub enum UpdateGroupInfoError {
+    #[error("Invalid input : {0}")]
+    InvalidInput(&'static str),
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The DS already stores a group info of the same or a later epoch.
+    #[error("The DS already stores the group info of epoch {stored_epoch}.")]
+    StaleGroupInfo { stored_epoch: u64 },
+    /// The group isn't in the epoch expected by
+    /// [`ApiClient::update_group_info_if_epoch`](crate::ApiClient::update_group_info_if_epoch).
+    #[error("The group is in epoch {current_epoch}.")]
+    PreconditionFailed { current_epoch: u64 },
+    /// The DS doesn't consider this client a member of the group.
+    #[error("This client is not a member of the group.")]
+    NotGroupMember,
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    UpdateGroupInfoError(SendMessageError),
+}
+
+impl From<SendMessageError> for UpdateGroupInfoError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::UpdateGroupInfoError(e),
+        }
+    }
+}
+
+/// Errors that can occur when sending or fetching delivery receipts.
+#[derive(Error, Debug)]
+pub enum DeliveryReceiptError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The DS doesn't consider this client a member of the group.
+    #[error("This client is not a member of the group.")]
+    NotGroupMember,
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
     #[error(transparent)]
-    CreateGroupError(#[from] SendMessageError),
+    DeliveryReceiptError(SendMessageError),
+}
+
+impl From<SendMessageError> for DeliveryReceiptError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::DeliveryReceiptError(e),
+        }
+    }
 }
 
 /// Errors that can occur when fetching a key package from the DS.
 #[derive(Error, Debug)]
 pub enum FetchKeyPackageError {
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The key package returned by the DS belongs to a different client than
+    /// the requested one.
+    #[error("Requested the key package of {requested}, but got one of {got}.")]
+    ClientIdMismatch {
+        requested: DsClientId,
+        got: DsClientId,
+    },
+    /// The credential of the key package returned by the DS doesn't contain a
+    /// valid client ID.
+    #[error(transparent)]
+    InvalidClientId(#[from] DsClientIdError),
+    /// The key package returned by the DS is invalid, e.g. because its
+    /// signature doesn't verify or its lifetime has expired.
+    #[error("Invalid key package: {0}")]
+    InvalidKeyPackage(#[from] KeyPackageVerifyError),
+    /// The lifetime or the extensions of the key package returned by the DS
+    /// can't be read.
+    #[error("Failed to parse the key package: {0}")]
+    Codec(#[from] tls_codec::Error),
     #[error(transparent)]
     FetchKeyPackageError(#[from] SendMessageError),
 }
 
+/// Errors that can occur when fetching the group info of a group from the DS.
+#[derive(Error, Debug)]
+pub enum FetchGroupInfoError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS returned a message that is not a GroupInfo.")]
+    NotGroupInfo,
+    /// The group info returned by the DS belongs to a different group than
+    /// the requested one.
+    #[error("The GroupInfo returned by the DS belongs to a different group.")]
+    GroupIdMismatch,
+    #[error("Could not deserialize the GroupInfo or RatchetTree: {0}")]
+    Codec(#[from] tls_codec::Error),
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    FetchGroupInfoError(SendMessageError),
+}
+
+impl From<SendMessageError> for FetchGroupInfoError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::FetchGroupInfoError(e),
+        }
+    }
+}
+
 /// Errors that can occur when distributing a group message through the DS.
 #[derive(Error, Debug)]
 pub enum DistributeGroupMessageError {
     #[error("Invalid input : {0}")]
     InvalidInput(&'static str),
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Commit is for epoch {actual}, but the group is in epoch {expected}.")]
+    EpochMismatch { expected: u64, actual: u64 },
+    /// The message or group info can't be assembled into an assisted message,
+    /// e.g. because the group info of a commit is missing.
+    #[error("Invalid assisted message: {0}")]
+    AssistedMessage(#[from] AssistedMessageError),
+    /// The DS doesn't consider this client a member of the group, e.g.
+    /// because it was removed. The local group state can be torn down once
+    /// the remaining queued messages of the group have been processed.
+    #[error("The client is not a member of the group.")]
+    RemovedFromGroup,
+    /// The group isn't in the epoch expected by
+    /// [`ApiClient::distribute_group_message_if_epoch`](crate::ApiClient::distribute_group_message_if_epoch).
+    #[error("The group is in epoch {current_epoch}.")]
+    PreconditionFailed { current_epoch: u64 },
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The DS returned a different number of results than items were sent.
+    #[error("Sent {sent} items, but received {received} results.")]
+    BatchLengthMismatch { sent: usize, received: usize },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
     #[error(transparent)]
-    DistributeGroupMessageError(#[from] SendMessageError),
+    DistributeGroupMessageError(SendMessageError),
+}
+
+impl From<SendMessageError> for DistributeGroupMessageError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::DistributeGroupMessageError(e),
+        }
+    }
+}
+
+/// Errors that can occur when sending group messages through an
+/// [`OutboundQueue`](crate::outbound::OutboundQueue).
+#[derive(Error, Debug)]
+pub enum OutboundQueueError {
+    #[error("Failed to serialize the message: {0}")]
+    Codec(#[from] tls_codec::Error),
+    #[error("Failed to persist the queue: {0}")]
+    SessionStoreError(SessionStoreError),
+    #[error(transparent)]
+    DistributeGroupMessageError(#[from] DistributeGroupMessageError),
 }
 
 /// Errors that can occur when distributing a welcome message through the DS.
@@ -96,8 +545,11 @@ pub enum DistributeGroupMessageError {
 pub enum DistributeWelcomeError {
     #[error("Invalid input : {0}")]
     InvalidInput(&'static str),
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
     #[error(transparent)]
     DistributeWelcomeError(#[from] SendMessageError),
 }
@@ -105,17 +557,482 @@ pub enum DistributeWelcomeError {
 /// Errors that can occur when deleting a group on the DS.
 #[derive(Error, Debug)]
 pub enum DeleteGroupError {
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The DS returned a different number of results than items were sent.
+    #[error("Sent {sent} items, but received {received} results.")]
+    BatchLengthMismatch { sent: usize, received: usize },
+    #[error("The group does not exist.")]
+    NotFound,
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    DeleteGroupError(SendMessageError),
+}
+
+impl From<SendMessageError> for DeleteGroupError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::DeleteGroupError(e),
+        }
+    }
+}
+
+/// Errors that can occur when restoring a group marked for deletion.
+#[derive(Error, Debug)]
+pub enum RestoreGroupError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The group does not exist.")]
+    NotFound,
+    /// The group exists, but isn't marked for deletion.
+    #[error("The group is not marked for deletion.")]
+    NotDeletable,
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
     #[error(transparent)]
-    DeleteGroupError(#[from] SendMessageError),
+    RestoreGroupError(SendMessageError),
+}
+
+impl From<SendMessageError> for RestoreGroupError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::RestoreGroupError(e),
+        }
+    }
 }
 
 /// Errors that can occur when deleting a client from the DS.
 #[derive(Error, Debug)]
 pub enum DeleteClientError {
-    #[error("Received an unexpected response.")]
-    UnexpectedResponse,
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The DS returned a different number of results than items were sent.
+    #[error("Sent {sent} items, but received {received} results.")]
+    BatchLengthMismatch { sent: usize, received: usize },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    DeleteClientError(SendMessageError),
+}
+
+impl From<SendMessageError> for DeleteClientError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::DeleteClientError(e),
+        }
+    }
+}
+
+/// Errors that can occur when querying the DS for information about this
+/// client.
+#[derive(Error, Debug)]
+pub enum WhoAmIError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    WhoAmIError(SendMessageError),
+}
+
+impl From<SendMessageError> for WhoAmIError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::WhoAmIError(e),
+        }
+    }
+}
+
+/// Errors that can occur when querying the groups this client belongs to.
+#[derive(Error, Debug)]
+pub enum MyGroupsError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    MyGroupsError(SendMessageError),
+}
+
+impl From<SendMessageError> for MyGroupsError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::MyGroupsError(e),
+        }
+    }
+}
+
+/// Errors that can occur when adding a member to a group via
+/// [`ApiClient::add_member`](crate::ApiClient::add_member).
+#[derive(Error, Debug)]
+pub enum AddMemberError {
+    #[error("Invalid input : {0}")]
+    InvalidInput(&'static str),
+    /// Distributing the commit failed, so the member was not added.
+    #[error("Failed to distribute the commit: {0}")]
+    CommitFailed(#[source] DistributeGroupMessageError),
+    /// The commit was distributed, but the welcome was not. The member is part
+    /// of the group, but can only join once the welcome is distributed.
+    #[error("Failed to distribute the welcome for {member}: {error}")]
+    WelcomeFailed {
+        member: DsClientId,
+        #[source]
+        error: DistributeWelcomeError,
+    },
+}
+
+/// Errors that indicate whether the DS rejected the client credentials, see
+/// [`ApiClient::with_auto_refresh`](crate::ApiClient::with_auto_refresh).
+pub trait IsUnauthorized {
+    fn is_unauthorized(&self) -> bool;
+}
+
+macro_rules! impl_is_unauthorized {
+    ($($error:ty),*) => {
+        $(
+            impl IsUnauthorized for $error {
+                fn is_unauthorized(&self) -> bool {
+                    matches!(self, Self::Unauthorized)
+                }
+            }
+        )*
+    };
+}
+
+impl_is_unauthorized!(
+    SendMessageError,
+    ListClientsError,
+    ListGroupsError,
+    FetchMessagesError,
+    UploadKeyPackagesError,
+    CreateGroupError,
+    DistributeGroupMessageError,
+    DeleteGroupError,
+    DeleteClientError,
+    WhoAmIError,
+    MyGroupsError,
+    RestoreGroupError,
+    PurgeQueueError,
+    QueueDepthError,
+    ProvisionTokenError,
+    FetchGroupInfoError,
+    UpdateGroupInfoError,
+    DeliveryReceiptError
+);
+
+macro_rules! impl_is_retryable {
+    ($($error:ident::$variant:ident),*) => {
+        $(
+            impl $error {
+                /// Whether the request failed with a retryable
+                /// [`SendMessageError`], see [`SendMessageError::is_retryable`].
+                pub fn is_retryable(&self) -> bool {
+                    matches!(self, Self::$variant(e) if e.is_retryable())
+                }
+            }
+        )*
+    };
+}
+
+impl_is_retryable!(
+    CapabilitiesError::CapabilitiesError,
+    RegisterClientError::RegisterClientError,
+    ListClientsError::RegisterClientError,
+    ListGroupsError::ListGroupsError,
+    UploadKeyPackagesError::UploadKeyPackageError,
+    CreateGroupError::CreateGroupError,
+    UpdateGroupInfoError::UpdateGroupInfoError,
+    FetchKeyPackageError::FetchKeyPackageError,
+    FetchGroupInfoError::FetchGroupInfoError,
+    DistributeGroupMessageError::DistributeGroupMessageError,
+    OutboundQueueError::DistributeGroupMessageError,
+    DistributeWelcomeError::DistributeWelcomeError,
+    DeleteGroupError::DeleteGroupError,
+    RestoreGroupError::RestoreGroupError,
+    DeleteClientError::DeleteClientError,
+    WhoAmIError::WhoAmIError,
+    MyGroupsError::MyGroupsError,
+    PurgeQueueError::PurgeQueueError,
+    QueueDepthError::QueueDepthError,
+    LogoutError::LogoutError,
+    ProvisionTokenError::ProvisionTokenError,
+    DeliveryReceiptError::DeliveryReceiptError
+);
+
+impl FetchMessagesError {
+    /// Whether the fetch failed with a retryable [`SendMessageError`], see
+    /// [`SendMessageError::is_retryable`], or timed out.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::FetchMessagesError(e) => e.is_retryable(),
+            Self::Timeout => true,
+            _ => false,
+        }
+    }
+}
+
+impl AddMemberError {
+    /// Whether distributing the commit or the welcome failed with a
+    /// retryable [`SendMessageError`], see [`SendMessageError::is_retryable`].
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::InvalidInput(_) => false,
+            Self::CommitFailed(e) => e.is_retryable(),
+            Self::WelcomeFailed { error, .. } => error.is_retryable(),
+        }
+    }
+}
+
+/// Returned when creating a [`NonEmpty`](crate::NonEmpty) from an empty list.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("The list is empty.")]
+pub struct EmptyListError;
+
+/// Errors that can occur when validating the URL of a DS.
+#[derive(Error, Debug)]
+pub enum DsUrlError {
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("Unsupported scheme {0}. Expected http or https.")]
+    UnsupportedScheme(String),
+    #[error("The URL has no host.")]
+    MissingHost,
+}
+
+/// Errors that can occur when purging the queue of a client.
+#[derive(Error, Debug)]
+pub enum PurgeQueueError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
     #[error(transparent)]
-    DeleteClientError(#[from] SendMessageError),
+    PurgeQueueError(SendMessageError),
+}
+
+impl From<SendMessageError> for PurgeQueueError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::PurgeQueueError(e),
+        }
+    }
+}
+
+/// Errors that can occur when querying the number of queued messages.
+#[derive(Error, Debug)]
+pub enum QueueDepthError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    QueueDepthError(SendMessageError),
+}
+
+impl From<SendMessageError> for QueueDepthError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::QueueDepthError(e),
+        }
+    }
+}
+
+/// Errors that can occur when logging out.
+#[derive(Error, Debug)]
+pub enum LogoutError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The DS rejected the auth token, e.g. because it already expired or the
+    /// client logged out before.
+    #[error("The auth token is already invalid.")]
+    TokenAlreadyInvalid,
+    #[error(transparent)]
+    LogoutError(SendMessageError),
+}
+
+impl From<SendMessageError> for LogoutError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::TokenAlreadyInvalid,
+            e => Self::LogoutError(e),
+        }
+    }
+}
+
+/// Errors that can occur when provisioning an additional auth token.
+#[derive(Error, Debug)]
+pub enum ProvisionTokenError {
+    #[error("Expected response {expected}, but received {got}.")]
+    UnexpectedResponse {
+        expected: &'static str,
+        got: &'static str,
+    },
+    #[error("The DS rejected the client credentials.")]
+    Unauthorized,
+    #[error(transparent)]
+    ProvisionTokenError(SendMessageError),
+}
+
+impl From<SendMessageError> for ProvisionTokenError {
+    fn from(e: SendMessageError) -> Self {
+        match e {
+            SendMessageError::Unauthorized => Self::Unauthorized,
+            e => Self::ProvisionTokenError(e),
+        }
+    }
+}
+
+/// Errors that can occur when importing a
+/// [`ClientState`](minimal_ds_types::ClientState).
+#[derive(Error, Debug)]
+pub enum ImportStateError {
+    #[error("Unsupported client state version {version}, at most {supported} is supported.")]
+    UnsupportedVersion { version: u16, supported: u16 },
+}
+
+/// Shorthand for results whose error is an [`ApiError`], so that call sites
+/// using several operations can propagate their errors with `?` without
+/// naming each error type.
+pub type Result<T, E = ApiError> = std::result::Result<T, E>;
+
+/// Any error returned by an operation of the API client. Each of the
+/// operation-specific errors converts into the variant named after its
+/// operation, so the operation that failed remains visible. Match on the
+/// wrapped error for details.
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("Request failed: {0}")]
+    SendMessage(#[from] SendMessageError),
+    #[error("Failed to query capabilities: {0}")]
+    Capabilities(#[from] CapabilitiesError),
+    #[error("Invalid key package: {0}")]
+    KeyPackageType(#[from] KeyPackageTypeError),
+    #[error("Failed to register client: {0}")]
+    RegisterClient(#[from] RegisterClientError),
+    #[error("Failed to list clients: {0}")]
+    ListClients(#[from] ListClientsError),
+    #[error("Failed to list groups: {0}")]
+    ListGroups(#[from] ListGroupsError),
+    #[error("Failed to fetch messages: {0}")]
+    FetchMessages(#[from] FetchMessagesError),
+    #[error("Invalid message stream configuration: {0}")]
+    MessageStreamConfig(#[from] MessageStreamConfigError),
+    #[error("Failed to upload key packages: {0}")]
+    UploadKeyPackages(#[from] UploadKeyPackagesError),
+    #[error("Failed to create group: {0}")]
+    CreateGroup(#[from] CreateGroupError),
+    #[error("Invalid group info: {0}")]
+    GroupInfo(#[from] GroupInfoError),
+    #[error("Failed to update group info: {0}")]
+    UpdateGroupInfo(#[from] UpdateGroupInfoError),
+    #[error("Failed to fetch key package: {0}")]
+    FetchKeyPackage(#[from] FetchKeyPackageError),
+    #[error("Failed to fetch group info: {0}")]
+    FetchGroupInfo(#[from] FetchGroupInfoError),
+    #[error("Failed to distribute group message: {0}")]
+    DistributeGroupMessage(#[from] DistributeGroupMessageError),
+    #[error("Failed to send queued group message: {0}")]
+    OutboundQueue(#[from] OutboundQueueError),
+    #[error("Failed to distribute welcome: {0}")]
+    DistributeWelcome(#[from] DistributeWelcomeError),
+    #[error("Failed to delete group: {0}")]
+    DeleteGroup(#[from] DeleteGroupError),
+    #[error("Failed to restore group: {0}")]
+    RestoreGroup(#[from] RestoreGroupError),
+    #[error("Failed to delete client: {0}")]
+    DeleteClient(#[from] DeleteClientError),
+    #[error("Failed to query client info: {0}")]
+    WhoAmI(#[from] WhoAmIError),
+    #[error("Failed to query groups: {0}")]
+    MyGroups(#[from] MyGroupsError),
+    #[error("Failed to add member: {0}")]
+    AddMember(#[from] AddMemberError),
+    #[error("Invalid DS URL: {0}")]
+    DsUrl(#[from] DsUrlError),
+    #[error("Empty list: {0}")]
+    EmptyList(#[from] EmptyListError),
+    #[error("Failed to purge queue: {0}")]
+    PurgeQueue(#[from] PurgeQueueError),
+    #[error("Failed to query queue depth: {0}")]
+    QueueDepth(#[from] QueueDepthError),
+    #[error("Failed to log out: {0}")]
+    Logout(#[from] LogoutError),
+    #[error("Failed to provision token: {0}")]
+    ProvisionToken(#[from] ProvisionTokenError),
+    #[error("Failed to handle delivery receipts: {0}")]
+    DeliveryReceipt(#[from] DeliveryReceiptError),
+    #[error("Failed to import client state: {0}")]
+    ImportState(#[from] ImportStateError),
+}
+
+impl ApiError {
+    /// Whether the underlying request failed with a retryable
+    /// [`SendMessageError`], see [`SendMessageError::is_retryable`].
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::SendMessage(e) => e.is_retryable(),
+            Self::Capabilities(e) => e.is_retryable(),
+            Self::RegisterClient(e) => e.is_retryable(),
+            Self::ListClients(e) => e.is_retryable(),
+            Self::ListGroups(e) => e.is_retryable(),
+            Self::FetchMessages(e) => e.is_retryable(),
+            Self::UploadKeyPackages(e) => e.is_retryable(),
+            Self::CreateGroup(e) => e.is_retryable(),
+            Self::UpdateGroupInfo(e) => e.is_retryable(),
+            Self::FetchKeyPackage(e) => e.is_retryable(),
+            Self::FetchGroupInfo(e) => e.is_retryable(),
+            Self::DistributeGroupMessage(e) => e.is_retryable(),
+            Self::OutboundQueue(e) => e.is_retryable(),
+            Self::DistributeWelcome(e) => e.is_retryable(),
+            Self::DeleteGroup(e) => e.is_retryable(),
+            Self::RestoreGroup(e) => e.is_retryable(),
+            Self::DeleteClient(e) => e.is_retryable(),
+            Self::WhoAmI(e) => e.is_retryable(),
+            Self::MyGroups(e) => e.is_retryable(),
+            Self::AddMember(e) => e.is_retryable(),
+            Self::PurgeQueue(e) => e.is_retryable(),
+            Self::QueueDepth(e) => e.is_retryable(),
+            Self::Logout(e) => e.is_retryable(),
+            Self::ProvisionToken(e) => e.is_retryable(),
+            Self::DeliveryReceipt(e) => e.is_retryable(),
+            Self::KeyPackageType(_)
+            | Self::MessageStreamConfig(_)
+            | Self::GroupInfo(_)
+            | Self::DsUrl(_)
+            | Self::EmptyList(_)
+            | Self::ImportState(_) => false,
+        }
+    }
 }
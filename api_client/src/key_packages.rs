@@ -0,0 +1,270 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Key packages typed by whether they are one-time or last resort key
+//! packages, so that the two can't be mixed up when uploading them.
+
+use std::{borrow::Cow, collections::HashSet};
+
+use openmls::{
+    framing::{MlsMessageBodyOut, MlsMessageOut},
+    key_packages::KeyPackageIn,
+    prelude::{tls_codec::Serialize, TlsSerialize, TlsSize},
+};
+
+use crate::errors::KeyPackageTypeError;
+
+/// A key package without the last resort extension, which the DS hands out
+/// only once.
+#[derive(Debug, Clone, TlsSize, TlsSerialize)]
+pub struct OneTimeKeyPackage(MlsMessageOut);
+
+impl OneTimeKeyPackage {
+    /// Wrap `message`, which must be a key package without the last resort
+    /// extension.
+    pub fn new(message: MlsMessageOut) -> Result<Self, KeyPackageTypeError> {
+        if is_last_resort(&message)? {
+            return Err(KeyPackageTypeError::LastResort);
+        }
+        Ok(Self(message))
+    }
+
+    /// The wrapped key package message.
+    pub fn as_message(&self) -> &MlsMessageOut {
+        &self.0
+    }
+
+    /// Unwrap the key package message.
+    pub fn into_message(self) -> MlsMessageOut {
+        self.0
+    }
+}
+
+impl TryFrom<MlsMessageOut> for OneTimeKeyPackage {
+    type Error = KeyPackageTypeError;
+
+    fn try_from(message: MlsMessageOut) -> Result<Self, Self::Error> {
+        Self::new(message)
+    }
+}
+
+/// A key package with the last resort extension, which the DS hands out once
+/// no one-time key packages are left.
+#[derive(Debug, Clone, TlsSize, TlsSerialize)]
+pub struct LastResortKeyPackage(MlsMessageOut);
+
+impl LastResortKeyPackage {
+    /// Wrap `message`, which must be a key package with the last resort
+    /// extension.
+    pub fn new(message: MlsMessageOut) -> Result<Self, KeyPackageTypeError> {
+        if !is_last_resort(&message)? {
+            return Err(KeyPackageTypeError::NotLastResort);
+        }
+        Ok(Self(message))
+    }
+
+    /// The wrapped key package message.
+    pub fn as_message(&self) -> &MlsMessageOut {
+        &self.0
+    }
+
+    /// Unwrap the key package message.
+    pub fn into_message(self) -> MlsMessageOut {
+        self.0
+    }
+}
+
+impl TryFrom<MlsMessageOut> for LastResortKeyPackage {
+    type Error = KeyPackageTypeError;
+
+    fn try_from(message: MlsMessageOut) -> Result<Self, Self::Error> {
+        Self::new(message)
+    }
+}
+
+/// The outcome of uploading one-time key packages, see
+/// [`ApiClient::upload_key_packages`](crate::ApiClient::upload_key_packages).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPackageUploadSummary {
+    /// The number of key packages sent to the DS.
+    pub uploaded: usize,
+    /// The number of key packages dropped because they were given more than
+    /// once.
+    pub duplicates: usize,
+}
+
+/// An upload of one-time key packages in chunks, which keeps track of the
+/// chunks uploaded so far, so that a failed upload can be resumed, see
+/// [`ApiClient::start_upload`](crate::ApiClient::start_upload).
+#[derive(Debug, Clone)]
+pub struct UploadHandle {
+    key_packages: Vec<OneTimeKeyPackage>,
+    last_resort_key_package: LastResortKeyPackage,
+    chunk_size: usize,
+    uploaded: usize,
+    duplicates: usize,
+}
+
+impl UploadHandle {
+    pub(crate) fn new(
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        chunk_size: usize,
+    ) -> Self {
+        let unique_key_packages = deduplicate(key_packages).into_owned();
+        Self {
+            duplicates: key_packages.len() - unique_key_packages.len(),
+            key_packages: unique_key_packages,
+            last_resort_key_package: last_resort_key_package.clone(),
+            // An empty chunk would never advance the upload.
+            chunk_size: chunk_size.max(1),
+            uploaded: 0,
+        }
+    }
+
+    /// The number of key packages uploaded so far.
+    pub fn uploaded(&self) -> usize {
+        self.uploaded
+    }
+
+    /// The number of key packages still to be uploaded.
+    pub fn remaining(&self) -> usize {
+        self.key_packages.len() - self.uploaded
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub(crate) fn next_chunk(&self) -> Option<&[OneTimeKeyPackage]> {
+        if self.is_complete() {
+            return None;
+        }
+        let end = (self.uploaded + self.chunk_size).min(self.key_packages.len());
+        Some(&self.key_packages[self.uploaded..end])
+    }
+
+    pub(crate) fn last_resort_key_package(&self) -> &LastResortKeyPackage {
+        &self.last_resort_key_package
+    }
+
+    /// Record that the chunk returned by [`Self::next_chunk`] was uploaded.
+    pub(crate) fn advance(&mut self, count: usize) {
+        self.uploaded += count;
+    }
+
+    pub(crate) fn summary(&self) -> KeyPackageUploadSummary {
+        KeyPackageUploadSummary {
+            uploaded: self.uploaded,
+            duplicates: self.duplicates,
+        }
+    }
+}
+
+/// Drop all but the first occurrence of each key package in `key_packages`.
+/// Key packages are compared by their serialization.
+pub(crate) fn deduplicate(key_packages: &[OneTimeKeyPackage]) -> Cow<'_, [OneTimeKeyPackage]> {
+    let mut seen = HashSet::new();
+    // A key package that can't be serialized can't be uploaded either, so it
+    // is kept for the upload to fail.
+    let first_occurrences: Vec<bool> = key_packages
+        .iter()
+        .map(|key_package| {
+            key_package
+                .tls_serialize_detached()
+                .map_or(true, |bytes| seen.insert(bytes))
+        })
+        .collect();
+    if first_occurrences.iter().all(|&first| first) {
+        return Cow::Borrowed(key_packages);
+    }
+    key_packages
+        .iter()
+        .zip(first_occurrences)
+        .filter(|(_, first)| *first)
+        .map(|(key_package, _)| key_package.clone())
+        .collect()
+}
+
+/// A key package claimed via
+/// [`ApiClient::claim_key_package`](crate::ApiClient::claim_key_package),
+/// typed by whether it has the last resort extension.
+#[derive(Debug, Clone)]
+pub enum ClaimedKeyPackage {
+    /// A one-time key package, which the DS deleted when handing it out.
+    OneTime(KeyPackageIn),
+    /// The last resort key package, which the DS keeps handing out until the
+    /// client uploads new one-time key packages.
+    LastResort(KeyPackageIn),
+}
+
+impl ClaimedKeyPackage {
+    pub fn key_package(&self) -> &KeyPackageIn {
+        match self {
+            Self::OneTime(key_package) | Self::LastResort(key_package) => key_package,
+        }
+    }
+
+    pub fn into_key_package(self) -> KeyPackageIn {
+        match self {
+            Self::OneTime(key_package) | Self::LastResort(key_package) => key_package,
+        }
+    }
+
+    pub fn is_last_resort(&self) -> bool {
+        matches!(self, Self::LastResort(_))
+    }
+}
+
+/// Whether the key package in `message` has the last resort extension. Used
+/// by [`OneTimeKeyPackage::new`] and [`LastResortKeyPackage::new`], so that
+/// [`register`](crate::UnregisteredApiClient::register) and
+/// [`upload_key_packages`](crate::ApiClient::upload_key_packages) can't be
+/// given a regular key package in place of the last resort one, or vice versa.
+pub fn is_last_resort(message: &MlsMessageOut) -> Result<bool, KeyPackageTypeError> {
+    match message.body() {
+        MlsMessageBodyOut::KeyPackage(key_package) => Ok(key_package.last_resort()),
+        _ => Err(KeyPackageTypeError::NotKeyPackage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use minimal_ds_types::key_package::{has_last_resort_extension, KeyPackageLifetime};
+    use openmls::prelude::{CryptoConfig, Extension, Extensions, KeyPackage, LastResortExtension};
+
+    use super::*;
+    use crate::test_utils::{TestClient, CIPHERSUITE};
+
+    fn assert_parsed_like_openmls(key_package: &KeyPackage) {
+        let bytes = key_package.tls_serialize_detached().unwrap();
+        let lifetime = KeyPackageLifetime::parse(&bytes).unwrap();
+        assert_eq!(lifetime.not_before, key_package.life_time().not_before());
+        assert_eq!(lifetime.not_after, key_package.life_time().not_after());
+        assert_eq!(
+            has_last_resort_extension(&bytes).unwrap(),
+            key_package.last_resort()
+        );
+    }
+
+    #[test]
+    fn key_package_parsing_matches_openmls() {
+        let client = TestClient::new(1);
+        assert_parsed_like_openmls(&client.key_package());
+
+        let last_resort_key_package = KeyPackage::builder()
+            .key_package_extensions(Extensions::single(Extension::LastResort(
+                LastResortExtension::default(),
+            )))
+            .build(
+                CryptoConfig::with_default_version(CIPHERSUITE),
+                &client.provider,
+                &client.signer,
+                client.credential_with_key.clone(),
+            )
+            .unwrap();
+        assert!(last_resort_key_package.last_resort());
+        assert_parsed_like_openmls(&last_resort_key_package);
+    }
+}
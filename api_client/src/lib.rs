@@ -9,165 +9,660 @@
 //! ## Usage
 //!
 //! To start interacting with the DS, create an [`UnregisteredApiClient`] with
-//! the URL of the DS. Then call `register` with the key packages of the client
-//! to register the client with the DS. The returned [`ApiClient`] provides the
-//! necessary methods to interact with the DS.
+//! the [`DsUrl`] of the DS. Then call `register` with the key packages of the
+//! client, wrapped in [`OneTimeKeyPackage`] and [`LastResortKeyPackage`], to
+//! register the client with the DS. The returned [`ApiClient`] provides the
+//! necessary methods to interact with the DS. Use
+//! [`UnregisteredApiClient::builder`] instead if the HTTP client needs further
+//! configuration, such as additional headers.
+//!
+//! To continuously receive messages, [`ApiClient::message_stream`] turns the
+//! client into a stream that polls the DS and recovers from transient errors.
+//!
+//! Each operation returns its own error type. All of them convert into
+//! [`errors::ApiError`], so code calling several operations can use
+//! [`errors::Result`] and propagate errors with `?`.
+//!
+//! With the `blocking` feature enabled, the [`blocking`] module provides a
+//! synchronous version of the clients. It must not be used from within an
+//! async runtime.
 //!
 //! Inputs to the methods can be generated using the `openmls` crate. See that
 //! crate's documentation for further guidance.
 
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use errors::{
-    CreateGroupError, DeleteClientError, DeleteGroupError, DistributeGroupMessageError,
-    DistributeWelcomeError, FetchKeyPackageError, FetchMessagesError, ListClientsError,
-    RegisterClientError, SendMessageError, UploadKeyPackagesError,
+    AddMemberError, CapabilitiesError, CreateGroupError, DeleteClientError, DeleteGroupError,
+    DeliveryReceiptError, DistributeGroupMessageError, DistributeWelcomeError, DrainMessagesError,
+    FetchGroupInfoError, FetchKeyPackageError, FetchMessagesError, GroupInfoError,
+    ListClientsError, ListGroupsError, LogoutError, MyGroupsError, ProvisionTokenError,
+    PurgeQueueError, QueueDepthError, RegisterClientError, RestoreGroupError, SendMessageError,
+    UploadKeyPackagesError, WhoAmIError,
 };
+use futures::StreamExt;
 use minimal_ds_types::{
+    key_package::{has_last_resort_extension, KeyPackageLifetime},
     requests::{
-        DeleteClientRequest, DeleteGroupRequest, FetchKeyPackageRequest, FetchMessagesRequest,
+        BatchItemResult, ClaimKeyPackageRequest, DeleteClientRequest, DeleteClientsRequest,
+        DeleteGroupRequest, DeleteGroupSoftRequest, DeleteGroupsRequest, DeliveryReceiptRequest,
+        FetchDeliveryReceiptsRequest, FetchFreshKeyPackageRequest, FetchGroupInfoRequest,
+        FetchGroupMessagesRequest, FetchKeyPackageRequest, FetchMessageRangeRequest,
+        FetchMessagesRequest, FetchMessagesSinceRequest, ListClientsRequest, ListGroupsRequest,
+        LogoutRequest, MyGroupsRequest, ProvisionTokenRequest, PurgeQueueRequest,
+        QueueDepthRequest, RestoreGroupRequest, WhoAmIRequest, ROTATE_TOKEN_SIGNATURE_LABEL,
     },
-    AuthToken, ClientCredentials,
+    tree_delta::RatchetTreeDelta,
+    ClientCredentials, MlsMessageHeader,
 };
-use mls_assist::messages::{AssistedMessageError, AssistedMessageOut};
 use openmls::{
-    framing::{MlsMessageBodyOut, MlsMessageIn, MlsMessageOut},
-    key_packages::KeyPackageIn,
+    framing::{MlsMessageBodyIn, MlsMessageBodyOut, MlsMessageIn, MlsMessageOut},
+    group::{GroupId, ProposalStore, PublicGroup},
+    key_packages::{KeyPackage, KeyPackageIn},
     prelude::{
-        tls_codec::{self, Serialize},
-        DeserializeBytes,
+        tls_codec::{self, Serialize, Size},
+        DeserializeBytes, LeafNodeIndex, OpenMlsProvider, ProtocolVersion, Signer,
     },
-    treesync::RatchetTree,
+    treesync::{RatchetTree, RatchetTreeIn},
+};
+use openmls_rust_crypto::OpenMlsRustCrypto;
+use requests::{
+    MinimalDsMessageOut, MinimalDsResponseIn, RegisterClientRequestOut, RotateTokenContentOut,
+    SignedRotateTokenRequestOut,
 };
-use requests::{MinimalDsMessageOut, MinimalDsResponseIn, RegisterClientRequestOut};
-use reqwest::{Client, Url};
+
+use builder::{ApiClientBuilder, ClientConfig};
+use connection::DsConnection;
+use dedup::RecentSequenceNumbers;
+use epochs::{header_group_id, message_header, EpochTracker};
+use session::{Session, SessionStore};
 
 // Re-export types
-pub use minimal_ds_types::{DsClientId, DsGroupId};
+pub use minimal_ds_types::{
+    diff_members,
+    requests::{
+        ClientInfo, DeliveryReceipt, DistributionReport, DsCapabilities, GroupInfoRef,
+        KeyPackageCounts, MessagePriority, MessageRef,
+    },
+    AuthToken, ClientState, DsClientId, DsGroupId, MemberDiff, MessageRoute, MlsMessageKind,
+    QueuedMessage, WelcomeHeader,
+};
+pub use mls_assist::messages::{AssistedMessageError, AssistedMessageOut};
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod builder;
+pub mod clock;
+mod connection;
+mod dedup;
+mod ds_url;
+mod epochs;
 pub mod errors;
+pub mod key_packages;
+mod non_empty;
+pub mod outbound;
+mod rate_limit;
+pub mod refresh;
+pub mod replenish;
 pub mod requests;
+pub mod session;
+#[cfg(feature = "stats")]
+pub mod stats;
+pub mod stream;
+pub mod teardown;
+#[cfg(test)]
+mod test_utils;
+#[cfg(any(test, feature = "test_vectors"))]
+pub mod test_vectors;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transport;
 
-#[derive(Clone)]
-struct DsConnection {
-    client: Client,
-    // For now we assume there's only one DS we can connect to.
-    ds_url: Url,
-}
+pub use connection::{
+    ConnectionState, HeaderProvider, RequestSigner, ResponseTimeObserver, ResponseValidationError,
+    ResponseValidator, UploadProgress, REQUEST_SIGNATURE_HEADER, REQUEST_TIMESTAMP_HEADER,
+    UPLOAD_CHUNK_SIZE,
+};
+pub use ds_url::DsUrl;
+pub use epochs::order_by_epoch;
+pub use key_packages::{
+    is_last_resort, ClaimedKeyPackage, KeyPackageUploadSummary, LastResortKeyPackage,
+    OneTimeKeyPackage, UploadHandle,
+};
+pub use non_empty::NonEmpty;
 
-impl DsConnection {
-    fn new(ds_url: Url) -> Self {
-        let client = Client::new();
-        Self { client, ds_url }
-    }
+/// Number of client IDs requested per page by [`ApiClient::list_clients`].
+const LIST_CLIENTS_PAGE_SIZE: u32 = 100;
 
-    async fn send_message(
-        &self,
-        message: MinimalDsMessageOut<'_>,
-    ) -> Result<MinimalDsResponseIn, SendMessageError> {
-        let message_bytes = message.tls_serialize_detached()?;
-        let response = self
-            .client
-            .post(self.ds_url.clone())
-            .body(message_bytes)
-            .send()
-            .await?;
-        match response.status() {
-            reqwest::StatusCode::OK => {
-                let response_bytes = response.bytes().await?;
-                let response = MinimalDsResponseIn::tls_deserialize_exact_bytes(&response_bytes)?;
-                Ok(response)
-            }
-            reqwest::StatusCode::INTERNAL_SERVER_ERROR => {
-                let error_string = response.text().await?;
-                Err(SendMessageError::MinimalDsError(error_string))
-            }
-            other => Err(SendMessageError::NetworkError(other)),
-        }
-    }
+/// Maximum number of sequence numbers a range passed to
+/// [`ApiClient::fetch_message_range`] may span.
+pub const MAX_MESSAGE_RANGE: u64 = 1000;
+
+/// A page of client IDs as returned by [`ApiClient::list_clients_page`].
+#[derive(Debug, Clone)]
+pub struct ListClientsPage {
+    /// The client IDs on this page.
+    pub client_ids: Vec<DsClientId>,
+    /// The total number of clients matching the request.
+    pub total: u32,
+}
+
+/// Messages fetched via [`ApiClient::fetch_messages_grouped`], sorted by
+/// group. Each message comes with its sequence number, and each list is in
+/// the order the messages were fetched.
+#[derive(Debug, Default)]
+pub struct GroupedMessages {
+    /// The messages of each group.
+    pub groups: HashMap<DsGroupId, Vec<(u64, MlsMessageIn)>>,
+    /// Messages that don't belong to a known group, i.e. welcomes, and
+    /// messages whose group ID isn't a valid [`DsGroupId`].
+    pub other: Vec<(u64, MlsMessageIn)>,
 }
 
 /// An API client that is not yet registered with the DS. Call `register` to
 /// register the client and obtain an [`ApiClient`].
 pub struct UnregisteredApiClient {
     connection: DsConnection,
+    config: ClientConfig,
 }
 
 impl UnregisteredApiClient {
-    /// Create a new API client that is not yet registered with the DS.
-    pub fn new(ds_url: Url) -> Self {
-        let connection = DsConnection::new(ds_url);
-        Self { connection }
+    /// Create a new API client that is not yet registered with the DS. A
+    /// [`DsUrl`] can be obtained from a [`reqwest::Url`] via [`TryFrom`],
+    /// which ensures that the URL is usable.
+    pub fn new(ds_url: DsUrl) -> Self {
+        let connection = DsConnection::new(ds_url.into());
+        Self {
+            connection,
+            config: ClientConfig::default(),
+        }
+    }
+
+    /// Create a builder to configure the HTTP client used to talk to the DS,
+    /// e.g. to add headers required by a gateway in front of the DS.
+    pub fn builder(ds_url: DsUrl) -> ApiClientBuilder {
+        ApiClientBuilder::new(ds_url.into())
+    }
+
+    /// Open a connection to the DS ahead of time, so that the first request,
+    /// usually [`Self::register`], doesn't pay for DNS resolution and the TLS
+    /// handshake. The connection is reused by clients obtained from this one.
+    pub async fn warmup(&self) -> Result<(), SendMessageError> {
+        self.connection.warmup().await
+    }
+
+    /// Query which optional features the DS supports, e.g. to only use
+    /// long polling if the DS holds fetch requests open.
+    pub async fn capabilities(&self) -> Result<DsCapabilities, CapabilitiesError> {
+        let message = MinimalDsMessageOut::Capabilities;
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::Capabilities(capabilities) => Ok(capabilities),
+            ds_response => Err(CapabilitiesError::UnexpectedResponse {
+                expected: "Capabilities",
+                got: ds_response.variant_name(),
+            }),
+        }
     }
 
     /// Register the client with the DS. The client will be registered with the
     /// key packages provided in `key_packages` and `last_resort_key_package`.
+    /// If strict UUID validation is enabled via
+    /// [`ApiClientBuilder::strict_uuid`], the client ID in the credential must
+    /// be exactly 16 bytes long. At most
+    /// [`ApiClientBuilder::max_key_packages_per_request`] key packages may be
+    /// given. `key_packages` must not be empty unless allowed via
+    /// [`ApiClientBuilder::allow_last_resort_only`].
+    ///
+    /// If the DS assigns a client ID, that ID must match the one in the
+    /// credential. See [`ApiClientBuilder::server_assigned_client_ids`] for
+    /// credentials that don't contain a client ID.
     pub async fn register(
         &self,
-        key_packages: &[MlsMessageOut],
-        last_resort_key_package: &MlsMessageOut,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
     ) -> Result<ApiClient, RegisterClientError> {
-        let MlsMessageBodyOut::KeyPackage(key_package) = last_resort_key_package.body() else {
+        if key_packages.is_empty() && !self.config.allow_last_resort_only {
             return Err(RegisterClientError::InvalidInput(
-                "MlsMessageOut is not a KeyPackage.",
+                "No one-time key packages provided.",
             ));
+        }
+        self.send_registration(key_packages, last_resort_key_package)
+            .await
+    }
+
+    /// Register the client with only a last resort key package, regardless of
+    /// [`ApiClientBuilder::allow_last_resort_only`]. Meant for clients such as
+    /// bots or bridges that don't maintain a supply of one-time key packages.
+    ///
+    /// Every client that adds this client to a group then uses the same last
+    /// resort key package, so the joins of all these groups are encrypted to
+    /// the same HPKE key. Compromising that key exposes all of them until a
+    /// new last resort key package is uploaded, which weakens forward secrecy
+    /// compared to one-time key packages.
+    pub async fn register_last_resort_only(
+        &self,
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<ApiClient, RegisterClientError> {
+        self.send_registration(&[], last_resort_key_package).await
+    }
+
+    /// Derive the client ID that registering with `last_resort_key_package`
+    /// would use, without contacting the DS. The key package is validated as
+    /// in [`Self::register`], including strict UUID validation if enabled.
+    pub fn derive_client_id(
+        &self,
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<DsClientId, RegisterClientError> {
+        let client_id = client_id_from_key_package(last_resort_key_package.as_message())?;
+        if self.config.strict_uuid {
+            DsClientId::new_uuid(client_id.as_bytes())?;
+        }
+        Ok(client_id)
+    }
+
+    /// Like [`Self::register`], but if the DS already knows the client, obtain
+    /// a fresh auth token for the existing client instead. The DS replaces the
+    /// client's key packages with the given ones.
+    ///
+    /// Since the client has no auth token to authenticate with, the request
+    /// is signed with `signer`, which must hold the signature key the client
+    /// registered with. The signature covers the key packages and the
+    /// current time according to the [`Clock`](clock::Clock) of this client,
+    /// see [`ROTATE_TOKEN_SIGNATURE_LABEL`] for how the DS checks it. A client
+    /// that still has its auth token should use
+    /// [`ApiClient::rotate_auth_token`] instead.
+    pub async fn register_or_rotate(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        signer: &impl Signer,
+    ) -> Result<ApiClient, RegisterClientError> {
+        match self.register(key_packages, last_resort_key_package).await {
+            Err(RegisterClientError::AlreadyRegistered) => (),
+            result => return result,
+        }
+        let derived_client_id = self.credential_client_id(last_resort_key_package)?;
+        let content = RotateTokenContentOut {
+            key_packages,
+            last_resort_key_package,
+            timestamp_ms: millis_since_epoch(self.config.clock.now()),
         };
-        let client_id = DsClientId::from_serialized_credential(
-            key_package.leaf_node().credential().serialized_content(),
-        )?;
+        let message = signed_token_rotation(content, signer)?;
+        let ds_response = self.connection.send_message(message).await?;
+        let token = issued_token(ds_response)?;
+        let client_id = resolve_client_id(derived_client_id, token.client_id.clone())?;
+        Ok(self.api_client(client_id, token))
+    }
+
+    /// Like [`Self::register`], but afterwards performs a cheap authenticated
+    /// request to confirm that the DS accepts the returned auth token.
+    pub async fn register_verified(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<ApiClient, RegisterClientError> {
+        let api_client = self.register(key_packages, last_resort_key_package).await?;
+        api_client
+            .verify_token()
+            .await
+            .map_err(|_| RegisterClientError::TokenVerificationFailed)?;
+        Ok(api_client)
+    }
+
+    /// Register several clients, each as in [`Self::register`], with at most
+    /// [`ApiClientBuilder::batch_concurrency`] registrations in flight at
+    /// once. Returns the result for each client in the order of
+    /// `registrations`.
+    pub async fn register_many(
+        &self,
+        registrations: Vec<(Vec<OneTimeKeyPackage>, LastResortKeyPackage)>,
+    ) -> Vec<Result<ApiClient, RegisterClientError>> {
+        futures::stream::iter(registrations)
+            .map(|(key_packages, last_resort_key_package)| async move {
+                self.register(&key_packages, &last_resort_key_package).await
+            })
+            .buffered(self.config.batch_concurrency)
+            .collect()
+            .await
+    }
+}
+
+// Helper functions
+impl UnregisteredApiClient {
+    async fn send_registration(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<ApiClient, RegisterClientError> {
+        let max = self.config.max_key_packages_per_request;
+        if key_packages.len() > max {
+            return Err(RegisterClientError::TooManyKeyPackages {
+                max,
+                got: key_packages.len(),
+            });
+        }
+        let derived_client_id = self.credential_client_id(last_resort_key_package)?;
         let request = RegisterClientRequestOut {
             key_packages,
             last_resort_key_package,
         };
         let message = MinimalDsMessageOut::RegisterClient(request);
-        let ds_response = self.connection.send_message(message).await?;
-        let auth_token = match ds_response {
-            MinimalDsResponseIn::AuthToken(token) => token,
-            _ => return Err(RegisterClientError::UnexpectedResponse),
+        let token = match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::AlreadyRegistered => {
+                return Err(RegisterClientError::AlreadyRegistered)
+            }
+            ds_response => issued_token(ds_response)?,
         };
-        Ok(ApiClient {
+        let client_id = resolve_client_id(derived_client_id, token.client_id.clone())?;
+        Ok(self.api_client(client_id, token))
+    }
+
+    /// The client ID contained in the credential of `last_resort_key_package`.
+    /// If the DS assigns client IDs, a credential without a valid client ID
+    /// is accepted and yields `None`.
+    fn credential_client_id(
+        &self,
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<Option<DsClientId>, RegisterClientError> {
+        match self.derive_client_id(last_resort_key_package) {
+            Ok(client_id) => Ok(Some(client_id)),
+            Err(RegisterClientError::InvalidClientId(_))
+                if self.config.server_assigned_client_ids =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn api_client(&self, client_id: DsClientId, token: IssuedToken) -> ApiClient {
+        ApiClient {
             connection: self.connection.clone(),
-            auth_token,
+            config: self.config.clone(),
+            auth_token: token.token,
+            token_expires_at: token.expires_at,
+            server_time: token.server_time,
             client_id,
             last_seen_message_sequence_number: 0,
-        })
+            epoch_tracker: None,
+            recent_sequence_numbers: None,
+            own_leaf_indices: HashMap::new(),
+            group_cursors: HashMap::new(),
+            cursor_callback: None,
+            continuation_token: None,
+            token_refresher: None,
+            refresh_error: None,
+            session_store: None,
+            #[cfg(feature = "stats")]
+            fetch_stats: Default::default(),
+        }
+    }
+
+    fn resumed_client(&self, session: Session) -> ApiClient {
+        let token = IssuedToken {
+            token: session.auth_token,
+            client_id: None,
+            expires_at: None,
+            server_time: None,
+        };
+        let mut api_client = self.api_client(session.client_id, token);
+        api_client.last_seen_message_sequence_number = session.last_seen_sequence_number;
+        api_client
     }
 }
 
 /// An API client that is registered with the DS. It can be used to interact
 /// with the DS through the methods provided.
+///
+/// `ApiClient` is guaranteed to be `Send` and `Sync`, so it can be shared
+/// between tasks, e.g. as `Arc<tokio::sync::Mutex<ApiClient>>`.
 pub struct ApiClient {
     connection: DsConnection,
+    config: ClientConfig,
     client_id: DsClientId,
     auth_token: AuthToken,
+    token_expires_at: Option<SystemTime>,
+    server_time: Option<SystemTime>,
     last_seen_message_sequence_number: u64,
+    epoch_tracker: Option<EpochTracker>,
+    recent_sequence_numbers: Option<RecentSequenceNumbers>,
+    // The leaf index of this client in each group, if set by the caller.
+    own_leaf_indices: HashMap<DsGroupId, LeafNodeIndex>,
+    // The cursor of each group fetched via `fetch_messages_for_group`.
+    group_cursors: HashMap<DsGroupId, u64>,
+    cursor_callback: Option<Box<dyn Fn(u64) + Send + Sync>>,
+    // Set if the DS paginates using opaque continuation tokens.
+    continuation_token: Option<Vec<u8>>,
+    token_refresher: Option<refresh::TokenRefresher>,
+    // The error of the last failed refresh in `with_auto_refresh`.
+    refresh_error: Option<RegisterClientError>,
+    session_store: Option<Arc<dyn SessionStore>>,
+    #[cfg(feature = "stats")]
+    fetch_stats: stats::FetchStatsRecorder,
 }
 
+// Fails to compile if a field makes `ApiClient` lose `Send` or `Sync`.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ApiClient>();
+};
+
 impl ApiClient {
     /// Upload the given key packages to the DS. Key packages are used by other
-    /// clients to add this client to groups.
+    /// clients to add this client to groups. At most
+    /// [`ApiClientBuilder::max_key_packages_per_request`] distinct key
+    /// packages may be given.
+    ///
+    /// Key packages given more than once are only uploaded once, so that
+    /// peers can't be handed the same key package twice. The returned summary
+    /// reports how many were dropped. Key packages uploaded by earlier
+    /// requests are not detected, since the DS doesn't reveal which key
+    /// packages it stores.
     pub async fn upload_key_packages(
         &mut self,
-        key_packages: &[MlsMessageOut],
-        last_resort_key_package: &MlsMessageOut,
-    ) -> Result<(), UploadKeyPackagesError> {
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        self.send_key_packages(key_packages, last_resort_key_package, None)
+            .await
+    }
+
+    /// Like [`Self::upload_key_packages`], but report the progress of sending
+    /// the request to `progress`, e.g. for large batches on slow links.
+    pub async fn upload_key_packages_with_progress(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        progress: UploadProgress,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        self.send_key_packages(key_packages, last_resort_key_package, Some(&progress))
+            .await
+    }
+
+    /// Like [`Self::upload_key_packages`], but only returns once the DS
+    /// confirms that the key packages were persisted durably. If the DS
+    /// only accepted the key packages without confirming persistence, this
+    /// returns [`UploadKeyPackagesError::NotDurable`] and the upload should be
+    /// retried if losing the key packages is not acceptable.
+    pub async fn upload_key_packages_durable(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        let unique_key_packages = crate::key_packages::deduplicate(key_packages);
+        self.check_key_package_count(&unique_key_packages)?;
         let request = requests::UploadKeyPackagesRequestOut {
             credentials: &self.client_credentials(),
+            key_packages: &unique_key_packages,
+            last_resort_key_package,
+        };
+        let message = MinimalDsMessageOut::UploadKeyPackagesDurable(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::Persisted => Ok(KeyPackageUploadSummary {
+                uploaded: unique_key_packages.len(),
+                duplicates: key_packages.len() - unique_key_packages.len(),
+            }),
+            MinimalDsResponseIn::Ok => Err(UploadKeyPackagesError::NotDurable),
+            ds_response => Err(UploadKeyPackagesError::UnexpectedResponse {
+                expected: "Persisted",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Prepare uploading `key_packages` in chunks of at most
+    /// [`ApiClientBuilder::max_key_packages_per_request`] key packages, e.g.
+    /// when there are more than fit into a single request. Key packages given
+    /// more than once are only uploaded once. Nothing is sent until the
+    /// returned handle is passed to [`Self::resume_upload`].
+    pub fn start_upload(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> UploadHandle {
+        UploadHandle::new(
             key_packages,
             last_resort_key_package,
+            self.config.max_key_packages_per_request,
+        )
+    }
+
+    /// Upload the chunks of `handle` that weren't uploaded yet, in order. If
+    /// a chunk fails, the error is returned and `handle` keeps track of the
+    /// chunks uploaded before, so that calling this again continues with the
+    /// failed chunk. The DS skips key packages of a chunk that it already
+    /// stores, so sending a chunk again that the DS received before the
+    /// request failed, e.g. because the response was lost, doesn't store its
+    /// key packages twice.
+    pub async fn resume_upload(
+        &mut self,
+        handle: &mut UploadHandle,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        while let Some(chunk) = handle.next_chunk() {
+            let count = chunk.len();
+            let request = requests::UploadKeyPackagesRequestOut {
+                credentials: &self.client_credentials(),
+                key_packages: chunk,
+                last_resort_key_package: handle.last_resort_key_package(),
+            };
+            let message = MinimalDsMessageOut::UploadKeyPackageChunk(request);
+            self.connection.send_message(message).await?;
+            handle.advance(count);
+        }
+        Ok(handle.summary())
+    }
+
+    /// Replace all key packages of this client stored on the DS by the given
+    /// ones in a single request, e.g. after the one-time key packages were
+    /// compromised. Unlike [`Self::upload_key_packages`], which adds to the
+    /// stored key packages, this discards all previously uploaded key
+    /// packages. The DS only discards them if it accepts the new ones, so if
+    /// this fails, the old key packages are still in place. Returns the
+    /// number of one-time key packages stored afterwards.
+    ///
+    /// Key packages given more than once are only uploaded once. Unlike
+    /// [`Self::upload_key_packages`], no [`KeyPackageUploadSummary`] with the
+    /// number of duplicates is returned, since after a reset the returned
+    /// count is exactly the number of unique key packages the DS accepted.
+    pub async fn reset_key_packages(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<u32, UploadKeyPackagesError> {
+        let unique_key_packages = crate::key_packages::deduplicate(key_packages);
+        self.check_key_package_count(&unique_key_packages)?;
+        let request = requests::UploadKeyPackagesRequestOut {
+            credentials: &self.client_credentials(),
+            key_packages: &unique_key_packages,
+            last_resort_key_package,
         };
-        let message = MinimalDsMessageOut::UploadKeyPackages(request);
-        self.connection.send_message(message).await?;
-        Ok(())
+        let message = MinimalDsMessageOut::ResetKeyPackages(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::KeyPackageCounts(counts) => Ok(counts.one_time),
+            ds_response => Err(UploadKeyPackagesError::UnexpectedResponse {
+                expected: "KeyPackageCounts",
+                got: ds_response.variant_name(),
+            }),
+        }
     }
 
-    /// Obtain a list of all clients registered with the DS.
+    /// Obtain a list of all clients registered with the DS. This pages through
+    /// the full list using [`Self::list_clients_page`].
     pub async fn list_clients(&self) -> Result<Vec<DsClientId>, ListClientsError> {
-        let message = MinimalDsMessageOut::ListClients;
+        let mut client_ids = Vec::new();
+        loop {
+            let offset = client_ids.len() as u32;
+            let page = self
+                .list_clients_page(offset, LIST_CLIENTS_PAGE_SIZE, None)
+                .await?;
+            let page_is_empty = page.client_ids.is_empty();
+            client_ids.extend(page.client_ids);
+            if page_is_empty || client_ids.len() as u32 >= page.total {
+                break;
+            }
+        }
+        Ok(client_ids)
+    }
+
+    /// Obtain a page of at most `limit` clients registered with the DS,
+    /// starting at `offset`. If `prefix` is given, only clients whose ID
+    /// starts with `prefix` are returned. The returned [`ListClientsPage`]
+    /// also contains the total number of matching clients.
+    pub async fn list_clients_page(
+        &self,
+        offset: u32,
+        limit: u32,
+        prefix: Option<&[u8]>,
+    ) -> Result<ListClientsPage, ListClientsError> {
+        let request = ListClientsRequest {
+            credentials: self.client_credentials(),
+            offset,
+            limit,
+            prefix: prefix.map(|prefix| prefix.to_vec()),
+        };
+        let message = MinimalDsMessageOut::ListClients(request);
         let ds_response = self.connection.send_message(message).await?;
-        let client_ids = match ds_response {
-            MinimalDsResponseIn::ListClients(ids) => ids,
-            _ => return Err(ListClientsError::UnexpectedResponse),
+        let response = match ds_response {
+            MinimalDsResponseIn::ListClients(response) => response,
+            ds_response => {
+                return Err(ListClientsError::UnexpectedResponse {
+                    expected: "ListClients",
+                    got: ds_response.variant_name(),
+                })
+            }
+        };
+        Ok(ListClientsPage {
+            client_ids: response.client_ids,
+            total: response.total,
+        })
+    }
+
+    /// Obtain a page of at most `limit` of all groups stored on the DS,
+    /// starting at `offset`, together with the total number of groups, e.g.
+    /// for admin tooling. The DS may restrict this to privileged clients, in
+    /// which case other clients get [`ListGroupsError::Unauthorized`].
+    pub async fn list_groups(
+        &self,
+        offset: u64,
+        limit: u32,
+    ) -> Result<(Vec<DsGroupId>, u64), ListGroupsError> {
+        let request = ListGroupsRequest {
+            credentials: self.client_credentials(),
+            offset,
+            limit,
         };
-        Ok(client_ids.into_iter().map(|id| id.into()).collect())
+        let message = MinimalDsMessageOut::ListGroups(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::ListGroups(response) => Ok((response.group_ids, response.total)),
+            ds_response => Err(ListGroupsError::UnexpectedResponse {
+                expected: "ListGroups",
+                got: ds_response.variant_name(),
+            }),
+        }
     }
 
     /// Create a new group on the DS with the given group info and ratchet tree.
@@ -176,135 +671,2251 @@ impl ApiClient {
         group_info: &MlsMessageOut,
         ratchet_tree: &RatchetTree,
     ) -> Result<(), CreateGroupError> {
+        self.send_create_group(group_info, ratchet_tree, None).await
+    }
+
+    /// Like [`Self::create_group`], but report the progress of sending the
+    /// request to `progress`, e.g. for groups with large ratchet trees.
+    pub async fn create_group_with_progress(
+        &self,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        progress: UploadProgress,
+    ) -> Result<(), CreateGroupError> {
+        self.send_create_group(group_info, ratchet_tree, Some(&progress))
+            .await
+    }
+
+    /// The size in bytes of the request that [`Self::create_group`] would send
+    /// for the given inputs.
+    pub fn create_group_size(
+        &self,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+    ) -> usize {
         let request = requests::CreateGroupRequestOut {
             credentials: &self.client_credentials(),
             group_info,
             ratchet_tree,
         };
-        let message = MinimalDsMessageOut::CreateGroup(request);
-        self.connection.send_message(message).await?;
-        Ok(())
+        MinimalDsMessageOut::CreateGroup(request).tls_serialized_len()
     }
 
-    /// Fetch the key package for the client with the given [`DsClientId`] from the DS.
-    pub async fn fetch_key_package(
+    /// The size in bytes of the request that [`Self::upload_key_packages`]
+    /// would send for the given inputs, i.e. without duplicate key packages.
+    pub fn upload_key_packages_size(
         &self,
-        client_id: DsClientId,
-    ) -> Result<Option<KeyPackageIn>, FetchKeyPackageError> {
-        let request = FetchKeyPackageRequest { client_id };
-        let message = MinimalDsMessageOut::FetchKeyPackage(request);
-        let ds_response = self.connection.send_message(message).await?;
-        let key_package = match ds_response {
-            MinimalDsResponseIn::KeyPackageOption(key_package_option) => key_package_option,
-            _ => return Err(FetchKeyPackageError::UnexpectedResponse),
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> usize {
+        let unique_key_packages = crate::key_packages::deduplicate(key_packages);
+        let request = requests::UploadKeyPackagesRequestOut {
+            credentials: &self.client_credentials(),
+            key_packages: &unique_key_packages,
+            last_resort_key_package,
         };
-        Ok(key_package)
+        MinimalDsMessageOut::UploadKeyPackages(request).tls_serialized_len()
     }
 
-    /// Distribute a group message to all clients in a group. If the group
-    /// message is a commit, `group_info_option` must be provided. `message`
-    /// must be an [`MlsMessageOut`] with either a private or a public
-    /// MLSMessage.
-    pub async fn distribute_group_message(
+    /// Like [`Self::create_group`], but first checks `group_info` and
+    /// `ratchet_tree` using [`verify_group_info`]. This requires hashing the
+    /// full tree and verifying the group info signature, so it is more
+    /// expensive than [`Self::create_group`].
+    pub async fn create_group_checked(
         &self,
-        message: &MlsMessageOut,
-        group_info_option: Option<&MlsMessageOut>,
-    ) -> Result<(), DistributeGroupMessageError> {
-        let message = AssistedMessageOut::new(message.clone(), group_info_option.cloned())
-            .map_err(|e| {
-                let str = match e {
-                    AssistedMessageError::InvalidMessage => "Unexpected MlsMessageBody.",
-                    AssistedMessageError::MissingGroupInfo => "Missing GroupInfo.",
-                };
-                DistributeGroupMessageError::InvalidInput(str)
-            })?;
-        let request = requests::DistributeGroupMessageRequestOut {
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+    ) -> Result<(), CreateGroupError> {
+        verify_group_info(group_info, ratchet_tree)?;
+        self.create_group(group_info, ratchet_tree).await
+    }
+
+    /// Like [`Self::create_group`], but instead of uploading `ratchet_tree`,
+    /// refer to a ratchet tree the DS already stores under `group_info_ref`.
+    /// If the DS does not know the referenced tree, this falls back to
+    /// uploading `ratchet_tree` in full.
+    pub async fn create_group_from_ref(
+        &self,
+        group_info: &MlsMessageOut,
+        group_info_ref: GroupInfoRef,
+        ratchet_tree: &RatchetTree,
+    ) -> Result<(), CreateGroupError> {
+        let request = requests::CreateGroupFromRefRequestOut {
             credentials: &self.client_credentials(),
-            message: &message,
+            group_info,
+            group_info_ref,
         };
-        let message = MinimalDsMessageOut::DistributeGroupMessage(request);
-        self.connection.send_message(message).await?;
-        Ok(())
+        let message = MinimalDsMessageOut::CreateGroupFromRef(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::UnknownGroupInfoRef => {
+                self.create_group(group_info, ratchet_tree).await
+            }
+            ds_response => create_group_result(ds_response),
+        }
     }
 
-    /// Distribute a welcome message to all clients in a group. `message` must be
-    /// a welcome message.
-    pub async fn distribute_welcome(
+    /// Replace the group info and ratchet tree the DS stores for the group
+    /// with the given [`DsGroupId`], e.g. after a commit, so that external
+    /// joins use the current epoch. The DS rejects group infos whose epoch
+    /// isn't newer than that of the stored one with
+    /// [`UpdateGroupInfoError::StaleGroupInfo`].
+    pub async fn update_group_info(
         &self,
-        message: &MlsMessageOut,
-    ) -> Result<(), DistributeWelcomeError> {
-        if !matches!(message.body(), MlsMessageBodyOut::Welcome(_)) {
-            return Err(DistributeWelcomeError::InvalidInput(
-                "MlsMessageOut is not a Welcome message.",
+        group_id: DsGroupId,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+    ) -> Result<(), UpdateGroupInfoError> {
+        self.send_group_info(group_id, group_info, ratchet_tree, None)
+            .await
+    }
+
+    /// Like [`Self::update_group_info`], but the DS only updates the group
+    /// info if the group is in `expected_epoch`, and fails with
+    /// [`UpdateGroupInfoError::PreconditionFailed`] otherwise. This prevents
+    /// concurrent updates from overwriting each other.
+    pub async fn update_group_info_if_epoch(
+        &self,
+        group_id: DsGroupId,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        expected_epoch: u64,
+    ) -> Result<(), UpdateGroupInfoError> {
+        self.send_group_info(group_id, group_info, ratchet_tree, Some(expected_epoch))
+            .await
+    }
+
+    /// Like [`Self::update_group_info`], but only uploads the nodes of
+    /// `ratchet_tree` that differ from `base_tree`, the tree previously
+    /// uploaded for `base_epoch`. Saves bandwidth for large groups in which
+    /// few leaves change per epoch. If the DS no longer stores the base tree,
+    /// or if the delta isn't smaller than the full tree, this falls back to
+    /// uploading `ratchet_tree` in full.
+    pub async fn update_group_info_delta(
+        &self,
+        group_id: DsGroupId,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        base_epoch: u64,
+        base_tree: &RatchetTree,
+    ) -> Result<(), UpdateGroupInfoError> {
+        if !matches!(group_info.body(), MlsMessageBodyOut::GroupInfo(_)) {
+            return Err(UpdateGroupInfoError::InvalidInput(
+                "MlsMessageOut is not a GroupInfo.",
             ));
         }
-        let request = requests::DistributeWelcomeRequestOut { message };
-        let message = MinimalDsMessageOut::DistributeWelcome(request);
-        self.connection.send_message(message).await?;
-        Ok(())
+        let Some(ratchet_tree_delta) = ratchet_tree_delta(base_tree, ratchet_tree)
+            .filter(|delta| delta.tls_serialized_len() < ratchet_tree.tls_serialized_len())
+        else {
+            return self
+                .update_group_info(group_id, group_info, ratchet_tree)
+                .await;
+        };
+        let request = requests::UpdateGroupInfoDeltaRequestOut {
+            credentials: &self.client_credentials(),
+            group_id: group_id.clone(),
+            group_info,
+            base_epoch,
+            ratchet_tree_delta,
+        };
+        let message = MinimalDsMessageOut::UpdateGroupInfoDelta(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::UnknownGroupInfoRef => {
+                self.update_group_info(group_id, group_info, ratchet_tree)
+                    .await
+            }
+            ds_response => update_group_info_result(ds_response),
+        }
     }
 
-    /// Fetch messages from the DS that were sent to this client.
-    pub async fn fetch_messages(&mut self) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
-        let request = FetchMessagesRequest {
+    /// Fetch the latest group info and ratchet tree the DS stores for the
+    /// group with the given [`DsGroupId`], e.g. to join the group via an
+    /// external commit or to recover the group state. Returns `None` if the
+    /// DS doesn't know the group. A group info of a different group is
+    /// rejected with [`FetchGroupInfoError::GroupIdMismatch`]. The group info
+    /// isn't verified against the ratchet tree, which `openmls` does when
+    /// joining.
+    pub async fn fetch_group_info(
+        &self,
+        group_id: DsGroupId,
+    ) -> Result<Option<(MlsMessageIn, RatchetTreeIn)>, FetchGroupInfoError> {
+        let request = FetchGroupInfoRequest {
             credentials: self.client_credentials(),
-            last_seen_sequence_number: self.last_seen_message_sequence_number,
-            number_of_messages: 100,
+            group_id: group_id.clone(),
         };
-        let message = MinimalDsMessageOut::FetchMessages(request);
-        let ds_response = self.connection.send_message(message).await?;
-        match ds_response {
-            MinimalDsResponseIn::FetchMessages(response) => {
-                self.last_seen_message_sequence_number = response
-                    .messages
-                    .last()
-                    .map(|m| m.sequence_number)
-                    .unwrap_or(0);
-                let messages = response
-                    .messages
-                    .into_iter()
-                    .map(|m| m.message.deserialize())
-                    .collect::<Result<Vec<_>, tls_codec::Error>>()?;
-                Ok(messages)
+        let message = MinimalDsMessageOut::FetchGroupInfo(request);
+        let response = match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::GroupInfo(Some(response)) => response,
+            MinimalDsResponseIn::GroupInfo(None) => return Ok(None),
+            ds_response => {
+                return Err(FetchGroupInfoError::UnexpectedResponse {
+                    expected: "GroupInfo",
+                    got: ds_response.variant_name(),
+                })
             }
-            _ => Err(FetchMessagesError::UnexpectedResponse),
+        };
+        let group_info = response.group_info()?;
+        let MlsMessageBodyIn::GroupInfo(verifiable_group_info) = group_info.clone().extract()
+        else {
+            return Err(FetchGroupInfoError::NotGroupInfo);
+        };
+        if verifiable_group_info.group_id() != &GroupId::from(group_id) {
+            return Err(FetchGroupInfoError::GroupIdMismatch);
         }
+        Ok(Some((group_info, response.ratchet_tree()?)))
     }
 
-    /// Delete the group with the given [`DsGroupId`] from the DS.
-    pub async fn delete_group(&self, group_id: DsGroupId) -> Result<(), DeleteGroupError> {
-        let request = DeleteGroupRequest {
-            credentials: self.client_credentials(),
-            group_id: group_id,
+    /// Fetch the key package for the client with the given [`DsClientId`] from the DS.
+    /// A key package whose credential names a different client is rejected
+    /// with [`FetchKeyPackageError::ClientIdMismatch`], so that the DS can't
+    /// substitute the key package of another client.
+    pub async fn fetch_key_package(
+        &self,
+        client_id: DsClientId,
+    ) -> Result<Option<KeyPackageIn>, FetchKeyPackageError> {
+        let request = FetchKeyPackageRequest {
+            client_id: client_id.clone(),
         };
-        let message = MinimalDsMessageOut::DeleteGroup(request);
-        self.connection.send_message(message).await?;
-        Ok(())
+        let message = MinimalDsMessageOut::FetchKeyPackage(request);
+        let ds_response = self.connection.send_message(message).await?;
+        fetched_key_package(ds_response, client_id)
     }
 
-    /// Delete the client with the given [`DsClientId`] from the DS.
-    pub async fn delete_client(&self, client_id: DsClientId) -> Result<(), DeleteClientError> {
-        let request = DeleteClientRequest {
-            credentials: self.client_credentials(),
-            client_id,
+    /// Like [`Self::fetch_key_package`], but only returns a key package that
+    /// is valid for at least `min_remaining` more, so that members aren't
+    /// added with key packages that are about to expire. Returns `None` if
+    /// the client has no such key package. The DS selects the key package,
+    /// but its lifetime is checked against the [`Clock`](clock::Clock) of
+    /// this client as well, and a key package expiring too soon is treated
+    /// as if the DS returned none.
+    pub async fn fetch_key_package_fresh(
+        &self,
+        client_id: DsClientId,
+        min_remaining: Duration,
+    ) -> Result<Option<KeyPackageIn>, FetchKeyPackageError> {
+        let request = FetchFreshKeyPackageRequest {
+            client_id: client_id.clone(),
+            // Round up, so that the DS doesn't return key packages expiring
+            // within the sub-second remainder.
+            min_remaining_seconds: min_remaining.as_secs()
+                + u64::from(min_remaining.subsec_nanos() > 0),
         };
-        let message = MinimalDsMessageOut::DeleteClient(request);
-        self.connection.send_message(message).await?;
-        Ok(())
+        let message = MinimalDsMessageOut::FetchFreshKeyPackage(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let Some(key_package) = fetched_key_package(ds_response, client_id)? else {
+            return Ok(None);
+        };
+        let lifetime = KeyPackageLifetime::parse(&key_package.tls_serialize_detached()?)?;
+        if lifetime.remaining(self.config.clock.now()) < min_remaining {
+            return Ok(None);
+        }
+        Ok(Some(key_package))
     }
 
-    /// Get the client ID of this client.
-    pub fn client_id(&self) -> DsClientId {
-        self.client_id.clone()
+    /// Claim a key package of the client with the given [`DsClientId`]. Unlike
+    /// [`Self::fetch_key_package`], the DS deletes a returned one-time key
+    /// package in the same operation, so that clients claiming concurrently
+    /// never get the same one. Once the one-time key packages are used up,
+    /// the last resort key package is returned. Which of the two was returned
+    /// is read from the key package itself, whose extensions are covered by
+    /// its signature, rather than trusted from the DS.
+    pub async fn claim_key_package(
+        &self,
+        client_id: DsClientId,
+    ) -> Result<Option<ClaimedKeyPackage>, FetchKeyPackageError> {
+        let request = ClaimKeyPackageRequest {
+            client_id: client_id.clone(),
+        };
+        let message = MinimalDsMessageOut::ClaimKeyPackage(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let Some(key_package) = fetched_key_package(ds_response, client_id)? else {
+            return Ok(None);
+        };
+        if has_last_resort_extension(&key_package.tls_serialize_detached()?)? {
+            Ok(Some(ClaimedKeyPackage::LastResort(key_package)))
+        } else {
+            Ok(Some(ClaimedKeyPackage::OneTime(key_package)))
+        }
     }
-}
 
-// Helper functions
-impl ApiClient {
-    fn client_credentials(&self) -> ClientCredentials {
-        ClientCredentials {
-            client_id: self.client_id.clone(),
-            token: self.auth_token,
-        }
+    /// Fetch the key packages of several clients, sending one request per
+    /// client with at most [`ApiClientBuilder::batch_concurrency`] requests
+    /// in flight at once. Returns the result for each client in the order the
+    /// requests complete.
+    pub async fn fetch_key_packages(
+        &self,
+        client_ids: &NonEmpty<DsClientId>,
+    ) -> Vec<(
+        DsClientId,
+        Result<Option<KeyPackageIn>, FetchKeyPackageError>,
+    )> {
+        futures::stream::iter(client_ids.iter().cloned())
+            .map(|client_id| async move {
+                let result = self.fetch_key_package(client_id.clone()).await;
+                (client_id, result)
+            })
+            .buffer_unordered(self.config.batch_concurrency)
+            .collect()
+            .await
+    }
+
+    /// Like [`Self::fetch_key_package`], but additionally validates the key
+    /// package using the crypto backend of `provider`, including its
+    /// signatures and lifetime. A key package that fails validation is
+    /// returned as [`FetchKeyPackageError::InvalidKeyPackage`].
+    pub async fn fetch_key_package_validated(
+        &self,
+        client_id: DsClientId,
+        provider: &impl OpenMlsProvider,
+    ) -> Result<Option<KeyPackage>, FetchKeyPackageError> {
+        let Some(key_package) = self.fetch_key_package(client_id).await? else {
+            return Ok(None);
+        };
+        let key_package = key_package.validate(provider.crypto(), ProtocolVersion::Mls10)?;
+        Ok(Some(key_package))
+    }
+
+    /// Distribute a group message to all clients in a group. If the group
+    /// message is a commit, `group_info_option` must be provided, and
+    /// otherwise it must be `None`. `message`
+    /// must be an [`MlsMessageOut`] with either a private or a public
+    /// MLSMessage. The returned [`DistributionReport`] states how many clients
+    /// the message was enqueued for and which recipients are unknown to the
+    /// DS. Commits and proposals are sent with [`MessagePriority::Control`],
+    /// all other messages with [`MessagePriority::Bulk`].
+    pub async fn distribute_group_message(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.distribute_group_message_with_priority(
+            message,
+            group_info_option,
+            message_priority(message),
+        )
+        .await
+    }
+
+    /// Like [`Self::distribute_group_message`], but the DS only distributes
+    /// the message if the group is in `expected_epoch`, and fails with
+    /// [`DistributeGroupMessageError::PreconditionFailed`] otherwise. Meant
+    /// for commits, so that of two concurrent commits for the same epoch only
+    /// one is distributed. `message` must be a commit for `expected_epoch`.
+    pub async fn distribute_group_message_if_epoch(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        expected_epoch: u64,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        let Some(commit_header) = message_header(message) else {
+            return Err(DistributeGroupMessageError::InvalidInput(
+                "The message header can't be parsed.",
+            ));
+        };
+        if commit_header.kind != MlsMessageKind::Commit {
+            return Err(DistributeGroupMessageError::InvalidInput(
+                "The message is not a commit.",
+            ));
+        }
+        let actual = commit_header.epoch.unwrap_or_default();
+        if actual != expected_epoch {
+            return Err(DistributeGroupMessageError::EpochMismatch {
+                expected: expected_epoch,
+                actual,
+            });
+        }
+        let header = self.check_group_message(message)?;
+        let assisted_message = Self::prepare_group_message(message, group_info_option)?;
+        let request = requests::DistributeGroupMessageIfEpochRequestOut {
+            credentials: &self.client_credentials(),
+            message: &assisted_message,
+            priority: message_priority(message),
+            expected_epoch,
+        };
+        let message = MinimalDsMessageOut::DistributeGroupMessageIfEpoch(request);
+        let report = distribution_report(self.connection.send_message(message).await?)?;
+        self.observe_epoch(header);
+        Ok(report)
+    }
+
+    /// Like [`Self::distribute_group_message`], but attach `metadata`, e.g. a
+    /// routing hint, which the DS stores and relays verbatim to the
+    /// recipients as [`QueuedMessage::metadata`]. The metadata is visible to
+    /// the DS and not authenticated by MLS, so recipients can't rely on it
+    /// having been set by the sender.
+    pub async fn distribute_group_message_with_metadata(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        metadata: &[u8],
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        let header = self.check_group_message(message)?;
+        let assisted_message = Self::prepare_group_message(message, group_info_option)?;
+        let report = self
+            .send_assisted_message(&assisted_message, message_priority(message), metadata)
+            .await?;
+        self.observe_epoch(header);
+        Ok(report)
+    }
+
+    /// Like [`Self::distribute_group_message`], but with an explicit delivery
+    /// priority.
+    pub async fn distribute_group_message_with_priority(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        priority: MessagePriority,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        let header = self.check_group_message(message)?;
+        let assisted_message = Self::prepare_group_message(message, group_info_option)?;
+        let report = self
+            .distribute_assisted_message(&assisted_message, priority)
+            .await?;
+        self.observe_epoch(header);
+        Ok(report)
+    }
+
+    /// Distribute messages to several groups in a single request, e.g. when
+    /// relaying into many groups. Each item consists of a message and, for
+    /// commits, the group info, as for [`Self::distribute_group_message`].
+    /// The returned results are in the same order as `items`. Items that
+    /// can't be prepared, e.g. because of an epoch mismatch, fail
+    /// individually and are not sent, without affecting the other items.
+    pub async fn distribute_group_messages(
+        &self,
+        items: &[(MlsMessageOut, Option<MlsMessageOut>)],
+    ) -> Result<Vec<Result<(), DistributeGroupMessageError>>, DistributeGroupMessageError> {
+        let mut results = Vec::with_capacity(items.len());
+        let mut prepared = Vec::new();
+        for (message, group_info_option) in items {
+            let item = self.check_group_message(message).and_then(|header| {
+                let assisted_message =
+                    Self::prepare_group_message(message, group_info_option.as_ref())?;
+                Ok((assisted_message, message_priority(message), header))
+            });
+            match item {
+                Ok(item) => {
+                    prepared.push(item);
+                    results.push(Ok(()));
+                }
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        if prepared.is_empty() {
+            return Ok(results);
+        }
+        let messages: Vec<_> = prepared
+            .iter()
+            .map(|(message, priority, _)| requests::GroupMessageOut {
+                message,
+                priority: *priority,
+            })
+            .collect();
+        let request = requests::DistributeGroupMessagesRequestOut {
+            credentials: &self.client_credentials(),
+            messages: &messages,
+        };
+        let message = MinimalDsMessageOut::DistributeGroupMessages(request);
+        let sent_results = match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::BatchResults(sent_results)
+                if sent_results.len() != prepared.len() =>
+            {
+                return Err(DistributeGroupMessageError::BatchLengthMismatch {
+                    sent: prepared.len(),
+                    received: sent_results.len(),
+                })
+            }
+            MinimalDsResponseIn::BatchResults(sent_results) => sent_results,
+            ds_response => {
+                return Err(DistributeGroupMessageError::UnexpectedResponse {
+                    expected: "BatchResults",
+                    got: ds_response.variant_name(),
+                })
+            }
+        };
+        // The items that failed locally weren't sent, so the remaining `Ok`s
+        // correspond to the sent items in order.
+        let pending = results.iter_mut().filter(|result| result.is_ok());
+        for (result, ((_, _, header), sent_result)) in
+            pending.zip(prepared.into_iter().zip(sent_results))
+        {
+            *result = batch_item_result(sent_result).map_err(DistributeGroupMessageError::from);
+            if result.is_ok() {
+                self.observe_epoch(header);
+            }
+        }
+        Ok(results)
+    }
+
+    /// The size in bytes of the request that [`Self::distribute_group_message`]
+    /// would send for the given inputs.
+    pub fn distribute_group_message_size(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+    ) -> Result<usize, DistributeGroupMessageError> {
+        let priority = message_priority(message);
+        let message = Self::prepare_group_message(message, group_info_option)?;
+        let request = requests::DistributeGroupMessageRequestOut {
+            credentials: &self.client_credentials(),
+            message: (&message).into(),
+            priority,
+            metadata: &[],
+        };
+        Ok(MinimalDsMessageOut::DistributeGroupMessage(request).tls_serialized_len())
+    }
+
+    /// Like [`Self::distribute_group_message`], but only distributes the
+    /// message to the given `recipients` instead of all clients in the group.
+    pub async fn distribute_group_message_to(
+        &self,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+        recipients: &NonEmpty<DsClientId>,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        let header = self.check_group_message(message)?;
+        let priority = message_priority(message);
+        let message = Self::prepare_group_message(message, group_info_option)?;
+        let request = requests::DistributeGroupMessageToRequestOut {
+            credentials: &self.client_credentials(),
+            message: &message,
+            recipients: recipients.as_slice(),
+            priority,
+        };
+        let message = MinimalDsMessageOut::DistributeGroupMessageTo(request);
+        let report = distribution_report(self.connection.send_message(message).await?)?;
+        self.observe_epoch(header);
+        Ok(report)
+    }
+
+    /// Build the [`AssistedMessageOut`] that [`Self::distribute_group_message`]
+    /// would send, without sending it. The same requirements on `message` and
+    /// `group_info_option` apply. The result can be inspected and then sent
+    /// via [`Self::distribute_assisted_message`].
+    pub fn prepare_group_message(
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+    ) -> Result<AssistedMessageOut, DistributeGroupMessageError> {
+        // Messages whose header can't be parsed are left to
+        // `AssistedMessageOut::new`.
+        if let Some(header) = message_header(message) {
+            match (header.kind, group_info_option) {
+                (MlsMessageKind::Commit, None) => {
+                    return Err(DistributeGroupMessageError::InvalidInput(
+                        "The message is a commit, but no group info was provided.",
+                    ));
+                }
+                (kind, Some(_)) if kind != MlsMessageKind::Commit => {
+                    return Err(DistributeGroupMessageError::InvalidInput(
+                        "A group info was provided, but the message is not a commit.",
+                    ));
+                }
+                _ => {}
+            }
+        }
+        let message = AssistedMessageOut::new(message.clone(), group_info_option.cloned())?;
+        Ok(message)
+    }
+
+    /// Distribute an already constructed [`AssistedMessageOut`] to all clients
+    /// in a group with the given delivery priority.
+    pub async fn distribute_assisted_message(
+        &self,
+        message: &AssistedMessageOut,
+        priority: MessagePriority,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        self.send_assisted_message(message, priority, &[]).await
+    }
+
+    /// Distribute a welcome message to all clients in a group. `message` must be
+    /// a welcome message.
+    pub async fn distribute_welcome(
+        &self,
+        message: &MlsMessageOut,
+    ) -> Result<(), DistributeWelcomeError> {
+        if !matches!(message.body(), MlsMessageBodyOut::Welcome(_)) {
+            return Err(DistributeWelcomeError::InvalidInput(
+                "MlsMessageOut is not a Welcome message.",
+            ));
+        }
+        let request = requests::DistributeWelcomeRequestOut { message };
+        let message = MinimalDsMessageOut::DistributeWelcome(request);
+        self.connection.send_message(message).await?;
+        Ok(())
+    }
+
+    /// Like [`Self::distribute_welcome`], but enqueues the welcome for the
+    /// given `recipients` regardless of which clients the welcome refers to,
+    /// e.g. to notify clients joining through an external commit.
+    pub async fn distribute_welcome_to(
+        &self,
+        message: &MlsMessageOut,
+        recipients: &NonEmpty<DsClientId>,
+    ) -> Result<(), DistributeWelcomeError> {
+        if !matches!(message.body(), MlsMessageBodyOut::Welcome(_)) {
+            return Err(DistributeWelcomeError::InvalidInput(
+                "MlsMessageOut is not a Welcome message.",
+            ));
+        }
+        let request = requests::DistributeWelcomeToRequestOut {
+            credentials: &self.client_credentials(),
+            message,
+            recipients: recipients.as_slice(),
+        };
+        let message = MinimalDsMessageOut::DistributeWelcomeTo(request);
+        self.connection.send_message(message).await?;
+        Ok(())
+    }
+
+    /// Add `member` to the group with the given ID by first distributing
+    /// `commit`, which must add `member` to the group, together with the
+    /// resulting `group_info`, and then distributing `welcome`. The welcome is
+    /// only distributed if the commit was accepted, since otherwise it would
+    /// refer to an epoch that doesn't exist on the DS. All inputs are checked
+    /// before sending the commit. Returns the report for the distributed
+    /// commit.
+    ///
+    /// `member` is not checked against `commit` or `welcome`, which may be
+    /// encrypted. It is only used to report which member wasn't welcomed,
+    /// see [`AddMemberError::WelcomeFailed`].
+    pub async fn add_member(
+        &self,
+        group_id: DsGroupId,
+        member: DsClientId,
+        commit: &MlsMessageOut,
+        group_info: &MlsMessageOut,
+        welcome: &MlsMessageOut,
+    ) -> Result<DistributionReport, AddMemberError> {
+        let Some(header) = message_header(commit) else {
+            return Err(AddMemberError::InvalidInput(
+                "Failed to parse the header of the commit.",
+            ));
+        };
+        if header.kind != MlsMessageKind::Commit {
+            return Err(AddMemberError::InvalidInput(
+                "MlsMessageOut is not a Commit.",
+            ));
+        }
+        if header.group_id.as_deref() != Some(group_id.as_slice().as_slice()) {
+            return Err(AddMemberError::InvalidInput(
+                "The commit belongs to a different group.",
+            ));
+        }
+        if !matches!(welcome.body(), MlsMessageBodyOut::Welcome(_)) {
+            return Err(AddMemberError::InvalidInput(
+                "MlsMessageOut is not a Welcome message.",
+            ));
+        }
+        let report = self
+            .distribute_group_message(commit, Some(group_info))
+            .await
+            .map_err(AddMemberError::CommitFailed)?;
+        self.distribute_welcome(welcome)
+            .await
+            .map_err(|error| AddMemberError::WelcomeFailed { member, error })?;
+        Ok(report)
+    }
+
+    /// Fetch messages from the DS that were sent to this client. If any of
+    /// the messages can't be deserialized, the whole batch fails. Use
+    /// [`Self::fetch_messages_lenient`] to handle such messages individually.
+    ///
+    /// Messages are queued per client, not per group, so messages queued
+    /// before this client was removed from a group, including the commit
+    /// removing it, are still delivered after the removal. Fetched messages
+    /// don't indicate the removal, since the DS can't read private commits.
+    /// It's detected when processing the removing commit, e.g. using
+    /// `StagedCommit::self_removed` in openmls, and sending to the group
+    /// afterwards fails with [`DistributeGroupMessageError::RemovedFromGroup`].
+    pub async fn fetch_messages(&mut self) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        let messages = self
+            .fetch_messages_lenient()
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<_>, tls_codec::Error>>()?;
+        Ok(messages)
+    }
+
+    /// Fetch messages from the DS that were sent to this client, returning a
+    /// separate result for each message. The cursor advances past all fetched
+    /// messages, including those that could not be deserialized.
+    pub async fn fetch_messages_lenient(
+        &mut self,
+    ) -> Result<Vec<Result<MlsMessageIn, tls_codec::Error>>, FetchMessagesError> {
+        let messages = self
+            .fetch_queued_messages()
+            .await?
+            .into_iter()
+            .map(|m| m.message.deserialize())
+            .collect();
+        Ok(messages)
+    }
+
+    /// Fetch messages from the DS that were sent to this client without
+    /// deserializing them, together with their sequence number and, if
+    /// provided by the DS, the time at which they were enqueued. Advances the
+    /// cursor past the fetched messages. The [`MessageRoute`] of each message,
+    /// obtained via `message.route()`, tells welcomes to new groups apart from
+    /// messages of existing groups.
+    pub async fn fetch_queued_messages(
+        &mut self,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        self.fetch_queued_messages_waiting(0).await
+    }
+
+    /// Fetch up to 100 messages that the DS enqueued for this client at or
+    /// after `since`, e.g. to sync against a wall-clock checkpoint, oldest
+    /// first. Enqueue times have millisecond precision, and the bound is
+    /// inclusive: all messages sharing the timestamp `since` are returned, so
+    /// fetching again from the enqueue time of the last returned message
+    /// returns it again, together with any other messages enqueued in the
+    /// same millisecond. Such repeats can be skipped by their sequence
+    /// number. The cursor advances past the returned messages, but never moves
+    /// backwards.
+    pub async fn fetch_messages_since(
+        &mut self,
+        since: SystemTime,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        let request = FetchMessagesSinceRequest {
+            credentials: self.client_credentials(),
+            since_ms: millis_since_epoch(since),
+            number_of_messages: 100,
+        };
+        let message = MinimalDsMessageOut::FetchMessagesSince(request);
+        let messages = match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::FetchMessagesWithTimestamps(response) => response
+                .messages
+                .into_iter()
+                .map(QueuedMessage::from)
+                .collect::<Vec<_>>(),
+            ds_response => {
+                return Err(FetchMessagesError::UnexpectedResponse {
+                    expected: "FetchMessagesWithTimestamps",
+                    got: ds_response.variant_name(),
+                })
+            }
+        };
+        self.record_batch(messages.len());
+        let last_sequence_number = messages.last().map(|m| m.sequence_number);
+        if last_sequence_number > Some(self.last_seen_message_sequence_number) {
+            self.observe_fetched(&messages);
+        }
+        Ok(messages)
+    }
+
+    /// Fetch all queued messages page by page until no messages are left,
+    /// calling `checkpoint` with the new cursor and the messages of each page.
+    /// The cursor only advances past a page once `checkpoint` returned
+    /// successfully for it, so `checkpoint` can persist the messages and the
+    /// cursor to resume an interrupted drain. If `checkpoint` fails, draining
+    /// stops and the error is returned. Returns the number of drained
+    /// messages.
+    ///
+    /// If deduplication is enabled, a page may consist only of messages that
+    /// were already fetched. Draining continues past such a page, and
+    /// `checkpoint` is called with the new cursor and no messages.
+    pub async fn drain_messages_with_checkpoint<E>(
+        &mut self,
+        mut checkpoint: impl FnMut(u64, &[MlsMessageIn]) -> Result<(), E>,
+    ) -> Result<usize, DrainMessagesError<E>> {
+        let mut drained = 0;
+        loop {
+            let page = self.fetch_page(0, 100).await?;
+            // Stop once the DS returns no messages, or only stale ones.
+            let Some(cursor) = page
+                .last_sequence_number
+                .filter(|&cursor| cursor > self.last_seen_message_sequence_number)
+            else {
+                self.commit_page(&page);
+                return Ok(drained);
+            };
+            let messages = page
+                .messages
+                .iter()
+                .map(|m| m.message.deserialize())
+                .collect::<Result<Vec<_>, tls_codec::Error>>()
+                .map_err(FetchMessagesError::from)?;
+            checkpoint(cursor, &messages).map_err(DrainMessagesError::Checkpoint)?;
+            self.commit_page(&page);
+            drained += messages.len();
+        }
+    }
+
+    /// Like [`Self::fetch_messages`], but if no messages are queued, the DS
+    /// holds the request open until a message arrives or `max_wait` elapses,
+    /// whichever comes first. Returns an empty list if no message arrived in
+    /// time. This avoids frequent empty polls for clients that rarely
+    /// receive messages.
+    pub async fn fetch_messages_blocking(
+        &mut self,
+        max_wait: Duration,
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        let wait_ms = u32::try_from(max_wait.as_millis()).unwrap_or(u32::MAX);
+        let messages = self
+            .fetch_queued_messages_waiting(wait_ms)
+            .await?
+            .into_iter()
+            .map(|m| m.message.deserialize())
+            .collect::<Result<Vec<_>, tls_codec::Error>>()?;
+        Ok(messages)
+    }
+
+    /// Like [`Self::fetch_messages`], but the DS only returns messages of the
+    /// given `kinds`, e.g. only application messages. Messages of other kinds
+    /// are skipped, but still count as fetched: the cursor advances past them,
+    /// so they won't be returned by subsequent fetches either. An empty list of
+    /// `kinds` returns messages of all kinds.
+    pub async fn fetch_messages_filtered(
+        &mut self,
+        kinds: &[MlsMessageKind],
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        self.fetch_messages_skipping(kinds, false).await
+    }
+
+    /// Like [`Self::fetch_messages`], but the DS skips messages it recorded as
+    /// sent by this client, such as its own commits echoed back to it. As with
+    /// [`Self::fetch_messages_filtered`], skipped messages count as fetched.
+    pub async fn fetch_messages_excluding_own(
+        &mut self,
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        self.fetch_messages_skipping(&[], true).await
+    }
+
+    /// Fetch up to `limit` messages queued for this client that belong to the
+    /// group with the given [`DsGroupId`], so that the messages of each group
+    /// can be processed independently, e.g. in parallel or by priority.
+    ///
+    /// Each group has its own cursor, which starts at zero and advances past
+    /// the messages fetched via this method, see [`Self::group_cursor`]. The
+    /// cursor of [`Self::fetch_messages`] is independent of it, so messages
+    /// fetched via this method are also returned by [`Self::fetch_messages`].
+    /// Clients should fetch each group's messages either per group or through
+    /// the shared queue, not both.
+    pub async fn fetch_messages_for_group(
+        &mut self,
+        group_id: DsGroupId,
+        limit: u32,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        let last_seen_sequence_number = self.group_cursor(&group_id);
+        let request = FetchGroupMessagesRequest {
+            credentials: self.client_credentials(),
+            group_id,
+            last_seen_sequence_number,
+            number_of_messages: limit,
+        };
+        let message = MinimalDsMessageOut::FetchGroupMessages(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let mut messages = queued_messages(ds_response)?;
+        self.record_batch(messages.len());
+        messages.retain(|m| m.sequence_number > last_seen_sequence_number);
+        if let Some(max_sequence_number) = messages.iter().map(|m| m.sequence_number).max() {
+            self.group_cursors.insert(group_id, max_sequence_number);
+        }
+        Ok(messages)
+    }
+
+    /// Fetch up to `limit` messages with a single request, like
+    /// [`Self::fetch_messages`], and sort them by the group they belong to,
+    /// so that each group's messages can be processed on their own. The group
+    /// ID is read from the header of each message. The cursor advances past
+    /// all fetched messages, independently of the cursors of
+    /// [`Self::fetch_messages_for_group`].
+    pub async fn fetch_messages_grouped(
+        &mut self,
+        limit: u32,
+    ) -> Result<GroupedMessages, FetchMessagesError> {
+        let page = self.fetch_page(0, limit).await?;
+        let mut grouped = GroupedMessages::default();
+        for message in &page.messages {
+            let mls_message = message.message.deserialize()?;
+            let group_id = message
+                .message
+                .header()
+                .ok()
+                .and_then(|header| header_group_id(&header));
+            let bucket = match group_id {
+                Some(group_id) => grouped.groups.entry(group_id).or_default(),
+                None => &mut grouped.other,
+            };
+            bucket.push((message.sequence_number, mls_message));
+        }
+        // Only advance the cursor once all messages could be deserialized.
+        self.commit_page(&page);
+        Ok(grouped)
+    }
+
+    /// The sequence number of the last message fetched for the group with the
+    /// given [`DsGroupId`] via [`Self::fetch_messages_for_group`], or zero if
+    /// none was fetched yet.
+    pub fn group_cursor(&self, group_id: &DsGroupId) -> u64 {
+        self.group_cursors.get(group_id).copied().unwrap_or(0)
+    }
+
+    /// Ask the DS how many messages are queued for this client after the
+    /// cursor, without fetching them, e.g. to show an unread badge.
+    pub async fn queue_depth(&self) -> Result<u64, QueueDepthError> {
+        let request = QueueDepthRequest {
+            credentials: self.client_credentials(),
+            last_seen_sequence_number: self.last_seen_message_sequence_number,
+        };
+        let message = MinimalDsMessageOut::QueueDepth(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::QueueDepth(depth) => {
+                #[cfg(feature = "stats")]
+                self.fetch_stats.record_queue_depth(depth);
+                Ok(depth)
+            }
+            ds_response => Err(QueueDepthError::UnexpectedResponse {
+                expected: "QueueDepth",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Ask the DS to drop all messages queued for this client, e.g. before
+    /// re-syncing group state from scratch. Returns the number of dropped
+    /// messages. If messages were dropped, the cursor is then moved forward to
+    /// the last sequence number ever queued for this client, so that
+    /// subsequent fetches only return new messages. Like fetching, this never
+    /// rewinds the cursor.
+    pub async fn purge_queue(&mut self) -> Result<u64, PurgeQueueError> {
+        let request = PurgeQueueRequest {
+            credentials: self.client_credentials(),
+        };
+        let message = MinimalDsMessageOut::PurgeQueue(request);
+        let response = match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::QueuePurged(response) => response,
+            ds_response => {
+                return Err(PurgeQueueError::UnexpectedResponse {
+                    expected: "QueuePurged",
+                    got: ds_response.variant_name(),
+                })
+            }
+        };
+        if response.purged_count > 0 {
+            self.continuation_token = None;
+            if response.high_water_mark > self.last_seen_message_sequence_number {
+                self.advance_cursor(response.high_water_mark);
+            }
+        }
+        Ok(response.purged_count)
+    }
+
+    /// The request [`Self::fetch_messages`] would send to fetch up to `limit`
+    /// messages after the current cursor or continuation token, without
+    /// sending it, e.g. to dispatch it via a custom transport or to inspect
+    /// it first.
+    pub fn build_fetch_request(&self, limit: u32) -> FetchMessagesRequest {
+        FetchMessagesRequest {
+            credentials: self.client_credentials(),
+            last_seen_sequence_number: self.last_seen_message_sequence_number,
+            number_of_messages: limit,
+            wait_ms: 0,
+            continuation_token: self.continuation_token.clone(),
+            kinds: Vec::new(),
+            exclude_own: false,
+        }
+    }
+
+    /// Fetch the single message with the given sequence number, if it is
+    /// still queued on the DS. Unlike [`Self::fetch_messages`], this doesn't
+    /// advance the cursor.
+    pub async fn fetch_message_at(
+        &self,
+        sequence_number: u64,
+    ) -> Result<Option<MlsMessageIn>, FetchMessagesError> {
+        let Some(last_seen_sequence_number) = sequence_number.checked_sub(1) else {
+            return Ok(None);
+        };
+        let request = FetchMessagesRequest {
+            credentials: self.client_credentials(),
+            last_seen_sequence_number,
+            number_of_messages: 1,
+            wait_ms: 0,
+            continuation_token: None,
+            kinds: Vec::new(),
+            exclude_own: false,
+        };
+        let message = MinimalDsMessageOut::FetchMessages(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let messages = queued_messages(ds_response)?;
+        let Some(message) = messages
+            .into_iter()
+            .find(|m| m.sequence_number == sequence_number)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(message.message.deserialize()?))
+    }
+
+    /// Fetch all messages still queued on the DS with a sequence number in
+    /// `[from, to]`, e.g. to inspect or replay them. Like
+    /// [`Self::fetch_message_at`], this doesn't advance the cursor. The range
+    /// may span at most [`MAX_MESSAGE_RANGE`] sequence numbers.
+    pub async fn fetch_message_range(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        if from > to {
+            return Err(FetchMessagesError::InvalidInput(
+                "The start of the range is after its end.",
+            ));
+        }
+        if to - from >= MAX_MESSAGE_RANGE {
+            return Err(FetchMessagesError::InvalidInput(
+                "The range spans too many sequence numbers.",
+            ));
+        }
+        let request = FetchMessageRangeRequest {
+            credentials: self.client_credentials(),
+            from,
+            to,
+        };
+        let message = MinimalDsMessageOut::FetchMessageRange(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let mut messages = queued_messages(ds_response)?;
+        messages.retain(|m| (from..=to).contains(&m.sequence_number));
+        Ok(messages)
+    }
+
+    /// Delete the group with the given [`DsGroupId`] from the DS.
+    pub async fn delete_group(&self, group_id: DsGroupId) -> Result<(), DeleteGroupError> {
+        let request = DeleteGroupRequest {
+            credentials: self.client_credentials(),
+            group_id,
+        };
+        let message = MinimalDsMessageOut::DeleteGroup(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::Ok => Ok(()),
+            MinimalDsResponseIn::GroupNotFound => Err(DeleteGroupError::NotFound),
+            ds_response => Err(DeleteGroupError::UnexpectedResponse {
+                expected: "Ok",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Like [`Self::delete_group`], but treats a group that doesn't exist
+    /// (anymore) as successfully deleted.
+    pub async fn delete_group_idempotent(
+        &self,
+        group_id: DsGroupId,
+    ) -> Result<(), DeleteGroupError> {
+        match self.delete_group(group_id).await {
+            Err(DeleteGroupError::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Mark the group with the given [`DsGroupId`] for deletion. The DS
+    /// deletes the group once `retain` has passed. Until then, the deletion
+    /// can be undone using [`Self::restore_group`].
+    pub async fn delete_group_soft(
+        &self,
+        group_id: DsGroupId,
+        retain: Duration,
+    ) -> Result<(), DeleteGroupError> {
+        let request = DeleteGroupSoftRequest {
+            credentials: self.client_credentials(),
+            group_id,
+            retain_ms: u64::try_from(retain.as_millis()).unwrap_or(u64::MAX),
+        };
+        let message = MinimalDsMessageOut::DeleteGroupSoft(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::Ok => Ok(()),
+            MinimalDsResponseIn::GroupNotFound => Err(DeleteGroupError::NotFound),
+            ds_response => Err(DeleteGroupError::UnexpectedResponse {
+                expected: "Ok",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Restore a group marked for deletion using [`Self::delete_group_soft`]
+    /// whose grace period hasn't passed yet.
+    pub async fn restore_group(&self, group_id: DsGroupId) -> Result<(), RestoreGroupError> {
+        let request = RestoreGroupRequest {
+            credentials: self.client_credentials(),
+            group_id,
+        };
+        let message = MinimalDsMessageOut::RestoreGroup(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::Ok => Ok(()),
+            MinimalDsResponseIn::NotMarkedForDeletion => Err(RestoreGroupError::NotDeletable),
+            MinimalDsResponseIn::GroupNotFound => Err(RestoreGroupError::NotFound),
+            ds_response => Err(RestoreGroupError::UnexpectedResponse {
+                expected: "Ok",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Delete the client with the given [`DsClientId`] from the DS.
+    pub async fn delete_client(&self, client_id: DsClientId) -> Result<(), DeleteClientError> {
+        let request = DeleteClientRequest {
+            credentials: self.client_credentials(),
+            client_id,
+        };
+        let message = MinimalDsMessageOut::DeleteClient(request);
+        self.connection.send_message(message).await?;
+        Ok(())
+    }
+
+    /// Ask the DS to invalidate the auth token of this client and consume the
+    /// client. Unlike [`Self::delete_client`], the client stays registered
+    /// and keeps its key packages and queued messages, but a new token has
+    /// to be obtained, e.g. via
+    /// [`UnregisteredApiClient::register_or_rotate`], to use it again. A
+    /// session persisted via [`Self::set_session_store`] can't be resumed
+    /// afterwards.
+    pub async fn logout(self) -> Result<(), LogoutError> {
+        let request = LogoutRequest {
+            credentials: self.client_credentials(),
+        };
+        let message = MinimalDsMessageOut::Logout(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::Ok => Ok(()),
+            ds_response => Err(LogoutError::UnexpectedResponse {
+                expected: "Ok",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Obtain an additional auth token for this client, e.g. to use the same
+    /// identity on a second device without registering a new client. The
+    /// current token stays valid, and each token can be revoked separately
+    /// via [`Self::logout`]. The other device can use the token via
+    /// [`UnregisteredApiClient::with_session`].
+    ///
+    /// Messages are queued per client, not per token, and the cursor is kept
+    /// by each [`ApiClient`] rather than by the DS. Each device therefore
+    /// fetches all messages queued for the client, starting from the cursor
+    /// it was given, e.g. the one of this client at the time of provisioning.
+    pub async fn provision_additional_token(&self) -> Result<AuthToken, ProvisionTokenError> {
+        let request = ProvisionTokenRequest {
+            credentials: self.client_credentials(),
+        };
+        let message = MinimalDsMessageOut::ProvisionToken(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::AuthToken(token) => Ok(token),
+            ds_response => Err(ProvisionTokenError::UnexpectedResponse {
+                expected: "AuthToken",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Delete the groups with the given [`DsGroupId`]s from the DS in a single
+    /// request. The returned results are in the same order as `group_ids`.
+    pub async fn delete_groups(
+        &self,
+        group_ids: &NonEmpty<DsGroupId>,
+    ) -> Result<Vec<Result<(), DeleteGroupError>>, DeleteGroupError> {
+        let request = DeleteGroupsRequest {
+            credentials: self.client_credentials(),
+            group_ids: group_ids.to_vec(),
+        };
+        let message = MinimalDsMessageOut::DeleteGroups(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let results = match ds_response {
+            MinimalDsResponseIn::BatchResults(results) if results.len() != group_ids.len() => {
+                return Err(DeleteGroupError::BatchLengthMismatch {
+                    sent: group_ids.len(),
+                    received: results.len(),
+                })
+            }
+            MinimalDsResponseIn::BatchResults(results) => results,
+            ds_response => {
+                return Err(DeleteGroupError::UnexpectedResponse {
+                    expected: "BatchResults",
+                    got: ds_response.variant_name(),
+                })
+            }
+        };
+        Ok(results
+            .into_iter()
+            .map(|result| batch_item_result(result).map_err(DeleteGroupError::from))
+            .collect())
+    }
+
+    /// Delete the clients with the given [`DsClientId`]s from the DS in a
+    /// single request. The returned results are in the same order as
+    /// `client_ids`.
+    pub async fn delete_clients(
+        &self,
+        client_ids: &NonEmpty<DsClientId>,
+    ) -> Result<Vec<Result<(), DeleteClientError>>, DeleteClientError> {
+        let request = DeleteClientsRequest {
+            credentials: self.client_credentials(),
+            client_ids: client_ids.to_vec(),
+        };
+        let message = MinimalDsMessageOut::DeleteClients(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let results = match ds_response {
+            MinimalDsResponseIn::BatchResults(results) if results.len() != client_ids.len() => {
+                return Err(DeleteClientError::BatchLengthMismatch {
+                    sent: client_ids.len(),
+                    received: results.len(),
+                })
+            }
+            MinimalDsResponseIn::BatchResults(results) => results,
+            ds_response => {
+                return Err(DeleteClientError::UnexpectedResponse {
+                    expected: "BatchResults",
+                    got: ds_response.variant_name(),
+                })
+            }
+        };
+        Ok(results
+            .into_iter()
+            .map(|result| batch_item_result(result).map_err(DeleteClientError::from))
+            .collect())
+    }
+
+    /// Ask the DS what it knows about this client. Useful to debug
+    /// mismatches between local and server state.
+    pub async fn whoami(&self) -> Result<ClientInfo, WhoAmIError> {
+        let request = WhoAmIRequest {
+            credentials: self.client_credentials(),
+        };
+        let message = MinimalDsMessageOut::WhoAmI(request);
+        let ds_response = self.connection.send_message(message).await?;
+        match ds_response {
+            MinimalDsResponseIn::WhoAmI(client_info) => Ok(client_info),
+            ds_response => Err(WhoAmIError::UnexpectedResponse {
+                expected: "WhoAmI",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Check whether the DS still accepts the credentials of this client,
+    /// e.g. to evict dead sessions from a pool. Uses [`Self::whoami`], which
+    /// has no side effects. Returns `Ok(false)` if the DS rejects the
+    /// credentials, and an error only if the request itself failed.
+    pub async fn validate_credentials(&self) -> Result<bool, WhoAmIError> {
+        match self.whoami().await {
+            Ok(_) => Ok(true),
+            Err(WhoAmIError::Unauthorized) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Obtain the IDs of all groups on the DS whose ratchet tree contains this
+    /// client. Useful to recover after local group state was lost.
+    pub async fn my_groups(&self) -> Result<Vec<DsGroupId>, MyGroupsError> {
+        let request = MyGroupsRequest {
+            credentials: self.client_credentials(),
+        };
+        let message = MinimalDsMessageOut::MyGroups(request);
+        let ds_response = self.connection.send_message(message).await?;
+        match ds_response {
+            MinimalDsResponseIn::GroupIds(group_ids) => Ok(group_ids),
+            ds_response => Err(MyGroupsError::UnexpectedResponse {
+                expected: "GroupIds",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Notify the DS that this client processed the message with the given
+    /// sequence number, which was fetched for the group with the given
+    /// [`DsGroupId`]. The sender of the message can then see the receipt via
+    /// [`Self::fetch_delivery_receipts`].
+    pub async fn send_delivery_receipt(
+        &self,
+        group_id: DsGroupId,
+        sequence_number: u64,
+    ) -> Result<(), DeliveryReceiptError> {
+        let request = DeliveryReceiptRequest {
+            credentials: self.client_credentials(),
+            group_id,
+            sequence_number,
+        };
+        let message = MinimalDsMessageOut::SendDeliveryReceipt(request);
+        let ds_response = self.connection.send_message(message).await?;
+        match ds_response {
+            MinimalDsResponseIn::Ok => Ok(()),
+            MinimalDsResponseIn::NotGroupMember => Err(DeliveryReceiptError::NotGroupMember),
+            ds_response => Err(DeliveryReceiptError::UnexpectedResponse {
+                expected: "Ok",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Fetch the delivery receipts the recipients sent for a message this
+    /// client distributed to the group with the given [`DsGroupId`]. The
+    /// message is identified by its [`MessageRef`], see
+    /// [`MessageRef::for_message`].
+    pub async fn fetch_delivery_receipts(
+        &self,
+        group_id: DsGroupId,
+        message_ref: MessageRef,
+    ) -> Result<Vec<DeliveryReceipt>, DeliveryReceiptError> {
+        let request = FetchDeliveryReceiptsRequest {
+            credentials: self.client_credentials(),
+            group_id,
+            message_ref,
+        };
+        let message = MinimalDsMessageOut::FetchDeliveryReceipts(request);
+        let ds_response = self.connection.send_message(message).await?;
+        match ds_response {
+            MinimalDsResponseIn::DeliveryReceipts(receipts) => Ok(receipts),
+            MinimalDsResponseIn::NotGroupMember => Err(DeliveryReceiptError::NotGroupMember),
+            ds_response => Err(DeliveryReceiptError::UnexpectedResponse {
+                expected: "DeliveryReceipts",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Start tracking the epoch of each group based on the commits sent and
+    /// fetched by this client. While enabled, distributing a commit whose
+    /// epoch differs from the last known epoch of its group fails with
+    /// [`DistributeGroupMessageError::EpochMismatch`].
+    pub fn enable_epoch_tracking(&mut self) {
+        self.epoch_tracker.get_or_insert_with(EpochTracker::default);
+    }
+
+    /// The last known epoch of the group with the given [`DsGroupId`], if
+    /// epoch tracking is enabled and a commit for the group was observed.
+    pub fn known_epoch(&self, group_id: &DsGroupId) -> Option<u64> {
+        self.epoch_tracker.as_ref()?.epoch(group_id)
+    }
+
+    /// Start dropping fetched messages whose sequence number is among the
+    /// last `window` sequence numbers fetched, e.g. because a retried fetch
+    /// returned them again. Calling this again resets the remembered sequence
+    /// numbers.
+    pub fn enable_deduplication(&mut self, window: usize) {
+        self.recent_sequence_numbers = Some(RecentSequenceNumbers::new(window));
+    }
+
+    /// Record the leaf index of this client in the group with `group_id`,
+    /// e.g. as returned by `MlsGroup::own_leaf_index`. Afterwards, public
+    /// messages for the group whose sender is a different leaf are rejected
+    /// with [`DistributeGroupMessageError::InvalidInput`] before they are
+    /// sent, unless disabled via [`ApiClientBuilder::verify_sender`].
+    pub fn set_own_leaf_index(&mut self, group_id: DsGroupId, leaf_index: LeafNodeIndex) {
+        self.own_leaf_indices.insert(group_id, leaf_index);
+    }
+
+    /// Set a callback that is called with the new cursor, i.e. the sequence
+    /// number of the last fetched message, whenever fetching messages advances
+    /// it. The callback is called synchronously before the fetched messages
+    /// are returned, so persisting the cursor in the callback means that
+    /// messages are delivered at most once across restarts.
+    pub fn on_cursor_advance(&mut self, f: impl Fn(u64) + Send + Sync + 'static) {
+        self.cursor_callback = Some(Box::new(f));
+    }
+
+    /// The opaque continuation token last returned by the DS, if the DS
+    /// paginates fetched messages using such tokens instead of sequence
+    /// numbers. Like the cursor, it can be persisted to resume fetching after
+    /// a restart using [`Self::set_continuation_token`].
+    pub fn continuation_token(&self) -> Option<&[u8]> {
+        self.continuation_token.as_deref()
+    }
+
+    /// Resume fetching messages from the given continuation token, previously
+    /// obtained via [`Self::continuation_token`]. Pass `None` to fall back to
+    /// the sequence number cursor.
+    pub fn set_continuation_token(&mut self, continuation_token: Option<Vec<u8>>) {
+        self.continuation_token = continuation_token;
+    }
+
+    /// Sign every subsequent request with `signer`, which should hold the
+    /// signature key of this client's credential, so that the DS can
+    /// authenticate requests independently of the auth token. The signature
+    /// covers the body and the current time according to the
+    /// [`Clock`](clock::Clock) of this client, and is sent hex-encoded in the
+    /// [`REQUEST_SIGNATURE_HEADER`] header along with the time in the
+    /// [`REQUEST_TIMESTAMP_HEADER`] header. See
+    /// [`REQUEST_SIGNATURE_LABEL`](minimal_ds_types::requests::REQUEST_SIGNATURE_LABEL)
+    /// for how the DS checks it.
+    pub fn enable_request_signing(&mut self, signer: RequestSigner) {
+        self.connection
+            .set_signer(signer, self.config.clock.clone());
+    }
+
+    /// Replace the HTTP client of this client by a new one with the same URL
+    /// and settings, e.g. after a network change left pooled connections
+    /// stale. Subsequent requests open fresh connections. Requests already in
+    /// flight are unaffected and complete on their old connections, as do
+    /// other clients sharing the old connection pool.
+    pub fn reset_connection(&mut self) -> Result<(), reqwest::Error> {
+        self.connection.reset()
+    }
+
+    /// The time at which the auth token of this client expires, if the DS
+    /// reported one. Refreshing the token before then, e.g. via
+    /// [`Self::rotate_auth_token`], avoids failing requests.
+    pub fn token_expires_at(&self) -> Option<SystemTime> {
+        self.token_expires_at
+    }
+
+    /// Whether the auth token of this client expired according to the
+    /// [`Clock`](clock::Clock) of this client. Tokens without a reported
+    /// expiry never expire.
+    pub fn is_token_expired(&self) -> bool {
+        self.token_expires_at
+            .is_some_and(|expires_at| expires_at <= self.config.clock.now())
+    }
+
+    /// The time of the DS when it issued the auth token of this client, if
+    /// reported. Comparing it to the local time at registration gives an
+    /// estimate of the clock offset to the DS.
+    pub fn server_time(&self) -> Option<SystemTime> {
+        self.server_time
+    }
+
+    /// Get the client ID of this client.
+    pub fn client_id(&self) -> DsClientId {
+        self.client_id.clone()
+    }
+
+    /// The URL of the DS this client talks to. Changes if the DS redirects
+    /// the client to its canonical endpoint, see
+    /// [`ApiClientBuilder::follow_ds_redirects`], so callers may want to
+    /// persist it.
+    pub fn ds_url(&self) -> reqwest::Url {
+        self.connection.ds_url()
+    }
+
+    /// Observe the [`ConnectionState`] of this client, e.g. to show whether
+    /// the DS is reachable. The state is derived from the outcome of the
+    /// requests of all clients sharing the connection of this one, i.e.
+    /// those obtained from the same [`UnregisteredApiClient`].
+    pub fn connection_state(&self) -> tokio::sync::watch::Receiver<ConnectionState> {
+        self.connection.health().subscribe()
+    }
+}
+
+/// Extract the messages from a response to a [`FetchMessagesRequest`],
+/// accepting responses both with and without enqueue timestamps.
+fn queued_messages(
+    ds_response: MinimalDsResponseIn,
+) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+    match ds_response {
+        MinimalDsResponseIn::FetchMessages(response) => {
+            Ok(response.messages.into_iter().map(Into::into).collect())
+        }
+        MinimalDsResponseIn::FetchMessagesWithTimestamps(response) => {
+            Ok(response.messages.into_iter().map(Into::into).collect())
+        }
+        MinimalDsResponseIn::FetchMessagesWithToken(response) => {
+            Ok(response.messages.into_iter().map(Into::into).collect())
+        }
+        MinimalDsResponseIn::FetchMessagesFiltered(response) => {
+            Ok(response.messages.into_iter().map(Into::into).collect())
+        }
+        ds_response => Err(FetchMessagesError::UnexpectedResponse {
+            expected: "FetchMessages",
+            got: ds_response.variant_name(),
+        }),
+    }
+}
+
+/// An auth token issued by the DS, with the lifetime information the DS
+/// provided, if any.
+struct IssuedToken {
+    token: AuthToken,
+    /// The client ID, if assigned by the DS.
+    client_id: Option<DsClientId>,
+    expires_at: Option<SystemTime>,
+    server_time: Option<SystemTime>,
+}
+
+/// A page of fetched messages whose cursor hasn't been committed yet.
+struct FetchedPage {
+    messages: Vec<QueuedMessage>,
+    /// The highest sequence number returned by the DS, including those of
+    /// messages dropped as duplicates or stale.
+    last_sequence_number: Option<u64>,
+    /// The continuation token for the next page, if the DS paginates using
+    /// continuation tokens.
+    continuation_token: Option<Option<Vec<u8>>>,
+}
+
+/// Extract the auth token from the response to a registration or token
+/// rotation.
+fn issued_token(ds_response: MinimalDsResponseIn) -> Result<IssuedToken, RegisterClientError> {
+    match ds_response {
+        MinimalDsResponseIn::AuthToken(token) => Ok(IssuedToken {
+            token,
+            client_id: None,
+            expires_at: None,
+            server_time: None,
+        }),
+        MinimalDsResponseIn::Registration(response) => Ok(IssuedToken {
+            token: response.token,
+            client_id: None,
+            expires_at: response.expires_at.map(system_time_from_millis),
+            server_time: Some(system_time_from_millis(response.server_time)),
+        }),
+        MinimalDsResponseIn::AssignedRegistration(response) => Ok(IssuedToken {
+            token: response.registration.token,
+            client_id: Some(response.client_id),
+            expires_at: response
+                .registration
+                .expires_at
+                .map(system_time_from_millis),
+            server_time: Some(system_time_from_millis(response.registration.server_time)),
+        }),
+        ds_response => Err(RegisterClientError::UnexpectedResponse {
+            expected: "AuthToken",
+            got: ds_response.variant_name(),
+        }),
+    }
+}
+
+/// Determine the client ID of a freshly registered client from the ID
+/// contained in its credential and the ID assigned by the DS, if any. If both
+/// are present, they must match.
+fn resolve_client_id(
+    derived: Option<DsClientId>,
+    assigned: Option<DsClientId>,
+) -> Result<DsClientId, RegisterClientError> {
+    match (derived, assigned) {
+        (Some(derived), Some(assigned)) if derived != assigned => {
+            Err(RegisterClientError::ClientIdMismatch { derived, assigned })
+        }
+        (_, Some(client_id)) | (Some(client_id), None) => Ok(client_id),
+        (None, None) => Err(RegisterClientError::MissingClientId),
+    }
+}
+
+fn system_time_from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Sign `content` to rotate the auth token of a client without a valid one,
+/// see [`ROTATE_TOKEN_SIGNATURE_LABEL`].
+fn signed_token_rotation<'a>(
+    content: RotateTokenContentOut<'a>,
+    signer: &impl Signer,
+) -> Result<MinimalDsMessageOut<'a>, RegisterClientError> {
+    let mut signed_content = ROTATE_TOKEN_SIGNATURE_LABEL.to_vec();
+    content
+        .tls_serialize(&mut signed_content)
+        .map_err(SendMessageError::from)?;
+    let signature = signer
+        .sign(&signed_content)
+        .map_err(RegisterClientError::SigningError)?;
+    let request = SignedRotateTokenRequestOut { content, signature };
+    Ok(MinimalDsMessageOut::RotateTokenSigned(request))
+}
+
+/// Milliseconds since the UNIX epoch, or zero for times before it.
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Interpret the response to a request to create a group.
+fn create_group_result(ds_response: MinimalDsResponseIn) -> Result<(), CreateGroupError> {
+    match ds_response {
+        MinimalDsResponseIn::Ok => Ok(()),
+        MinimalDsResponseIn::GroupExists(response) => Err(CreateGroupError::GroupExists {
+            owner_is_self: response.owner_is_self,
+            current_epoch: response.current_epoch,
+        }),
+        ds_response => Err(CreateGroupError::UnexpectedResponse {
+            expected: "Ok",
+            got: ds_response.variant_name(),
+        }),
+    }
+}
+
+fn update_group_info_result(ds_response: MinimalDsResponseIn) -> Result<(), UpdateGroupInfoError> {
+    match ds_response {
+        MinimalDsResponseIn::Ok => Ok(()),
+        MinimalDsResponseIn::StaleGroupInfo(stored_epoch) => {
+            Err(UpdateGroupInfoError::StaleGroupInfo { stored_epoch })
+        }
+        MinimalDsResponseIn::NotGroupMember => Err(UpdateGroupInfoError::NotGroupMember),
+        MinimalDsResponseIn::PreconditionFailed(current_epoch) => {
+            Err(UpdateGroupInfoError::PreconditionFailed { current_epoch })
+        }
+        ds_response => Err(UpdateGroupInfoError::UnexpectedResponse {
+            expected: "Ok",
+            got: ds_response.variant_name(),
+        }),
+    }
+}
+
+/// The delta from `base_tree` to `ratchet_tree`, or `None` if a tree can't be
+/// split into its nodes, e.g. because it contains a credential type unknown
+/// to [`RatchetTreeDelta`].
+fn ratchet_tree_delta(
+    base_tree: &RatchetTree,
+    ratchet_tree: &RatchetTree,
+) -> Option<RatchetTreeDelta> {
+    let base_tree = base_tree.tls_serialize_detached().ok()?;
+    let ratchet_tree = ratchet_tree.tls_serialize_detached().ok()?;
+    RatchetTreeDelta::diff(&base_tree, &ratchet_tree).ok()
+}
+
+/// The default delivery priority of `message`, based on its kind.
+fn message_priority(message: &MlsMessageOut) -> MessagePriority {
+    message_header(message)
+        .map(|header| MessagePriority::for_kind(header.kind))
+        .unwrap_or_default()
+}
+
+/// The key package in a response to a key package request, which must belong
+/// to the client with the given ID.
+fn fetched_key_package(
+    ds_response: MinimalDsResponseIn,
+    client_id: DsClientId,
+) -> Result<Option<KeyPackageIn>, FetchKeyPackageError> {
+    let key_package = match ds_response {
+        MinimalDsResponseIn::KeyPackageOption(key_package_option) => key_package_option,
+        ds_response => {
+            return Err(FetchKeyPackageError::UnexpectedResponse {
+                expected: "KeyPackageOption",
+                got: ds_response.variant_name(),
+            })
+        }
+    };
+    if let Some(key_package) = &key_package {
+        let got = DsClientId::from_credential(&key_package.unverified_credential().credential)?;
+        if got != client_id {
+            return Err(FetchKeyPackageError::ClientIdMismatch {
+                requested: client_id,
+                got,
+            });
+        }
+    }
+    Ok(key_package)
+}
+
+/// The report in a response to a request distributing a group message.
+fn distribution_report(
+    ds_response: MinimalDsResponseIn,
+) -> Result<DistributionReport, DistributeGroupMessageError> {
+    match ds_response {
+        MinimalDsResponseIn::Distributed(report) => Ok(report),
+        MinimalDsResponseIn::NotGroupMember => Err(DistributeGroupMessageError::RemovedFromGroup),
+        MinimalDsResponseIn::PreconditionFailed(current_epoch) => {
+            Err(DistributeGroupMessageError::PreconditionFailed { current_epoch })
+        }
+        ds_response => Err(DistributeGroupMessageError::UnexpectedResponse {
+            expected: "Distributed",
+            got: ds_response.variant_name(),
+        }),
+    }
+}
+
+/// Turn the result of a single batch item into a `Result`, treating errors
+/// like a DS error returned for an individual request.
+fn batch_item_result(result: BatchItemResult) -> Result<(), SendMessageError> {
+    match result {
+        BatchItemResult::Ok => Ok(()),
+        BatchItemResult::Error(message) => Err(SendMessageError::MinimalDsError(
+            String::from_utf8_lossy(&message).into_owned(),
+        )),
+    }
+}
+
+/// The ID of the group a public or private message belongs to, e.g. for
+/// routing the message locally before distributing it. Returns `None` for
+/// other kinds of messages and if the group ID isn't a valid [`DsGroupId`].
+pub fn group_id_of(message: &MlsMessageOut) -> Option<DsGroupId> {
+    header_group_id(&message_header(message)?)
+}
+
+/// The client ID in the credential of the key package in `message`, as used
+/// by [`UnregisteredApiClient::register`]. Unlike
+/// [`UnregisteredApiClient::derive_client_id`], this doesn't apply strict UUID
+/// validation.
+pub fn client_id_from_key_package(
+    message: &MlsMessageOut,
+) -> Result<DsClientId, RegisterClientError> {
+    let MlsMessageBodyOut::KeyPackage(key_package) = message.body() else {
+        return Err(RegisterClientError::InvalidInput(
+            "MlsMessageOut is not a KeyPackage.",
+        ));
+    };
+    Ok(DsClientId::from_credential(
+        key_package.leaf_node().credential(),
+    )?)
+}
+
+/// Verify the signature of `group_info` against the credential of its signer
+/// in `ratchet_tree`, and that `ratchet_tree` matches the tree hash in the
+/// group context of `group_info`. The DS rejects groups for which this check
+/// fails, so running it before [`ApiClient::create_group`] avoids a round
+/// trip. This requires hashing the full tree.
+pub fn verify_group_info(
+    group_info: &MlsMessageOut,
+    ratchet_tree: &RatchetTree,
+) -> Result<(), GroupInfoError> {
+    let group_info_bytes = group_info.tls_serialize_detached()?;
+    let group_info = MlsMessageIn::tls_deserialize_exact_bytes(&group_info_bytes)?;
+    let MlsMessageBodyIn::GroupInfo(verifiable_group_info) = group_info.extract() else {
+        return Err(GroupInfoError::NotGroupInfo);
+    };
+    let ratchet_tree_bytes = ratchet_tree.tls_serialize_detached()?;
+    let ratchet_tree = RatchetTreeIn::tls_deserialize_exact_bytes(&ratchet_tree_bytes)?;
+    let crypto = OpenMlsRustCrypto::default();
+    PublicGroup::from_external(
+        crypto.crypto(),
+        ratchet_tree,
+        verifiable_group_info,
+        ProposalStore::default(),
+    )
+    .map_err(|_| GroupInfoError::VerificationFailed)?;
+    Ok(())
+}
+
+// Helper functions
+impl ApiClient {
+    /// Check that the DS accepts this client's credentials by fetching zero
+    /// messages, which has no side effects on the queue.
+    async fn verify_token(&self) -> Result<(), FetchMessagesError> {
+        let request = FetchMessagesRequest {
+            credentials: self.client_credentials(),
+            last_seen_sequence_number: self.last_seen_message_sequence_number,
+            number_of_messages: 0,
+            wait_ms: 0,
+            continuation_token: None,
+            kinds: Vec::new(),
+            exclude_own: false,
+        };
+        let message = MinimalDsMessageOut::FetchMessages(request);
+        match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::FetchMessages(_) => Ok(()),
+            ds_response => Err(FetchMessagesError::UnexpectedResponse {
+                expected: "FetchMessages",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    async fn fetch_queued_messages_waiting(
+        &mut self,
+        wait_ms: u32,
+    ) -> Result<Vec<QueuedMessage>, FetchMessagesError> {
+        let page = self.fetch_page(wait_ms, 100).await?;
+        self.commit_page(&page);
+        Ok(page.messages)
+    }
+
+    /// Fetch the next page of up to `limit` messages without advancing the
+    /// cursor. Call [`Self::commit_page`] once the page was processed.
+    async fn fetch_page(
+        &self,
+        wait_ms: u32,
+        limit: u32,
+    ) -> Result<FetchedPage, FetchMessagesError> {
+        let request = FetchMessagesRequest {
+            wait_ms,
+            ..self.build_fetch_request(limit)
+        };
+        let message = MinimalDsMessageOut::FetchMessages(request);
+        let ds_response = self.connection.send_message(message).await?;
+        let continuation_token = match &ds_response {
+            MinimalDsResponseIn::FetchMessagesWithToken(response) => {
+                Some(response.continuation_token.clone())
+            }
+            _ => None,
+        };
+        let mut messages = queued_messages(ds_response)?;
+        self.record_batch(messages.len());
+        let last_sequence_number = messages.iter().map(|m| m.sequence_number).max();
+        self.drop_duplicates(&mut messages);
+        // Backends paginating with continuation tokens don't necessarily
+        // number messages in order.
+        if continuation_token.is_none() {
+            self.drop_stale(&mut messages);
+            self.check_sequence_numbers(&messages)?;
+        }
+        Ok(FetchedPage {
+            messages,
+            last_sequence_number,
+            continuation_token,
+        })
+    }
+
+    /// Advance the cursor past a page obtained from [`Self::fetch_page`],
+    /// including messages dropped from it as duplicates.
+    fn commit_page(&mut self, page: &FetchedPage) {
+        if let Some(continuation_token) = &page.continuation_token {
+            self.continuation_token = continuation_token.clone();
+        }
+        if let Some(last_sequence_number) = page.last_sequence_number {
+            if last_sequence_number > self.last_seen_message_sequence_number {
+                self.advance_cursor(last_sequence_number);
+            }
+        }
+        self.observe_fetched(&page.messages);
+    }
+
+    /// Record a fetch that returned `count` messages in the fetch statistics,
+    /// if enabled.
+    fn record_batch(&self, count: usize) {
+        #[cfg(feature = "stats")]
+        self.fetch_stats.record_batch(count);
+        #[cfg(not(feature = "stats"))]
+        let _ = count;
+    }
+
+    /// If deduplication is enabled, drop messages that were already fetched.
+    fn drop_duplicates(&self, messages: &mut Vec<QueuedMessage>) {
+        if let Some(recent) = &self.recent_sequence_numbers {
+            messages.retain(|m| !recent.contains(m.sequence_number));
+        }
+    }
+
+    /// Drop messages whose sequence number isn't greater than the cursor,
+    /// which a misbehaving DS may return again, e.g. after rebuilding its
+    /// queues. The number of dropped messages is recorded in the fetch
+    /// statistics, if enabled.
+    fn drop_stale(&self, messages: &mut Vec<QueuedMessage>) {
+        let count = messages.len();
+        messages.retain(|m| m.sequence_number > self.last_seen_message_sequence_number);
+        #[cfg(feature = "stats")]
+        self.fetch_stats.record_stale(count - messages.len());
+        #[cfg(not(feature = "stats"))]
+        let _ = count;
+    }
+
+    /// Check that the sequence numbers of the fetched messages are strictly
+    /// increasing and greater than the cursor, so that a misbehaving DS
+    /// can't rewind the cursor.
+    fn check_sequence_numbers(&self, messages: &[QueuedMessage]) -> Result<(), FetchMessagesError> {
+        let mut stored = self.last_seen_message_sequence_number;
+        for message in messages {
+            if message.sequence_number <= stored {
+                return Err(FetchMessagesError::NonMonotonicSequence {
+                    stored,
+                    received: message.sequence_number,
+                });
+            }
+            stored = message.sequence_number;
+        }
+        Ok(())
+    }
+
+    /// Advance the cursor to the largest sequence number of the given fetched
+    /// messages, unless it is already past it, and update the
+    /// epoch tracker and the deduplication window, if enabled.
+    fn observe_fetched(&mut self, messages: &[QueuedMessage]) {
+        let Some(max_sequence_number) = messages.iter().map(|m| m.sequence_number).max() else {
+            return;
+        };
+        // Never rewind the cursor, even if the DS returned messages out of
+        // order or below the cursor.
+        if max_sequence_number > self.last_seen_message_sequence_number {
+            self.advance_cursor(max_sequence_number);
+        }
+        if let Some(recent) = &mut self.recent_sequence_numbers {
+            for message in messages {
+                recent.insert(message.sequence_number);
+            }
+        }
+        if let Some(epoch_tracker) = &self.epoch_tracker {
+            for header in messages.iter().filter_map(|m| m.message.header().ok()) {
+                epoch_tracker.observe(&header);
+            }
+        }
+    }
+
+    fn advance_cursor(&mut self, sequence_number: u64) {
+        self.last_seen_message_sequence_number = sequence_number;
+        // See `set_session_store` for why errors are ignored.
+        let _ = self.save_session();
+        if let Some(cursor_callback) = &self.cursor_callback {
+            cursor_callback(sequence_number);
+        }
+    }
+
+    /// Check `message` before sending it: if sender verification is enabled
+    /// and the own leaf index in its group is known, that this client is its
+    /// sender, and, if epoch tracking is enabled, that it matches the last
+    /// known epoch of its group if it is a commit. Returns the parsed header
+    /// so that it can be observed once the message was sent.
+    fn check_group_message(
+        &self,
+        message: &MlsMessageOut,
+    ) -> Result<Option<MlsMessageHeader>, DistributeGroupMessageError> {
+        let verify_sender = self.config.verify_sender && !self.own_leaf_indices.is_empty();
+        if self.epoch_tracker.is_none() && !verify_sender {
+            return Ok(None);
+        }
+        let Some(header) = message_header(message) else {
+            return Ok(None);
+        };
+        if verify_sender {
+            self.check_sender(&header)?;
+        }
+        if let Some(epoch_tracker) = &self.epoch_tracker {
+            epoch_tracker.check_commit(&header).map_err(|expected| {
+                DistributeGroupMessageError::EpochMismatch {
+                    expected,
+                    actual: header.epoch.unwrap_or_default(),
+                }
+            })?;
+        }
+        Ok(Some(header))
+    }
+
+    /// Check that the sender leaf of a public message is the own leaf of
+    /// this client in the message's group, if known. The sender of private
+    /// messages is encrypted and can't be checked.
+    fn check_sender(&self, header: &MlsMessageHeader) -> Result<(), DistributeGroupMessageError> {
+        let (Some(group_id), Some(sender)) = (header_group_id(header), header.sender_leaf_index)
+        else {
+            return Ok(());
+        };
+        match self.own_leaf_indices.get(&group_id) {
+            Some(own) if own.u32() != sender => Err(DistributeGroupMessageError::InvalidInput(
+                "The sender of the message is not this client.",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn send_key_packages(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        progress: Option<&UploadProgress>,
+    ) -> Result<KeyPackageUploadSummary, UploadKeyPackagesError> {
+        let unique_key_packages = crate::key_packages::deduplicate(key_packages);
+        self.check_key_package_count(&unique_key_packages)?;
+        let request = requests::UploadKeyPackagesRequestOut {
+            credentials: &self.client_credentials(),
+            key_packages: &unique_key_packages,
+            last_resort_key_package,
+        };
+        let message = MinimalDsMessageOut::UploadKeyPackages(request);
+        self.connection
+            .send_message_with_progress(message, progress)
+            .await?;
+        Ok(KeyPackageUploadSummary {
+            uploaded: unique_key_packages.len(),
+            duplicates: key_packages.len() - unique_key_packages.len(),
+        })
+    }
+
+    async fn send_create_group(
+        &self,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        progress: Option<&UploadProgress>,
+    ) -> Result<(), CreateGroupError> {
+        let request = requests::CreateGroupRequestOut {
+            credentials: &self.client_credentials(),
+            group_info,
+            ratchet_tree,
+        };
+        let message = MinimalDsMessageOut::CreateGroup(request);
+        let ds_response = self
+            .connection
+            .send_message_with_progress(message, progress)
+            .await?;
+        create_group_result(ds_response)
+    }
+
+    async fn send_group_info(
+        &self,
+        group_id: DsGroupId,
+        group_info: &MlsMessageOut,
+        ratchet_tree: &RatchetTree,
+        expected_epoch: Option<u64>,
+    ) -> Result<(), UpdateGroupInfoError> {
+        if !matches!(group_info.body(), MlsMessageBodyOut::GroupInfo(_)) {
+            return Err(UpdateGroupInfoError::InvalidInput(
+                "MlsMessageOut is not a GroupInfo.",
+            ));
+        }
+        let credentials = &self.client_credentials();
+        let message = match expected_epoch {
+            None => MinimalDsMessageOut::UpdateGroupInfo(requests::UpdateGroupInfoRequestOut {
+                credentials,
+                group_id,
+                group_info,
+                ratchet_tree,
+            }),
+            Some(expected_epoch) => MinimalDsMessageOut::UpdateGroupInfoIfEpoch(
+                requests::UpdateGroupInfoIfEpochRequestOut {
+                    credentials,
+                    group_id,
+                    group_info,
+                    ratchet_tree,
+                    expected_epoch,
+                },
+            ),
+        };
+        let ds_response = self.connection.send_message(message).await?;
+        update_group_info_result(ds_response)
+    }
+
+    async fn send_assisted_message(
+        &self,
+        message: &AssistedMessageOut,
+        priority: MessagePriority,
+        metadata: &[u8],
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        let request = requests::DistributeGroupMessageRequestOut {
+            credentials: &self.client_credentials(),
+            message: message.into(),
+            priority,
+            metadata,
+        };
+        let message = MinimalDsMessageOut::DistributeGroupMessage(request);
+        distribution_report(self.connection.send_message(message).await?)
+    }
+
+    fn observe_epoch(&self, header: Option<MlsMessageHeader>) {
+        if let (Some(epoch_tracker), Some(header)) = (&self.epoch_tracker, header) {
+            epoch_tracker.observe(&header);
+        }
+    }
+
+    fn check_key_package_count(
+        &self,
+        key_packages: &[OneTimeKeyPackage],
+    ) -> Result<(), UploadKeyPackagesError> {
+        let max = self.config.max_key_packages_per_request;
+        if key_packages.len() > max {
+            return Err(UploadKeyPackagesError::TooManyKeyPackages {
+                max,
+                got: key_packages.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// A client with the same connection and credentials, but without the
+    /// fetch cursor, callbacks and epoch tracking of this one. Used for
+    /// background tasks that mustn't interfere with this client's state.
+    fn detached(&self) -> ApiClient {
+        ApiClient {
+            connection: self.connection.clone(),
+            config: self.config.clone(),
+            client_id: self.client_id.clone(),
+            auth_token: self.auth_token,
+            token_expires_at: self.token_expires_at,
+            server_time: self.server_time,
+            last_seen_message_sequence_number: 0,
+            epoch_tracker: None,
+            recent_sequence_numbers: None,
+            own_leaf_indices: HashMap::new(),
+            group_cursors: HashMap::new(),
+            cursor_callback: None,
+            continuation_token: None,
+            token_refresher: None,
+            refresh_error: None,
+            session_store: None,
+            #[cfg(feature = "stats")]
+            fetch_stats: Default::default(),
+        }
+    }
+
+    /// Fetch messages, letting the DS skip messages not matching `kinds` or,
+    /// if `exclude_own` is set, sent by this client.
+    async fn fetch_messages_skipping(
+        &mut self,
+        kinds: &[MlsMessageKind],
+        exclude_own: bool,
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        let request = FetchMessagesRequest {
+            continuation_token: None,
+            kinds: kinds.to_vec(),
+            exclude_own,
+            ..self.build_fetch_request(100)
+        };
+        let message = MinimalDsMessageOut::FetchMessages(request);
+        let response = match self.connection.send_message(message).await? {
+            MinimalDsResponseIn::FetchMessagesFiltered(response) => response,
+            ds_response => {
+                return Err(FetchMessagesError::UnexpectedResponse {
+                    expected: "FetchMessagesFiltered",
+                    got: ds_response.variant_name(),
+                })
+            }
+        };
+        let mut messages: Vec<QueuedMessage> =
+            response.messages.into_iter().map(Into::into).collect();
+        self.record_batch(messages.len());
+        self.drop_duplicates(&mut messages);
+        self.drop_stale(&mut messages);
+        self.check_sequence_numbers(&messages)?;
+        self.observe_fetched(&messages);
+        if response.last_scanned_sequence_number > self.last_seen_message_sequence_number {
+            self.advance_cursor(response.last_scanned_sequence_number);
+        }
+        let messages = messages
+            .into_iter()
+            .map(|m| m.message.deserialize())
+            .collect::<Result<Vec<_>, tls_codec::Error>>()?;
+        Ok(messages)
+    }
+
+    fn client_credentials(&self) -> ClientCredentials {
+        ClientCredentials::Token(self.client_id.clone(), self.auth_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use minimal_ds_types::requests::{request_signature_content, PurgeQueueResponse};
+
+    use super::*;
+    use crate::{
+        requests::Operation,
+        test_utils::{TestClient, TestGroup},
+        testing::MockTransport,
+        transport::TransportRequest,
+    };
+
+    #[test]
+    fn ratchet_tree_delta_round_trip() {
+        let group = TestGroup::new();
+        let base_tree = &group.initial_tree;
+        let new_tree = group.group.export_ratchet_tree();
+        let base = base_tree.tls_serialize_detached().unwrap();
+        let new = new_tree.tls_serialize_detached().unwrap();
+
+        let delta = ratchet_tree_delta(base_tree, &new_tree).unwrap();
+        assert!(!delta.changed_nodes.is_empty());
+        assert_eq!(delta.apply(&base).unwrap(), new);
+
+        // Deltas also remove nodes, e.g. when the tree shrinks.
+        let delta = ratchet_tree_delta(&new_tree, base_tree).unwrap();
+        assert_eq!(delta.apply(&new).unwrap(), base);
+    }
+
+    #[tokio::test]
+    async fn requests_are_sent_through_transport() {
+        let transport = MockTransport::new();
+        let alice = TestClient::new(1);
+        let client = alice.register(&transport).await;
+
+        transport.push_response(MinimalDsResponseIn::KeyPackageOption(None));
+        let key_package = client.fetch_key_package(alice.client_id.clone()).await;
+        assert!(key_package.unwrap().is_none());
+
+        let request = MinimalDsMessageOut::FetchKeyPackage(FetchKeyPackageRequest {
+            client_id: alice.client_id.clone(),
+        });
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].operation, Operation::RegisterClient);
+        assert_eq!(
+            requests[1],
+            TransportRequest {
+                operation: Operation::FetchKeyPackage,
+                body: request.tls_serialize_detached().unwrap(),
+                signature: None,
+            }
+        );
+
+        // Errors of the transport are returned as is.
+        transport.push_error(SendMessageError::MinimalDsError("error".to_owned()));
+        let result = client.fetch_key_package(alice.client_id.clone()).await;
+        assert!(matches!(
+            result,
+            Err(FetchKeyPackageError::FetchKeyPackageError(
+                SendMessageError::MinimalDsError(_)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn request_signatures_are_passed_to_transport() {
+        let transport = MockTransport::new();
+        let alice = TestClient::new(1);
+        let mut client = alice.register(&transport).await;
+        client.enable_request_signing(Arc::new(alice.signer.clone()));
+
+        transport.push_response(MinimalDsResponseIn::KeyPackageOption(None));
+        client
+            .fetch_key_package(alice.client_id.clone())
+            .await
+            .unwrap();
+
+        let request = transport.requests().pop().unwrap();
+        let signature = request.signature.unwrap();
+        let content = request_signature_content(signature.timestamp_ms, &request.body);
+        // Ed25519 signatures are deterministic.
+        assert_eq!(signature.signature, alice.signer.sign(&content).unwrap());
+    }
+
+    #[tokio::test]
+    async fn duplicate_key_packages_are_not_sent() {
+        let transport = MockTransport::new();
+        let alice = TestClient::new(1);
+        let mut client = alice.register(&transport).await;
+        let (key_packages, last_resort_key_package) = alice.key_packages(2);
+        let duplicated = [key_packages.clone(), key_packages.clone()].concat();
+
+        assert_eq!(
+            client.upload_key_packages_size(&duplicated, &last_resort_key_package),
+            client.upload_key_packages_size(&key_packages, &last_resort_key_package)
+        );
+
+        transport.push_response(MinimalDsResponseIn::KeyPackageCounts(KeyPackageCounts {
+            one_time: 2,
+            last_resort: 1,
+        }));
+        client
+            .reset_key_packages(&duplicated, &last_resort_key_package)
+            .await
+            .unwrap();
+        let request =
+            MinimalDsMessageOut::ResetKeyPackages(requests::UploadKeyPackagesRequestOut {
+                credentials: &client.client_credentials(),
+                key_packages: &key_packages,
+                last_resort_key_package: &last_resort_key_package,
+            });
+        let sent = transport.requests().pop().unwrap();
+        assert_eq!(sent.body, request.tls_serialize_detached().unwrap());
+    }
+
+    #[tokio::test]
+    async fn purging_the_queue_never_rewinds_the_cursor() {
+        let transport = MockTransport::new();
+        let alice = TestClient::new(1);
+        let mut client = alice.register(&transport).await;
+        client.last_seen_message_sequence_number = 10;
+
+        transport.push_response(MinimalDsResponseIn::QueuePurged(PurgeQueueResponse {
+            purged_count: 3,
+            high_water_mark: 5,
+        }));
+        assert_eq!(client.purge_queue().await.unwrap(), 3);
+        assert_eq!(client.last_seen_message_sequence_number, 10);
+
+        transport.push_response(MinimalDsResponseIn::QueuePurged(PurgeQueueResponse {
+            purged_count: 3,
+            high_water_mark: 15,
+        }));
+        client.purge_queue().await.unwrap();
+        assert_eq!(client.last_seen_message_sequence_number, 15);
+    }
+
+    #[tokio::test]
+    async fn key_package_of_other_client_is_rejected() {
+        let transport = MockTransport::new();
+        let alice = TestClient::new(1);
+        let bob = TestClient::new(2);
+        let client = alice.register(&transport).await;
+
+        let key_package = bob.key_package();
+        transport.push_response(MinimalDsResponseIn::KeyPackageOption(Some(
+            key_package.into(),
+        )));
+        let result = client.fetch_key_package(alice.client_id.clone()).await;
+        assert!(matches!(
+            result,
+            Err(FetchKeyPackageError::ClientIdMismatch { requested, got })
+                if requested == alice.client_id && got == bob.client_id
+        ));
     }
 }
@@ -0,0 +1,68 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Lists that are known to contain at least one element, for operations such
+//! as sending a message to a set of recipients that are meaningless when
+//! given an empty list.
+
+use std::ops::Deref;
+
+use crate::errors::EmptyListError;
+
+/// A list with at least one element. Dereferences to a slice.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NonEmpty<T>(Vec<T>);
+
+impl<T> NonEmpty<T> {
+    /// Wrap `items`, which must not be empty.
+    pub fn new(items: Vec<T>) -> Result<Self, EmptyListError> {
+        if items.is_empty() {
+            return Err(EmptyListError);
+        }
+        Ok(Self(items))
+    }
+
+    /// A list containing only `item`.
+    pub fn single(item: T) -> Self {
+        Self(vec![item])
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.0
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for NonEmpty<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> TryFrom<Vec<T>> for NonEmpty<T> {
+    type Error = EmptyListError;
+
+    fn try_from(items: Vec<T>) -> Result<Self, Self::Error> {
+        Self::new(items)
+    }
+}
+
+impl<T: Clone> TryFrom<&[T]> for NonEmpty<T> {
+    type Error = EmptyListError;
+
+    fn try_from(items: &[T]) -> Result<Self, Self::Error> {
+        Self::new(items.to_vec())
+    }
+}
+
+impl<T> From<NonEmpty<T>> for Vec<T> {
+    fn from(items: NonEmpty<T>) -> Self {
+        items.0
+    }
+}
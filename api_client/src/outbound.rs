@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Queueing group messages while the DS is unreachable and sending them once
+//! it's reachable again, e.g. for clients on unreliable mobile networks.
+
+use std::sync::Arc;
+
+use minimal_ds_types::requests::{DistributionReport, MessagePriority, MessageRef};
+use openmls::{framing::MlsMessageOut, prelude::tls_codec::Serialize};
+
+use crate::{
+    distribution_report,
+    errors::{DistributeGroupMessageError, OutboundQueueError},
+    message_priority,
+    requests::{self, GroupMessageBody, MinimalDsMessageOut},
+    session::{SessionStore, SessionStoreError},
+    ApiClient,
+};
+
+/// A group message waiting in an [`OutboundQueue`], serialized so that it can
+/// be persisted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundMessage {
+    /// Sent along with the message. The DS distributes a message only once
+    /// per key, so that sending it again is safe if it's unknown whether the
+    /// DS received it, e.g. after a timeout.
+    pub idempotency_key: MessageRef,
+    /// The serialized [`AssistedMessageOut`](crate::AssistedMessageOut).
+    pub message: Vec<u8>,
+    pub priority: MessagePriority,
+}
+
+/// A change of the state of a message in an [`OutboundQueue`].
+#[derive(Debug)]
+pub enum OutboundEvent<'a> {
+    /// The message couldn't be sent and was queued.
+    Queued(&'a OutboundMessage),
+    /// The queued message was sent.
+    Flushed(&'a OutboundMessage, &'a DistributionReport),
+    /// The DS rejected the queued message, which was dropped from the queue.
+    Failed(&'a OutboundMessage, &'a DistributeGroupMessageError),
+}
+
+/// Called by an [`OutboundQueue`] whenever one of its messages is queued,
+/// sent or dropped.
+pub type OutboundObserver = Arc<dyn Fn(OutboundEvent<'_>) + Send + Sync>;
+
+/// The outcome of [`OutboundQueue::flush`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FlushReport {
+    /// The number of messages sent.
+    pub flushed: usize,
+    /// The number of messages the DS rejected, which were dropped.
+    pub failed: usize,
+    /// The number of messages still queued, because the DS became
+    /// unreachable again.
+    pub remaining: usize,
+}
+
+/// Group messages that couldn't be sent because the DS was unreachable, in
+/// the order they were sent. The queue is written through to a
+/// [`SessionStore`] on every change, so that it survives restarts.
+///
+/// Messages are only checked when they are queued. Epoch tracking and
+/// sender verification of the [`ApiClient`] don't apply to them.
+pub struct OutboundQueue {
+    store: Arc<dyn SessionStore>,
+    messages: Vec<OutboundMessage>,
+    observer: Option<OutboundObserver>,
+}
+
+impl OutboundQueue {
+    /// Restore the queue persisted in `store`, which is empty if nothing was
+    /// queued.
+    pub fn load(store: Arc<dyn SessionStore>) -> Result<Self, SessionStoreError> {
+        let messages = store.load_outbound()?;
+        Ok(Self {
+            store,
+            messages,
+            observer: None,
+        })
+    }
+
+    /// Call `observer` whenever a message is queued, sent or dropped.
+    pub fn set_observer(&mut self, observer: OutboundObserver) {
+        self.observer = Some(observer);
+    }
+
+    /// The queued messages, oldest first.
+    pub fn messages(&self) -> &[OutboundMessage] {
+        &self.messages
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Distribute `message` like [`ApiClient::distribute_group_message`],
+    /// or queue it if the DS is unreachable. Returns the distribution report
+    /// if the message was sent and `None` if it was queued.
+    ///
+    /// Previously queued messages are flushed first, so that messages reach
+    /// the DS in order. If any remain queued, `message` is queued behind
+    /// them. If the flush fails, `message` is queued as well before the error
+    /// is returned. Only a [`OutboundQueueError::SessionStoreError`] means
+    /// that `message` might be neither sent nor queued.
+    pub async fn distribute_group_message(
+        &mut self,
+        api_client: &ApiClient,
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+    ) -> Result<Option<DistributionReport>, OutboundQueueError> {
+        let message = Self::prepare(message, group_info_option)?;
+        if let Err(e) = self.flush(api_client).await {
+            self.enqueue(message)
+                .map_err(OutboundQueueError::SessionStoreError)?;
+            return Err(e);
+        }
+        if !self.messages.is_empty() {
+            self.enqueue(message)
+                .map_err(OutboundQueueError::SessionStoreError)?;
+            return Ok(None);
+        }
+        match api_client.send_outbound_message(&message).await {
+            Ok(report) => Ok(Some(report)),
+            Err(e) if e.is_retryable() => {
+                self.enqueue(message)
+                    .map_err(OutboundQueueError::SessionStoreError)?;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Send the queued messages in order, e.g. once connectivity returns.
+    /// Stops at the first message that fails because the DS is unreachable,
+    /// which remains queued together with the following ones. Messages the
+    /// DS rejects are dropped and reported to the observer. Fails without
+    /// dropping the message if the DS rejects the client credentials.
+    pub async fn flush(
+        &mut self,
+        api_client: &ApiClient,
+    ) -> Result<FlushReport, OutboundQueueError> {
+        let mut report = FlushReport::default();
+        while let Some(message) = self.messages.first() {
+            match api_client.send_outbound_message(message).await {
+                Ok(distribution_report) => {
+                    self.notify(OutboundEvent::Flushed(message, &distribution_report));
+                    report.flushed += 1;
+                }
+                Err(e) if e.is_retryable() => break,
+                Err(DistributeGroupMessageError::Unauthorized) => {
+                    return Err(DistributeGroupMessageError::Unauthorized.into());
+                }
+                Err(e) => {
+                    self.notify(OutboundEvent::Failed(message, &e));
+                    report.failed += 1;
+                }
+            }
+            self.messages.remove(0);
+            self.store
+                .save_outbound(&self.messages)
+                .map_err(OutboundQueueError::SessionStoreError)?;
+        }
+        report.remaining = self.messages.len();
+        Ok(report)
+    }
+
+    fn prepare(
+        message: &MlsMessageOut,
+        group_info_option: Option<&MlsMessageOut>,
+    ) -> Result<OutboundMessage, OutboundQueueError> {
+        let assisted_message = ApiClient::prepare_group_message(message, group_info_option)?;
+        Ok(OutboundMessage {
+            idempotency_key: MessageRef::for_message(message)?,
+            message: assisted_message.tls_serialize_detached()?,
+            priority: message_priority(message),
+        })
+    }
+
+    fn enqueue(&mut self, message: OutboundMessage) -> Result<(), SessionStoreError> {
+        self.messages.push(message);
+        if let Err(e) = self.store.save_outbound(&self.messages) {
+            self.messages.pop();
+            return Err(e);
+        }
+        if let Some(message) = self.messages.last() {
+            self.notify(OutboundEvent::Queued(message));
+        }
+        Ok(())
+    }
+
+    fn notify(&self, event: OutboundEvent<'_>) {
+        if let Some(observer) = &self.observer {
+            observer(event);
+        }
+    }
+}
+
+impl ApiClient {
+    async fn send_outbound_message(
+        &self,
+        message: &OutboundMessage,
+    ) -> Result<DistributionReport, DistributeGroupMessageError> {
+        let request = requests::DistributeGroupMessageIdempotentRequestOut {
+            credentials: &self.client_credentials(),
+            message: GroupMessageBody::Serialized(&message.message),
+            priority: message.priority,
+            idempotency_key: message.idempotency_key,
+        };
+        let message = MinimalDsMessageOut::DistributeGroupMessageIdempotent(request);
+        distribution_report(self.connection.send_message(message).await?)
+    }
+}
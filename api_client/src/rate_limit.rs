@@ -0,0 +1,96 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::clock::{Clock, SystemClock};
+
+const MIN_REQUESTS_PER_SECOND: f64 = 0.001;
+
+/// A token bucket that limits the rate of requests sent to the DS.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    requests_per_second: f64,
+    burst: f64,
+    clock: Arc<dyn Clock>,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    // `None` until the first request, while the bucket is full.
+    last_refill: Option<SystemTime>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_second: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            // Guard against division by zero when computing the wait time.
+            requests_per_second: requests_per_second.max(MIN_REQUESTS_PER_SECOND),
+            burst,
+            clock: Arc::new(SystemClock),
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                last_refill: None,
+            }),
+        }
+    }
+
+    /// Measure the time and wait using `clock`.
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Wait until a request may be sent.
+    pub(crate) async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = self.clock.now();
+                // A clock going backwards doesn't refill the bucket.
+                let elapsed = state
+                    .last_refill
+                    .and_then(|last_refill| now.duration_since(last_refill).ok())
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                state.tokens = (state.tokens + elapsed * self.requests_per_second).min(self.burst);
+                state.last_refill = Some(now);
+                if state.tokens >= 1.0 {
+                    state.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - state.tokens) / self.requests_per_second)
+            };
+            self.clock.sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ManualClock;
+
+    #[tokio::test]
+    async fn rate_limiter_waits_on_clock() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let mut rate_limiter = RateLimiter::new(1.0, 1);
+        rate_limiter.set_clock(Arc::new(clock.clone()));
+
+        rate_limiter.acquire().await;
+        let mut second = Box::pin(rate_limiter.acquire());
+        assert!(futures::poll!(&mut second).is_pending());
+
+        clock.advance(Duration::from_millis(500));
+        assert!(futures::poll!(&mut second).is_pending());
+
+        clock.advance(Duration::from_millis(500));
+        second.await;
+    }
+}
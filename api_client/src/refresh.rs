@@ -0,0 +1,153 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Refreshing the auth token of a client when the DS rejects it.
+
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use openmls::prelude::Signer;
+
+use crate::{
+    errors::{IsUnauthorized, RegisterClientError},
+    issued_token,
+    key_packages::{LastResortKeyPackage, OneTimeKeyPackage},
+    millis_since_epoch,
+    requests::{MinimalDsMessageOut, RotateTokenContentOut, RotateTokenRequestOut},
+    resolve_client_id, signed_token_rotation, ApiClient,
+};
+
+/// A callback that obtains a fresh auth token for a client, usually by calling
+/// [`ApiClient::rotate_auth_token_signed`] with new key packages. Since the
+/// callback is also called once the DS rejects the current token,
+/// [`ApiClient::rotate_auth_token`] alone doesn't suffice. See
+/// [`ApiClient::set_token_refresher`].
+pub type TokenRefresher = Arc<
+    dyn for<'c> Fn(&'c mut ApiClient) -> BoxFuture<'c, Result<(), RegisterClientError>>
+        + Send
+        + Sync,
+>;
+
+impl ApiClient {
+    /// Obtain a fresh auth token for this client from the DS, replacing the
+    /// current one. Like
+    /// [`UnregisteredApiClient::register_or_rotate`](crate::UnregisteredApiClient::register_or_rotate),
+    /// this replaces the client's key packages with the given ones. The
+    /// request is authenticated with the current auth token, so it fails with
+    /// [`SendMessageError::Unauthorized`](crate::errors::SendMessageError::Unauthorized)
+    /// once the DS no longer accepts it. Use [`Self::rotate_auth_token_signed`]
+    /// in that case.
+    pub async fn rotate_auth_token(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+    ) -> Result<(), RegisterClientError> {
+        let request = RotateTokenRequestOut {
+            credentials: &self.client_credentials(),
+            key_packages,
+            last_resort_key_package,
+        };
+        let message = MinimalDsMessageOut::RotateToken(request);
+        self.send_token_rotation(message).await
+    }
+
+    /// Like [`Self::rotate_auth_token`], but instead of the current auth
+    /// token, the request is authenticated by a signature of `signer`, which
+    /// must hold the signature key the client registered with. This works
+    /// even once the DS no longer accepts the current token, see
+    /// [`UnregisteredApiClient::register_or_rotate`](crate::UnregisteredApiClient::register_or_rotate).
+    pub async fn rotate_auth_token_signed(
+        &mut self,
+        key_packages: &[OneTimeKeyPackage],
+        last_resort_key_package: &LastResortKeyPackage,
+        signer: &impl Signer,
+    ) -> Result<(), RegisterClientError> {
+        let content = RotateTokenContentOut {
+            key_packages,
+            last_resort_key_package,
+            timestamp_ms: millis_since_epoch(self.config.clock.now()),
+        };
+        let message = signed_token_rotation(content, signer)?;
+        self.send_token_rotation(message).await
+    }
+
+    /// Send a request to rotate the auth token and use the issued token.
+    async fn send_token_rotation(
+        &mut self,
+        message: MinimalDsMessageOut<'_>,
+    ) -> Result<(), RegisterClientError> {
+        let ds_response = self.connection.send_message(message).await?;
+        let token = issued_token(ds_response)?;
+        resolve_client_id(Some(self.client_id.clone()), token.client_id.clone())?;
+        self.auth_token = token.token;
+        self.token_expires_at = token.expires_at;
+        self.server_time = token.server_time;
+        self.save_session()
+            .map_err(RegisterClientError::SessionStoreError)
+    }
+
+    /// Set the callback used by [`Self::with_auto_refresh`] to refresh the
+    /// auth token.
+    pub fn set_token_refresher(&mut self, refresher: TokenRefresher) {
+        self.token_refresher = Some(refresher);
+    }
+
+    /// Run `operation` and, if the DS rejects the auth token, refresh the
+    /// token using the callback set via [`Self::set_token_refresher`] and run
+    /// `operation` once more. The retry happens at most once: if refreshing
+    /// fails or the retried operation is rejected again, the error of the
+    /// last attempt is returned. Without a refresher, `operation` runs once.
+    /// If the token already expired, see [`Self::is_token_expired`], it is
+    /// refreshed before running `operation` instead. If refreshing fails, its
+    /// error can be obtained via [`Self::take_refresh_error`].
+    ///
+    /// ```ignore
+    /// let messages = client
+    ///     .with_auto_refresh(|client| Box::pin(client.fetch_messages()))
+    ///     .await?;
+    /// ```
+    pub async fn with_auto_refresh<T, E>(
+        &mut self,
+        operation: impl for<'c> Fn(&'c mut ApiClient) -> BoxFuture<'c, Result<T, E>>,
+    ) -> Result<T, E>
+    where
+        E: IsUnauthorized,
+    {
+        let Some(refresher) = self.token_refresher.clone() else {
+            return operation(self).await;
+        };
+        if self.is_token_expired() {
+            // If refreshing fails, the operation reports the expired token.
+            self.refresh(&refresher).await;
+            return operation(self).await;
+        }
+        let result = operation(self).await;
+        if !matches!(&result, Err(e) if e.is_unauthorized()) {
+            return result;
+        }
+        if !self.refresh(&refresher).await {
+            return result;
+        }
+        operation(self).await
+    }
+
+    /// The error of the last attempt of [`Self::with_auto_refresh`] to
+    /// refresh the auth token, if it failed. The error is only returned
+    /// once, and replaced by later failures.
+    pub fn take_refresh_error(&mut self) -> Option<RegisterClientError> {
+        self.refresh_error.take()
+    }
+
+    /// Refresh the auth token using `refresher` and return whether it
+    /// succeeded, keeping the error otherwise.
+    async fn refresh(&mut self, refresher: &TokenRefresher) -> bool {
+        match refresher(self).await {
+            Ok(()) => true,
+            Err(e) => {
+                self.refresh_error = Some(e);
+                false
+            }
+        }
+    }
+}
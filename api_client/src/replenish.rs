@@ -0,0 +1,101 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! A background task that keeps the number of key packages on the DS above a
+//! threshold.
+
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::{ApiClient, LastResortKeyPackage, OneTimeKeyPackage};
+
+/// Configuration of the task spawned by [`ApiClient::spawn_replenisher`].
+#[derive(Debug, Clone)]
+pub struct ReplenishConfig {
+    /// Time between two checks of the number of key packages on the DS.
+    pub check_interval: Duration,
+    /// New key packages are uploaded once fewer than this many one-time key
+    /// packages are left.
+    pub low_watermark: u32,
+    /// Number of one-time key packages on the DS after uploading new ones.
+    pub high_watermark: u32,
+}
+
+impl Default for ReplenishConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(600),
+            low_watermark: 10,
+            high_watermark: 100,
+        }
+    }
+}
+
+/// Handle to the task spawned by [`ApiClient::spawn_replenisher`]. The task
+/// stops when the handle is dropped.
+pub struct ReplenisherHandle {
+    task: JoinHandle<()>,
+}
+
+impl ReplenisherHandle {
+    /// Stop the task. Equivalent to dropping the handle.
+    pub fn stop(self) {}
+}
+
+impl Drop for ReplenisherHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ApiClient {
+    /// Spawn a task on the current Tokio runtime that checks the number of
+    /// one-time key packages of this client on the DS every
+    /// [`ReplenishConfig::check_interval`]. If fewer than
+    /// [`ReplenishConfig::low_watermark`] are left, it calls `generator` with
+    /// the number of key packages missing to reach
+    /// [`ReplenishConfig::high_watermark`] (capped at
+    /// [`ApiClientBuilder::max_key_packages_per_request`](crate::builder::ApiClientBuilder::max_key_packages_per_request))
+    /// and uploads the returned key packages and last resort key package, e.g.
+    /// as produced by `testing::generate_key_packages`.
+    ///
+    /// Failed checks and uploads are retried at the next interval. The task
+    /// uses the current auth token of this client, so it needs to be spawned
+    /// again after the token changes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    pub fn spawn_replenisher(
+        &self,
+        config: ReplenishConfig,
+        generator: impl Fn(usize) -> (Vec<OneTimeKeyPackage>, LastResortKeyPackage)
+            + Send
+            + Sync
+            + 'static,
+    ) -> ReplenisherHandle {
+        let mut client = self.detached();
+        let task = tokio::spawn(async move {
+            loop {
+                if let Ok(client_info) = client.whoami().await {
+                    if client_info.key_package_counts.one_time < config.low_watermark {
+                        let missing = config
+                            .high_watermark
+                            .saturating_sub(client_info.key_package_counts.one_time)
+                            as usize;
+                        let count = missing.min(client.config.max_key_packages_per_request);
+                        let (key_packages, last_resort_key_package) = generator(count);
+                        // Errors are retried at the next interval.
+                        let _ = client
+                            .upload_key_packages(&key_packages, &last_resort_key_package)
+                            .await;
+                    }
+                }
+                client.config.clock.sleep(config.check_interval).await;
+            }
+        });
+        ReplenisherHandle { task }
+    }
+}
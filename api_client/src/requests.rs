@@ -2,43 +2,214 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use minimal_ds_types::{requests::FetchMessagesResponse, AuthToken, ClientCredentials, DsClientId};
+use minimal_ds_types::{
+    requests::{GroupInfoRef, MessagePriority, MessageRef},
+    tree_delta::RatchetTreeDelta,
+    ClientCredentials, DsClientId, DsGroupId,
+};
 use mls_assist::messages::AssistedMessageOut;
 use openmls::{
     framing::MlsMessageOut,
-    key_packages::KeyPackageIn,
-    prelude::{tls_codec, TlsDeserializeBytes, TlsSerialize, TlsSize},
+    prelude::{
+        tls_codec::{self, Serialize, Size},
+        TlsSerialize, TlsSize,
+    },
     treesync::RatchetTree,
 };
 
+use crate::key_packages::{LastResortKeyPackage, OneTimeKeyPackage};
+
 // Re-exports
 pub(super) use minimal_ds_types::requests::{
-    DeleteClientRequest, DeleteGroupRequest, FetchKeyPackageRequest, FetchMessagesRequest,
+    ClaimKeyPackageRequest, DeleteClientRequest, DeleteClientsRequest, DeleteGroupRequest,
+    DeleteGroupSoftRequest, DeleteGroupsRequest, DeliveryReceiptRequest,
+    DsResponse as MinimalDsResponseIn, FetchDeliveryReceiptsRequest, FetchFreshKeyPackageRequest,
+    FetchGroupInfoRequest, FetchGroupMessagesRequest, FetchKeyPackageRequest,
+    FetchMessageRangeRequest, FetchMessagesSinceRequest, ListClientsRequest, ListGroupsRequest,
+    LogoutRequest, MyGroupsRequest, ProvisionTokenRequest, PurgeQueueRequest, QueueDepthRequest,
+    RestoreGroupRequest, WhoAmIRequest,
 };
+pub use minimal_ds_types::requests::{DsResponse, FetchMessagesRequest};
 
-#[derive(TlsSize, TlsDeserializeBytes)]
-#[repr(u8)]
-pub(super) enum MinimalDsResponseIn {
-    Ok,
-    AuthToken(AuthToken),
-    KeyPackageOption(Option<KeyPackageIn>),
-    FetchMessages(FetchMessagesResponse),
-    ListClients(Vec<DsClientId>),
-}
+// Discriminants of the `DsResponse` variants carrying fetched messages, used to
+// parse such responses incrementally.
+pub(super) const FETCH_MESSAGES_RESPONSE_TAG: u8 = 3;
+pub(super) const FETCH_MESSAGES_WITH_TIMESTAMPS_RESPONSE_TAG: u8 = 9;
 
+// The discriminants are the tags of the requests on the wire. They must not
+// change, and new variants must use the next free value.
 #[derive(TlsSize, TlsSerialize)]
 #[repr(u8)]
 pub(super) enum MinimalDsMessageOut<'a> {
-    RegisterClient(RegisterClientRequestOut<'a>),
-    UploadKeyPackages(UploadKeyPackagesRequestOut<'a>),
+    RegisterClient(RegisterClientRequestOut<'a>) = 0,
+    UploadKeyPackages(UploadKeyPackagesRequestOut<'a>) = 1,
+    ListClients(ListClientsRequest) = 2,
+    CreateGroup(CreateGroupRequestOut<'a>) = 3,
+    FetchKeyPackage(FetchKeyPackageRequest) = 4,
+    DistributeGroupMessage(DistributeGroupMessageRequestOut<'a>) = 5,
+    DistributeWelcome(DistributeWelcomeRequestOut<'a>) = 6,
+    FetchMessages(FetchMessagesRequest) = 7,
+    DeleteGroup(DeleteGroupRequest) = 8,
+    DeleteClient(DeleteClientRequest) = 9,
+    DeleteGroups(DeleteGroupsRequest) = 10,
+    DeleteClients(DeleteClientsRequest) = 11,
+    WhoAmI(WhoAmIRequest) = 12,
+    DistributeGroupMessageTo(DistributeGroupMessageToRequestOut<'a>) = 13,
+    CreateGroupFromRef(CreateGroupFromRefRequestOut<'a>) = 14,
+    /// Issues a new auth token to a client that still has one. The DS must
+    /// reject the request unless it accepts `credentials`.
+    RotateToken(RotateTokenRequestOut<'a>) = 15,
+    MyGroups(MyGroupsRequest) = 16,
+    UploadKeyPackagesDurable(UploadKeyPackagesRequestOut<'a>) = 17,
+    DeleteGroupSoft(DeleteGroupSoftRequest) = 18,
+    RestoreGroup(RestoreGroupRequest) = 19,
+    DistributeWelcomeTo(DistributeWelcomeToRequestOut<'a>) = 20,
+    Capabilities = 21,
+    PurgeQueue(PurgeQueueRequest) = 22,
+    QueueDepth(QueueDepthRequest) = 23,
+    DistributeGroupMessages(DistributeGroupMessagesRequestOut<'a>) = 24,
+    Logout(LogoutRequest) = 25,
+    FetchMessagesSince(FetchMessagesSinceRequest) = 26,
+    ProvisionToken(ProvisionTokenRequest) = 27,
+    FetchMessageRange(FetchMessageRangeRequest) = 28,
+    /// Replaces all key packages of the client. The DS must only discard the
+    /// old key packages if it accepts the new ones.
+    ResetKeyPackages(UploadKeyPackagesRequestOut<'a>) = 29,
+    FetchGroupInfo(FetchGroupInfoRequest) = 30,
+    UpdateGroupInfo(UpdateGroupInfoRequestOut<'a>) = 31,
+    UpdateGroupInfoDelta(UpdateGroupInfoDeltaRequestOut<'a>) = 32,
+    SendDeliveryReceipt(DeliveryReceiptRequest) = 33,
+    FetchDeliveryReceipts(FetchDeliveryReceiptsRequest) = 34,
+    FetchGroupMessages(FetchGroupMessagesRequest) = 35,
+    ListGroups(ListGroupsRequest) = 36,
+    FetchFreshKeyPackage(FetchFreshKeyPackageRequest) = 37,
+    ClaimKeyPackage(ClaimKeyPackageRequest) = 38,
+    /// Like `UploadKeyPackages`, but the DS skips key packages it already
+    /// stores, so that a chunk of an interrupted upload can be sent again.
+    UploadKeyPackageChunk(UploadKeyPackagesRequestOut<'a>) = 39,
+    UpdateGroupInfoIfEpoch(UpdateGroupInfoIfEpochRequestOut<'a>) = 40,
+    DistributeGroupMessageIfEpoch(DistributeGroupMessageIfEpochRequestOut<'a>) = 41,
+    DistributeGroupMessageIdempotent(DistributeGroupMessageIdempotentRequestOut<'a>) = 42,
+    /// Issues a new auth token to a client without one. See
+    /// [`ROTATE_TOKEN_SIGNATURE_LABEL`](minimal_ds_types::requests::ROTATE_TOKEN_SIGNATURE_LABEL)
+    /// for how the DS must check the signature.
+    RotateTokenSigned(SignedRotateTokenRequestOut<'a>) = 43,
+}
+
+/// The operations that can be performed on the DS. Used to route individual
+/// operations to different endpoints, see
+/// [`ApiClientBuilder::route`](crate::builder::ApiClientBuilder::route).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    RegisterClient,
+    UploadKeyPackages,
     ListClients,
-    CreateGroup(CreateGroupRequestOut<'a>),
-    FetchKeyPackage(FetchKeyPackageRequest),
-    DistributeGroupMessage(DistributeGroupMessageRequestOut<'a>),
-    DistributeWelcome(DistributeWelcomeRequestOut<'a>),
-    FetchMessages(FetchMessagesRequest),
-    DeleteGroup(DeleteGroupRequest),
-    DeleteClient(DeleteClientRequest),
+    CreateGroup,
+    FetchKeyPackage,
+    DistributeGroupMessage,
+    DistributeWelcome,
+    FetchMessages,
+    DeleteGroup,
+    DeleteClient,
+    DeleteGroups,
+    DeleteClients,
+    WhoAmI,
+    DistributeGroupMessageTo,
+    CreateGroupFromRef,
+    RotateToken,
+    MyGroups,
+    UploadKeyPackagesDurable,
+    DeleteGroupSoft,
+    RestoreGroup,
+    DistributeWelcomeTo,
+    Capabilities,
+    PurgeQueue,
+    QueueDepth,
+    DistributeGroupMessages,
+    Logout,
+    FetchMessagesSince,
+    ProvisionToken,
+    FetchMessageRange,
+    ResetKeyPackages,
+    FetchGroupInfo,
+    UpdateGroupInfo,
+    UpdateGroupInfoDelta,
+    SendDeliveryReceipt,
+    FetchDeliveryReceipts,
+    FetchGroupMessages,
+    ListGroups,
+    FetchFreshKeyPackage,
+    ClaimKeyPackage,
+    UploadKeyPackageChunk,
+    UpdateGroupInfoIfEpoch,
+    DistributeGroupMessageIfEpoch,
+    DistributeGroupMessageIdempotent,
+    RotateTokenSigned,
+}
+
+impl MinimalDsMessageOut<'_> {
+    pub(super) fn operation(&self) -> Operation {
+        match self {
+            Self::RegisterClient(_) => Operation::RegisterClient,
+            Self::UploadKeyPackages(_) => Operation::UploadKeyPackages,
+            Self::ListClients(_) => Operation::ListClients,
+            Self::CreateGroup(_) => Operation::CreateGroup,
+            Self::FetchKeyPackage(_) => Operation::FetchKeyPackage,
+            Self::DistributeGroupMessage(_) => Operation::DistributeGroupMessage,
+            Self::DistributeWelcome(_) => Operation::DistributeWelcome,
+            Self::FetchMessages(_) => Operation::FetchMessages,
+            Self::DeleteGroup(_) => Operation::DeleteGroup,
+            Self::DeleteClient(_) => Operation::DeleteClient,
+            Self::DeleteGroups(_) => Operation::DeleteGroups,
+            Self::DeleteClients(_) => Operation::DeleteClients,
+            Self::WhoAmI(_) => Operation::WhoAmI,
+            Self::DistributeGroupMessageTo(_) => Operation::DistributeGroupMessageTo,
+            Self::CreateGroupFromRef(_) => Operation::CreateGroupFromRef,
+            Self::RotateToken(_) => Operation::RotateToken,
+            Self::MyGroups(_) => Operation::MyGroups,
+            Self::UploadKeyPackagesDurable(_) => Operation::UploadKeyPackagesDurable,
+            Self::DeleteGroupSoft(_) => Operation::DeleteGroupSoft,
+            Self::RestoreGroup(_) => Operation::RestoreGroup,
+            Self::DistributeWelcomeTo(_) => Operation::DistributeWelcomeTo,
+            Self::Capabilities => Operation::Capabilities,
+            Self::PurgeQueue(_) => Operation::PurgeQueue,
+            Self::QueueDepth(_) => Operation::QueueDepth,
+            Self::DistributeGroupMessages(_) => Operation::DistributeGroupMessages,
+            Self::Logout(_) => Operation::Logout,
+            Self::FetchMessagesSince(_) => Operation::FetchMessagesSince,
+            Self::ProvisionToken(_) => Operation::ProvisionToken,
+            Self::FetchMessageRange(_) => Operation::FetchMessageRange,
+            Self::ResetKeyPackages(_) => Operation::ResetKeyPackages,
+            Self::FetchGroupInfo(_) => Operation::FetchGroupInfo,
+            Self::UpdateGroupInfo(_) => Operation::UpdateGroupInfo,
+            Self::UpdateGroupInfoDelta(_) => Operation::UpdateGroupInfoDelta,
+            Self::SendDeliveryReceipt(_) => Operation::SendDeliveryReceipt,
+            Self::FetchDeliveryReceipts(_) => Operation::FetchDeliveryReceipts,
+            Self::FetchGroupMessages(_) => Operation::FetchGroupMessages,
+            Self::ListGroups(_) => Operation::ListGroups,
+            Self::FetchFreshKeyPackage(_) => Operation::FetchFreshKeyPackage,
+            Self::ClaimKeyPackage(_) => Operation::ClaimKeyPackage,
+            Self::UploadKeyPackageChunk(_) => Operation::UploadKeyPackageChunk,
+            Self::UpdateGroupInfoIfEpoch(_) => Operation::UpdateGroupInfoIfEpoch,
+            Self::DistributeGroupMessageIfEpoch(_) => Operation::DistributeGroupMessageIfEpoch,
+            Self::DistributeGroupMessageIdempotent(_) => {
+                Operation::DistributeGroupMessageIdempotent
+            }
+            Self::RotateTokenSigned(_) => Operation::RotateTokenSigned,
+        }
+    }
+}
+
+#[derive(Debug, TlsSize, TlsSerialize)]
+pub(super) struct DistributeGroupMessagesRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) messages: &'a [GroupMessageOut<'a>],
+}
+
+#[derive(Debug, TlsSize, TlsSerialize)]
+pub(super) struct GroupMessageOut<'a> {
+    pub(super) message: &'a AssistedMessageOut,
+    pub(super) priority: MessagePriority,
 }
 
 #[derive(TlsSize, TlsSerialize)]
@@ -46,10 +217,86 @@ pub(super) struct DistributeWelcomeRequestOut<'a> {
     pub(super) message: &'a MlsMessageOut,
 }
 
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct DistributeWelcomeToRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) message: &'a MlsMessageOut,
+    pub(super) recipients: &'a [DsClientId],
+}
+
 #[derive(Debug, TlsSize, TlsSerialize)]
 pub(super) struct DistributeGroupMessageRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) message: GroupMessageBody<'a>,
+    pub(super) priority: MessagePriority,
+    /// Opaque metadata the DS relays verbatim with the message.
+    pub(super) metadata: &'a [u8],
+}
+
+/// Like [`DistributeGroupMessageRequestOut`], but the DS distributes the
+/// message only once per `idempotency_key` and answers repeated requests with
+/// the report of the first one.
+#[derive(Debug, TlsSize, TlsSerialize)]
+pub(super) struct DistributeGroupMessageIdempotentRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) message: GroupMessageBody<'a>,
+    pub(super) priority: MessagePriority,
+    pub(super) idempotency_key: MessageRef,
+}
+
+/// The assisted message of a distribution request, either as
+/// assembled by the caller or already serialized, e.g. by an
+/// [`OutboundQueue`](crate::outbound::OutboundQueue). Both are encoded the
+/// same way.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum GroupMessageBody<'a> {
+    Assisted(&'a AssistedMessageOut),
+    Serialized(&'a [u8]),
+}
+
+impl<'a> From<&'a AssistedMessageOut> for GroupMessageBody<'a> {
+    fn from(message: &'a AssistedMessageOut) -> Self {
+        Self::Assisted(message)
+    }
+}
+
+impl Size for GroupMessageBody<'_> {
+    fn tls_serialized_len(&self) -> usize {
+        match self {
+            Self::Assisted(message) => message.tls_serialized_len(),
+            Self::Serialized(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl Serialize for GroupMessageBody<'_> {
+    fn tls_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, tls_codec::Error> {
+        match self {
+            Self::Assisted(message) => message.tls_serialize(writer),
+            Self::Serialized(bytes) => {
+                writer.write_all(bytes)?;
+                Ok(bytes.len())
+            }
+        }
+    }
+}
+
+/// Like [`DistributeGroupMessageRequestOut`], but the DS only distributes the
+/// commit in `message` if the group is in `expected_epoch`.
+#[derive(Debug, TlsSize, TlsSerialize)]
+pub(super) struct DistributeGroupMessageIfEpochRequestOut<'a> {
     pub(super) credentials: &'a ClientCredentials,
     pub(super) message: &'a AssistedMessageOut,
+    pub(super) priority: MessagePriority,
+    pub(super) expected_epoch: u64,
+}
+
+#[derive(Debug, TlsSize, TlsSerialize)]
+pub(super) struct DistributeGroupMessageToRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) message: &'a AssistedMessageOut,
+    pub(super) recipients: &'a [DsClientId],
+    pub(super) priority: MessagePriority,
 }
 
 #[derive(TlsSize, TlsSerialize)]
@@ -59,15 +306,125 @@ pub(super) struct CreateGroupRequestOut<'a> {
     pub(super) ratchet_tree: &'a RatchetTree,
 }
 
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct UpdateGroupInfoRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) group_id: DsGroupId,
+    pub(super) group_info: &'a MlsMessageOut,
+    pub(super) ratchet_tree: &'a RatchetTree,
+}
+
+/// Like [`UpdateGroupInfoRequestOut`], but the DS only updates the group info
+/// if the group is in `expected_epoch`.
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct UpdateGroupInfoIfEpochRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) group_id: DsGroupId,
+    pub(super) group_info: &'a MlsMessageOut,
+    pub(super) ratchet_tree: &'a RatchetTree,
+    pub(super) expected_epoch: u64,
+}
+
+/// Like [`UpdateGroupInfoRequestOut`], but with the ratchet tree given as a
+/// delta to the tree the DS stores for `base_epoch`.
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct UpdateGroupInfoDeltaRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) group_id: DsGroupId,
+    pub(super) group_info: &'a MlsMessageOut,
+    pub(super) base_epoch: u64,
+    pub(super) ratchet_tree_delta: RatchetTreeDelta,
+}
+
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct CreateGroupFromRefRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) group_info: &'a MlsMessageOut,
+    pub(super) group_info_ref: GroupInfoRef,
+}
+
 #[derive(TlsSize, TlsSerialize)]
 pub(super) struct UploadKeyPackagesRequestOut<'a> {
     pub(super) credentials: &'a ClientCredentials,
-    pub(super) key_packages: &'a [MlsMessageOut],
-    pub(super) last_resort_key_package: &'a MlsMessageOut,
+    pub(super) key_packages: &'a [OneTimeKeyPackage],
+    pub(super) last_resort_key_package: &'a LastResortKeyPackage,
 }
 
 #[derive(TlsSize, TlsSerialize)]
 pub(super) struct RegisterClientRequestOut<'a> {
-    pub(super) key_packages: &'a [MlsMessageOut],
-    pub(super) last_resort_key_package: &'a MlsMessageOut,
+    pub(super) key_packages: &'a [OneTimeKeyPackage],
+    pub(super) last_resort_key_package: &'a LastResortKeyPackage,
+}
+
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct RotateTokenRequestOut<'a> {
+    pub(super) credentials: &'a ClientCredentials,
+    pub(super) key_packages: &'a [OneTimeKeyPackage],
+    pub(super) last_resort_key_package: &'a LastResortKeyPackage,
+}
+
+/// The part of a [`SignedRotateTokenRequestOut`] covered by its signature.
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct RotateTokenContentOut<'a> {
+    pub(super) key_packages: &'a [OneTimeKeyPackage],
+    pub(super) last_resort_key_package: &'a LastResortKeyPackage,
+    /// Milliseconds since the UNIX epoch.
+    pub(super) timestamp_ms: u64,
+}
+
+#[derive(TlsSize, TlsSerialize)]
+pub(super) struct SignedRotateTokenRequestOut<'a> {
+    pub(super) content: RotateTokenContentOut<'a>,
+    pub(super) signature: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use minimal_ds_types::AuthToken;
+    use openmls::prelude::DeserializeBytes;
+
+    use super::*;
+    use crate::test_utils::TestGroup;
+
+    // Request bodies are serialized into a buffer of `tls_serialized_len`
+    // bytes, which must be exact to avoid reallocations.
+    fn assert_exactly_sized(message: &MinimalDsMessageOut<'_>) {
+        let bytes = message.tls_serialize_detached().unwrap();
+        assert_eq!(message.tls_serialized_len(), bytes.len());
+    }
+
+    #[test]
+    fn serialized_len_is_exact() {
+        let group = TestGroup::new();
+        let credentials = ClientCredentials::Token(
+            group.alice.client_id.clone(),
+            AuthToken::tls_deserialize_exact_bytes(&[0; 32]).unwrap(),
+        );
+        let (key_packages, last_resort_key_package) = group.alice.key_packages(10);
+        assert_exactly_sized(&MinimalDsMessageOut::UploadKeyPackages(
+            UploadKeyPackagesRequestOut {
+                credentials: &credentials,
+                key_packages: &key_packages,
+                last_resort_key_package: &last_resort_key_package,
+            },
+        ));
+
+        let group_info = group.group_info();
+        let ratchet_tree = group.group.export_ratchet_tree();
+        assert_exactly_sized(&MinimalDsMessageOut::CreateGroup(CreateGroupRequestOut {
+            credentials: &credentials,
+            group_info: &group_info,
+            ratchet_tree: &ratchet_tree,
+        }));
+
+        let commit = AssistedMessageOut::new(group.commit.clone(), Some(group_info)).unwrap();
+        assert_exactly_sized(&MinimalDsMessageOut::DistributeGroupMessage(
+            DistributeGroupMessageRequestOut {
+                credentials: &credentials,
+                message: (&commit).into(),
+                priority: MessagePriority::Control,
+                metadata: b"metadata",
+            },
+        ));
+    }
 }
@@ -0,0 +1,376 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Persisting the state needed to resume a registered client across restarts,
+//! and exporting the full client state.
+
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "rusqlite")]
+use minimal_ds_types::requests::{MessagePriority, MessageRef};
+use minimal_ds_types::{
+    state::{GroupCursor, GroupEpoch, RecentSequenceNumbersState},
+    AuthToken, ClientState, DsClientId,
+};
+
+use crate::{
+    dedup::RecentSequenceNumbers, epochs::EpochTracker, errors::ImportStateError,
+    outbound::OutboundMessage, ApiClient, UnregisteredApiClient,
+};
+
+/// Error returned by a [`SessionStore`].
+pub type SessionStoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The state of a registered client that must be persisted to resume it via
+/// [`UnregisteredApiClient::resume`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub client_id: DsClientId,
+    pub auth_token: AuthToken,
+    /// The sequence number of the last fetched message.
+    pub last_seen_sequence_number: u64,
+}
+
+/// Storage for the [`Session`] of a single client. Once set via
+/// [`ApiClient::set_session_store`], the client writes its session through to
+/// the store whenever the auth token or the fetch cursor changes.
+pub trait SessionStore: Send + Sync {
+    /// Load the stored session, if any.
+    fn load(&self) -> Result<Option<Session>, SessionStoreError>;
+
+    /// Store `session`, replacing any previously stored session.
+    fn save(&self, session: &Session) -> Result<(), SessionStoreError>;
+
+    /// Load the messages queued in an [`OutboundQueue`], oldest first. Stores
+    /// that don't override this and [`Self::save_outbound`] don't persist the
+    /// queue.
+    ///
+    /// [`OutboundQueue`]: crate::outbound::OutboundQueue
+    fn load_outbound(&self) -> Result<Vec<OutboundMessage>, SessionStoreError> {
+        Ok(Vec::new())
+    }
+
+    /// Store the messages queued in an [`OutboundQueue`], oldest first,
+    /// replacing any previously stored messages.
+    ///
+    /// [`OutboundQueue`]: crate::outbound::OutboundQueue
+    fn save_outbound(&self, _messages: &[OutboundMessage]) -> Result<(), SessionStoreError> {
+        Ok(())
+    }
+}
+
+/// A [`SessionStore`] that keeps the session in memory, e.g. for tests.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    session: Mutex<Option<Session>>,
+    outbound: Mutex<Vec<OutboundMessage>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self) -> Result<Option<Session>, SessionStoreError> {
+        Ok(self
+            .session
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone())
+    }
+
+    fn save(&self, session: &Session) -> Result<(), SessionStoreError> {
+        *self.session.lock().unwrap_or_else(|e| e.into_inner()) = Some(session.clone());
+        Ok(())
+    }
+
+    fn load_outbound(&self) -> Result<Vec<OutboundMessage>, SessionStoreError> {
+        Ok(self
+            .outbound
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone())
+    }
+
+    fn save_outbound(&self, messages: &[OutboundMessage]) -> Result<(), SessionStoreError> {
+        *self.outbound.lock().unwrap_or_else(|e| e.into_inner()) = messages.to_vec();
+        Ok(())
+    }
+}
+
+/// A [`SessionStore`] backed by a SQLite database. The auth token is stored
+/// sealed under a key provided by the caller, see [`AuthToken::seal`].
+#[cfg(feature = "rusqlite")]
+pub struct SqliteSessionStore {
+    // `rusqlite::Connection` isn't `Sync`.
+    connection: Mutex<rusqlite::Connection>,
+    key: [u8; 32],
+}
+
+#[cfg(feature = "rusqlite")]
+impl SqliteSessionStore {
+    /// Use `connection` to store the session, creating the tables
+    /// `ds_session` and `ds_outbound` if they don't exist yet. `key` is used
+    /// to seal the auth token and must be the same across restarts.
+    pub fn new(connection: rusqlite::Connection, key: [u8; 32]) -> rusqlite::Result<Self> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS ds_session (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                client_id BLOB NOT NULL,
+                sealed_auth_token BLOB NOT NULL,
+                last_seen_sequence_number INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS ds_outbound (
+                position INTEGER PRIMARY KEY,
+                idempotency_key BLOB NOT NULL,
+                message BLOB NOT NULL,
+                priority INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            key,
+        })
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl SessionStore for SqliteSessionStore {
+    fn load(&self) -> Result<Option<Session>, SessionStoreError> {
+        use rusqlite::OptionalExtension;
+
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let row = connection
+            .query_row(
+                "SELECT client_id, sealed_auth_token, last_seen_sequence_number
+                FROM ds_session WHERE id = 0",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, DsClientId>(0)?,
+                        row.get::<_, Vec<u8>>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((client_id, sealed_auth_token, last_seen_sequence_number)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Session {
+            client_id,
+            auth_token: AuthToken::open(&sealed_auth_token, &self.key)?,
+            // Stored as `i64`, since SQLite integers are signed.
+            last_seen_sequence_number: last_seen_sequence_number as u64,
+        }))
+    }
+
+    fn save(&self, session: &Session) -> Result<(), SessionStoreError> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection.execute(
+            "INSERT OR REPLACE INTO ds_session
+            (id, client_id, sealed_auth_token, last_seen_sequence_number)
+            VALUES (0, ?1, ?2, ?3)",
+            rusqlite::params![
+                session.client_id,
+                session.auth_token.seal(&self.key),
+                session.last_seen_sequence_number as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_outbound(&self) -> Result<Vec<OutboundMessage>, SessionStoreError> {
+        use openmls::prelude::tls_codec::DeserializeBytes;
+
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = connection.prepare(
+            "SELECT idempotency_key, message, priority FROM ds_outbound ORDER BY position",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                row.get::<_, Vec<u8>>(1)?,
+                row.get::<_, u8>(2)?,
+            ))
+        })?;
+        let mut messages = Vec::new();
+        for row in rows {
+            let (idempotency_key, message, priority) = row?;
+            messages.push(OutboundMessage {
+                idempotency_key: MessageRef::tls_deserialize_exact_bytes(&idempotency_key)?,
+                message,
+                priority: match priority {
+                    0 => MessagePriority::Control,
+                    1 => MessagePriority::Bulk,
+                    other => return Err(format!("Invalid message priority {other}").into()),
+                },
+            });
+        }
+        Ok(messages)
+    }
+
+    fn save_outbound(&self, messages: &[OutboundMessage]) -> Result<(), SessionStoreError> {
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM ds_outbound", [])?;
+        for (position, message) in messages.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO ds_outbound (position, idempotency_key, message, priority)
+                VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![
+                    position as i64,
+                    message.idempotency_key.as_bytes().as_slice(),
+                    message.message,
+                    message.priority as u8,
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+impl UnregisteredApiClient {
+    /// Resume the client whose session is stored in `store`, or return `None`
+    /// if no session is stored. The returned client writes its session
+    /// through to `store`, see [`ApiClient::set_session_store`].
+    pub fn resume(
+        &self,
+        store: Arc<dyn SessionStore>,
+    ) -> Result<Option<ApiClient>, SessionStoreError> {
+        let Some(session) = store.load()? else {
+            return Ok(None);
+        };
+        let mut api_client = self.with_session(session);
+        api_client.session_store = Some(store);
+        Ok(Some(api_client))
+    }
+
+    /// Create a client from a previously obtained session without a session
+    /// store, e.g. on a second device using a token from
+    /// [`ApiClient::provision_additional_token`].
+    pub fn with_session(&self, session: Session) -> ApiClient {
+        self.resumed_client(session)
+    }
+}
+
+impl ApiClient {
+    /// The current session of this client, to be persisted and later passed
+    /// to [`UnregisteredApiClient::resume`].
+    pub fn session(&self) -> Session {
+        Session {
+            client_id: self.client_id.clone(),
+            auth_token: self.auth_token,
+            last_seen_sequence_number: self.last_seen_message_sequence_number,
+        }
+    }
+
+    /// Write the session of this client through to `store`, starting by
+    /// saving the current session. Afterwards, the session is saved whenever
+    /// the auth token or the fetch cursor changes. Failing to save an
+    /// advanced cursor is ignored, since the fetched messages have already
+    /// been received; after a restart, they are then fetched again.
+    pub fn set_session_store(
+        &mut self,
+        store: Arc<dyn SessionStore>,
+    ) -> Result<(), SessionStoreError> {
+        store.save(&self.session())?;
+        self.session_store = Some(store);
+        Ok(())
+    }
+
+    /// Export the full state of this client, including tracked epochs, the
+    /// deduplication window and the cursors of per-group fetches, e.g. to
+    /// back it up or to move the client to another host via
+    /// [`Self::import_state`]. Callbacks, the session store and other
+    /// settings are not included.
+    pub fn export_state(&self) -> ClientState {
+        ClientState {
+            version: ClientState::CURRENT_VERSION,
+            client_id: self.client_id.clone(),
+            auth_token: self.auth_token,
+            last_seen_sequence_number: self.last_seen_message_sequence_number,
+            continuation_token: self.continuation_token.clone(),
+            epochs: self.epoch_tracker.as_ref().map(|epoch_tracker| {
+                epoch_tracker
+                    .snapshot()
+                    .into_iter()
+                    .map(|(group_id, epoch)| GroupEpoch { group_id, epoch })
+                    .collect()
+            }),
+            recent_sequence_numbers: self.recent_sequence_numbers.as_ref().map(|recent| {
+                RecentSequenceNumbersState {
+                    window: recent.window() as u64,
+                    sequence_numbers: recent.sequence_numbers().collect(),
+                }
+            }),
+            group_cursors: Some(
+                self.group_cursors
+                    .iter()
+                    .map(|(group_id, &last_seen_sequence_number)| GroupCursor {
+                        group_id: *group_id,
+                        last_seen_sequence_number,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Restore a client from a state exported via [`Self::export_state`],
+    /// using the connection and configuration of `client`.
+    pub fn import_state(
+        client: &UnregisteredApiClient,
+        state: ClientState,
+    ) -> Result<ApiClient, ImportStateError> {
+        if state.version > ClientState::CURRENT_VERSION {
+            return Err(ImportStateError::UnsupportedVersion {
+                version: state.version,
+                supported: ClientState::CURRENT_VERSION,
+            });
+        }
+        let mut api_client = client.with_session(Session {
+            client_id: state.client_id,
+            auth_token: state.auth_token,
+            last_seen_sequence_number: state.last_seen_sequence_number,
+        });
+        api_client.continuation_token = state.continuation_token;
+        api_client.epoch_tracker = state.epochs.map(|epochs| {
+            EpochTracker::from_snapshot(
+                epochs
+                    .into_iter()
+                    .map(|GroupEpoch { group_id, epoch }| (group_id, epoch)),
+            )
+        });
+        api_client.recent_sequence_numbers = state.recent_sequence_numbers.map(|state| {
+            let window = usize::try_from(state.window).unwrap_or(usize::MAX);
+            let mut recent = RecentSequenceNumbers::new(window);
+            for sequence_number in state.sequence_numbers {
+                recent.insert(sequence_number);
+            }
+            recent
+        });
+        api_client.group_cursors = state
+            .group_cursors
+            .into_iter()
+            .flatten()
+            .map(|cursor| (cursor.group_id, cursor.last_seen_sequence_number))
+            .collect();
+        Ok(api_client)
+    }
+
+    /// Save the session to the session store, if one is set.
+    pub(crate) fn save_session(&self) -> Result<(), SessionStoreError> {
+        match &self.session_store {
+            Some(store) => store.save(&self.session()),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Statistics about fetched messages and queue depths, accumulated in-process,
+//! e.g. to be shown in a debug UI.
+
+use std::sync::Mutex;
+
+use crate::ApiClient;
+
+/// Number of buckets of [`FetchStats::batch_sizes`].
+pub const BATCH_SIZE_BUCKETS: usize = 9;
+
+/// Statistics about the messages fetched by a client, see
+/// [`ApiClient::fetch_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchStats {
+    /// The number of fetches that returned a response.
+    pub fetches: u64,
+    /// The number of messages fetched in total.
+    pub total_fetched: u64,
+    /// The number of fetches that returned no messages.
+    pub empty_polls: u64,
+    /// The largest number of messages returned by a single fetch.
+    pub max_batch: u32,
+    /// A histogram of the number of messages returned per fetch. Bucket 0
+    /// counts empty fetches, and bucket `i > 0` counts fetches of at least
+    /// `2^(i - 1)` and less than `2^i` messages. The last bucket also counts
+    /// all larger fetches.
+    pub batch_sizes: [u64; BATCH_SIZE_BUCKETS],
+    /// The queue depth last reported via [`ApiClient::queue_depth`].
+    pub last_queue_depth: Option<u64>,
+    /// The largest queue depth reported via [`ApiClient::queue_depth`].
+    pub max_queue_depth: u64,
+    /// The number of fetched messages that were dropped because their
+    /// sequence number wasn't greater than the cursor. Non-zero values
+    /// indicate a misbehaving DS.
+    pub stale_dropped: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FetchStatsRecorder {
+    stats: Mutex<FetchStats>,
+}
+
+impl FetchStatsRecorder {
+    /// Record a fetch that returned `count` messages.
+    pub(crate) fn record_batch(&self, count: usize) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.fetches += 1;
+        stats.total_fetched += count as u64;
+        if count == 0 {
+            stats.empty_polls += 1;
+        }
+        stats.max_batch = stats
+            .max_batch
+            .max(u32::try_from(count).unwrap_or(u32::MAX));
+        let bucket = (usize::BITS - count.leading_zeros()) as usize;
+        stats.batch_sizes[bucket.min(BATCH_SIZE_BUCKETS - 1)] += 1;
+    }
+
+    pub(crate) fn record_stale(&self, count: usize) {
+        self.stats.lock().unwrap().stale_dropped += count as u64;
+    }
+
+    pub(crate) fn record_queue_depth(&self, depth: u64) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.last_queue_depth = Some(depth);
+        stats.max_queue_depth = stats.max_queue_depth.max(depth);
+    }
+}
+
+impl ApiClient {
+    /// The statistics accumulated since this client was created or the
+    /// statistics were last reset. Fetches via
+    /// [`ApiClient::fetch_messages_streaming`] are not included.
+    pub fn fetch_stats(&self) -> FetchStats {
+        self.fetch_stats.stats.lock().unwrap().clone()
+    }
+
+    /// Reset the statistics returned by [`Self::fetch_stats`].
+    pub fn reset_fetch_stats(&self) {
+        *self.fetch_stats.stats.lock().unwrap() = FetchStats::default();
+    }
+}
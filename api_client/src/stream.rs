@@ -0,0 +1,561 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Streams of messages fetched from the DS: a self-healing stream that polls
+//! the DS, and a stream that parses a single response incrementally. Also
+//! contains a simple adaptive polling helper.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{future, stream, Stream};
+use minimal_ds_types::{NumberedDsQueueMessage, QueuedMessage, TimestampedDsQueueMessage};
+use openmls::{
+    framing::MlsMessageIn,
+    prelude::{tls_codec, DeserializeBytes},
+};
+use rand::Rng;
+use tokio::sync::Notify;
+
+use crate::{
+    clock::Clock,
+    connection::RawResponse,
+    errors::{FetchMessagesError, MessageStreamConfigError, SendMessageError},
+    requests::{
+        FetchMessagesRequest, MinimalDsMessageOut, MinimalDsResponseIn,
+        FETCH_MESSAGES_RESPONSE_TAG, FETCH_MESSAGES_WITH_TIMESTAMPS_RESPONSE_TAG,
+    },
+    ApiClient,
+};
+
+/// Configuration of the polling and reconnect behaviour of
+/// [`ApiClient::message_stream`].
+#[derive(Debug, Clone)]
+pub struct MessageStreamConfig {
+    /// Time to wait before polling again after the DS returned no messages.
+    pub poll_interval: Duration,
+    /// Backoff after the first failed poll. Doubles with every consecutive
+    /// failure.
+    pub initial_backoff: Duration,
+    /// Upper bound for the backoff between two polls.
+    pub max_backoff: Duration,
+    /// Number of consecutive recoverable failures after which the stream
+    /// yields the last error and terminates.
+    pub max_consecutive_failures: u32,
+    /// How long the DS may hold each poll open while no messages are queued.
+    /// Zero disables long polling, in which case the stream waits for
+    /// `poll_interval` after an empty poll instead.
+    pub long_poll_wait: Duration,
+    /// How long to wait for the response to a poll before considering the
+    /// connection dead and retrying. Must exceed `long_poll_wait`, since
+    /// otherwise healthy long polls time out. `None` waits indefinitely.
+    pub request_timeout: Option<Duration>,
+}
+
+impl Default for MessageStreamConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(1),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            max_consecutive_failures: 10,
+            long_poll_wait: Duration::ZERO,
+            request_timeout: None,
+        }
+    }
+}
+
+impl MessageStreamConfig {
+    /// Check that the configured durations are consistent.
+    pub fn validate(&self) -> Result<(), MessageStreamConfigError> {
+        match self.request_timeout {
+            Some(request_timeout) if request_timeout <= self.long_poll_wait => {
+                Err(MessageStreamConfigError::TimeoutWithinLongPoll {
+                    request_timeout,
+                    long_poll_wait: self.long_poll_wait,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Poll the DS once, using long polling and the request timeout as
+    /// configured.
+    async fn poll(&self, client: &mut ApiClient) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        let fetch = async {
+            if self.long_poll_wait.is_zero() {
+                client.fetch_messages().await
+            } else {
+                client.fetch_messages_blocking(self.long_poll_wait).await
+            }
+        };
+        match self.request_timeout {
+            Some(request_timeout) => tokio::time::timeout(request_timeout, fetch)
+                .await
+                .unwrap_or(Err(FetchMessagesError::Timeout)),
+            None => fetch.await,
+        }
+    }
+
+    /// Compute the jittered backoff after `failures` consecutive failures.
+    /// The result lies between half and the full exponential backoff.
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        let half = backoff / 2;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=half);
+        half + jitter
+    }
+}
+
+/// Configuration of [`ApiClient::poll_with_adaptive_backoff`].
+#[derive(Debug, Clone)]
+pub struct AdaptivePollConfig {
+    /// Interval between polls while the queue recently had messages.
+    pub min_interval: Duration,
+    /// Upper bound for the interval between polls.
+    pub max_interval: Duration,
+    /// Factor by which the interval grows after each empty poll beyond
+    /// `empty_polls_before_backoff`.
+    pub multiplier: f64,
+    /// Number of consecutive empty polls after which the interval starts to
+    /// grow.
+    pub empty_polls_before_backoff: u32,
+}
+
+impl Default for AdaptivePollConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(60),
+            multiplier: 2.0,
+            empty_polls_before_backoff: 3,
+        }
+    }
+}
+
+/// A handle to stop a stream created with
+/// [`ApiClient::message_stream_with_shutdown`].
+#[derive(Debug, Clone, Default)]
+pub struct StreamShutdown {
+    signal: Arc<ShutdownSignal>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownSignal {
+    is_shut_down: AtomicBool,
+    notify: Notify,
+}
+
+impl StreamShutdown {
+    /// Stop the stream. A request that is currently in flight is completed,
+    /// but no new requests are sent and pending waits are interrupted. Messages
+    /// that were already fetched are still yielded before the stream ends,
+    /// since the cursor has already advanced past them.
+    pub fn shutdown(&self) {
+        self.signal.is_shut_down.store(true, Ordering::SeqCst);
+        self.signal.notify.notify_waiters();
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.signal.is_shut_down.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for `duration` according to `clock` or until [`Self::shutdown`]
+    /// is called.
+    async fn sleep(&self, clock: &dyn Clock, duration: Duration) {
+        let notified = self.signal.notify.notified();
+        if self.is_shut_down() {
+            return;
+        }
+        let sleep = clock.sleep(duration);
+        futures::pin_mut!(notified, sleep);
+        future::select(notified, sleep).await;
+    }
+}
+
+struct StreamState {
+    client: ApiClient,
+    config: MessageStreamConfig,
+    shutdown: StreamShutdown,
+    buffer: VecDeque<MlsMessageIn>,
+    consecutive_failures: u32,
+    terminated: bool,
+}
+
+impl ApiClient {
+    /// Turn this client into a stream of messages sent to this client. The
+    /// stream polls the DS using [`ApiClient::fetch_messages`], or
+    /// [`ApiClient::fetch_messages_blocking`] if long polling is configured.
+    /// Recoverable errors are retried with jittered exponential backoff,
+    /// resuming from the current cursor. After
+    /// `config.max_consecutive_failures` consecutive failures, or on a
+    /// non-recoverable error, the error is yielded and the stream ends.
+    ///
+    /// Fails if `config` is inconsistent, see
+    /// [`MessageStreamConfig::validate`].
+    pub fn message_stream(
+        self,
+        config: MessageStreamConfig,
+    ) -> Result<
+        impl Stream<Item = Result<MlsMessageIn, FetchMessagesError>>,
+        MessageStreamConfigError,
+    > {
+        Ok(self.message_stream_with_shutdown(config)?.0)
+    }
+
+    /// Like [`ApiClient::message_stream`], but additionally returns a
+    /// [`StreamShutdown`] handle to stop the stream gracefully.
+    pub fn message_stream_with_shutdown(
+        self,
+        config: MessageStreamConfig,
+    ) -> Result<
+        (
+            impl Stream<Item = Result<MlsMessageIn, FetchMessagesError>>,
+            StreamShutdown,
+        ),
+        MessageStreamConfigError,
+    > {
+        config.validate()?;
+        let shutdown = StreamShutdown::default();
+        let state = StreamState {
+            client: self,
+            config,
+            shutdown: shutdown.clone(),
+            buffer: VecDeque::new(),
+            consecutive_failures: 0,
+            terminated: false,
+        };
+        let stream = stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(message) = state.buffer.pop_front() {
+                    return Some((Ok(message), state));
+                }
+                if state.terminated || state.shutdown.is_shut_down() {
+                    return None;
+                }
+                match state.config.poll(&mut state.client).await {
+                    Ok(messages) => {
+                        state.consecutive_failures = 0;
+                        if messages.is_empty() && state.config.long_poll_wait.is_zero() {
+                            state
+                                .shutdown
+                                .sleep(&*state.client.config.clock, state.config.poll_interval)
+                                .await;
+                        }
+                        state.buffer.extend(messages);
+                    }
+                    Err(e) => {
+                        state.consecutive_failures += 1;
+                        if !is_recoverable(&e)
+                            || state.consecutive_failures >= state.config.max_consecutive_failures
+                        {
+                            state.terminated = true;
+                            return Some((Err(e), state));
+                        }
+                        let mut backoff = state.config.backoff(state.consecutive_failures);
+                        if let FetchMessagesError::FetchMessagesError(e) = &e {
+                            backoff = backoff.max(e.retry_after().unwrap_or_default());
+                        }
+                        state
+                            .shutdown
+                            .sleep(&*state.client.config.clock, backoff)
+                            .await;
+                    }
+                }
+            }
+        });
+        Ok((stream, shutdown))
+    }
+}
+
+impl ApiClient {
+    /// Poll the DS using [`ApiClient::fetch_messages`] until messages arrive,
+    /// for clients whose DS doesn't support long polling. After
+    /// `config.empty_polls_before_backoff` consecutive empty polls, the
+    /// interval between polls grows by `config.multiplier` with every further
+    /// empty poll, up to `config.max_interval`. Each call starts again at
+    /// `config.min_interval`, so calling this in a loop polls quickly while
+    /// messages keep arriving. Errors are returned immediately.
+    pub async fn poll_with_adaptive_backoff(
+        &mut self,
+        config: &AdaptivePollConfig,
+    ) -> Result<Vec<MlsMessageIn>, FetchMessagesError> {
+        let mut interval = config.min_interval;
+        let mut empty_polls = 0u32;
+        loop {
+            let messages = self.fetch_messages().await?;
+            if !messages.is_empty() {
+                return Ok(messages);
+            }
+            self.config.clock.sleep(interval).await;
+            empty_polls = empty_polls.saturating_add(1);
+            if empty_polls >= config.empty_polls_before_backoff {
+                let next = interval.as_secs_f64() * config.multiplier.max(1.0);
+                interval = Duration::try_from_secs_f64(next)
+                    .unwrap_or(config.max_interval)
+                    .min(config.max_interval);
+            }
+        }
+    }
+}
+
+/// Whether a failed poll is worth retrying, i.e. whether it was caused by the
+/// transport or by a temporary server-side condition.
+fn is_recoverable(error: &FetchMessagesError) -> bool {
+    error.is_retryable()
+}
+
+/// State of the stream returned by [`ApiClient::fetch_messages_streaming`].
+enum StreamingFetch<'a> {
+    Start(&'a mut ApiClient),
+    Body {
+        client: &'a mut ApiClient,
+        body: MessageBody,
+    },
+    Done,
+}
+
+/// The start of a response to a [`FetchMessagesRequest`].
+enum OpenedBody {
+    Messages(MessageBody),
+    /// The DS redirected the request to the contained endpoint.
+    CanonicalEndpoint(Vec<u8>),
+}
+
+/// The body of a response to a [`FetchMessagesRequest`], which is parsed
+/// message by message as it is received.
+struct MessageBody {
+    // `None` if a custom transport returned the complete body up front.
+    response: Option<reqwest::Response>,
+    buffer: Vec<u8>,
+    // Upper bound for the size of `buffer`, see
+    // `ApiClientBuilder::max_buffer_bytes`.
+    max_buffer_bytes: Option<usize>,
+    // Number of bytes of the message vector that have not yet been parsed.
+    remaining: usize,
+    timestamped: bool,
+}
+
+impl MessageBody {
+    /// Read the response up to and including the length of the message
+    /// vector.
+    async fn open(
+        response: RawResponse,
+        max_buffer_bytes: Option<usize>,
+    ) -> Result<OpenedBody, FetchMessagesError> {
+        let (response, buffer) = match response {
+            RawResponse::Http(response) => (Some(response), Vec::new()),
+            RawResponse::Bytes(bytes) => (None, bytes),
+        };
+        let mut body = Self {
+            response,
+            buffer,
+            max_buffer_bytes,
+            remaining: 0,
+            timestamped: false,
+        };
+        loop {
+            if let Some((tag, length, header_length)) = parse_body_header(&body.buffer)? {
+                body.timestamped = match tag {
+                    FETCH_MESSAGES_RESPONSE_TAG => false,
+                    FETCH_MESSAGES_WITH_TIMESTAMPS_RESPONSE_TAG => true,
+                    _ => return body.other_response().await,
+                };
+                body.remaining = length;
+                body.buffer.drain(..header_length);
+                return Ok(OpenedBody::Messages(body));
+            }
+            if !body.fill().await? {
+                return body.other_response().await;
+            }
+        }
+    }
+
+    /// Read the rest of a response that doesn't contain messages. Returns the
+    /// endpoint of a redirect, and an error for any other response.
+    async fn other_response(mut self) -> Result<OpenedBody, FetchMessagesError> {
+        while self.fill().await? {}
+        match MinimalDsResponseIn::tls_deserialize_exact_bytes(&self.buffer)? {
+            MinimalDsResponseIn::CanonicalEndpoint(endpoint) => {
+                Ok(OpenedBody::CanonicalEndpoint(endpoint))
+            }
+            ds_response => Err(FetchMessagesError::UnexpectedResponse {
+                expected: "FetchMessages",
+                got: ds_response.variant_name(),
+            }),
+        }
+    }
+
+    /// Read the next chunk of the response into the buffer. Returns `false`
+    /// if the response is complete.
+    async fn fill(&mut self) -> Result<bool, FetchMessagesError> {
+        let Some(response) = &mut self.response else {
+            return Ok(false);
+        };
+        match response.chunk().await.map_err(SendMessageError::from)? {
+            Some(chunk) => {
+                if let Some(limit) = self.max_buffer_bytes {
+                    if self.buffer.len() + chunk.len() > limit {
+                        return Err(FetchMessagesError::BufferLimitExceeded { limit });
+                    }
+                }
+                self.buffer.extend_from_slice(&chunk);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Parse the next message, reading more of the response as necessary.
+    async fn next_message(&mut self) -> Result<Option<QueuedMessage>, FetchMessagesError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        loop {
+            let parsed = if self.timestamped {
+                TimestampedDsQueueMessage::tls_deserialize_bytes(&self.buffer)
+                    .map(|(message, rest)| (QueuedMessage::from(message), rest.len()))
+            } else {
+                NumberedDsQueueMessage::tls_deserialize_bytes(&self.buffer)
+                    .map(|(message, rest)| (QueuedMessage::from(message), rest.len()))
+            };
+            match parsed {
+                Ok((message, rest_length)) => {
+                    let consumed = self.buffer.len() - rest_length;
+                    self.remaining = self
+                        .remaining
+                        .checked_sub(consumed)
+                        .ok_or(tls_codec::Error::InvalidVectorLength)?;
+                    self.buffer.drain(..consumed);
+                    return Ok(Some(message));
+                }
+                Err(tls_codec::Error::EndOfStream) => {
+                    if !self.fill().await? {
+                        return Err(tls_codec::Error::EndOfStream.into());
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Parse the response tag and the variable-length encoded length of the
+/// message vector. Returns `None` if more bytes are needed.
+fn parse_body_header(bytes: &[u8]) -> Result<Option<(u8, usize, usize)>, tls_codec::Error> {
+    let (Some(&tag), Some(&first)) = (bytes.first(), bytes.get(1)) else {
+        return Ok(None);
+    };
+    let length_bytes = match first >> 6 {
+        0 => 1,
+        1 => 2,
+        2 => 4,
+        _ => return Err(tls_codec::Error::InvalidVectorLength),
+    };
+    let Some(length_field) = bytes.get(1..1 + length_bytes) else {
+        return Ok(None);
+    };
+    let length = length_field[1..]
+        .iter()
+        .fold((first & 0x3f) as usize, |length, &byte| {
+            (length << 8) | byte as usize
+        });
+    Ok(Some((tag, length, 1 + length_bytes)))
+}
+
+impl ApiClient {
+    /// Like [`ApiClient::fetch_messages`], but parses the response
+    /// incrementally and yields each message together with its sequence
+    /// number as soon as it is received. The cursor advances past each
+    /// yielded message, including messages that fail to deserialize.
+    /// Duplicates are skipped if deduplication is enabled. This always uses
+    /// the sequence number cursor, so it is not supported by DS backends that
+    /// paginate using continuation tokens. Like other requests, the request
+    /// is sent again to the canonical endpoint if the DS redirects it, see
+    /// [`ApiClientBuilder::follow_ds_redirects`](crate::builder::ApiClientBuilder::follow_ds_redirects).
+    ///
+    /// Only the unparsed part of the response is held in memory, so memory
+    /// constrained clients should prefer this over [`ApiClient::fetch_messages`]
+    /// and bound the buffer via
+    /// [`ApiClientBuilder::max_buffer_bytes`](crate::builder::ApiClientBuilder::max_buffer_bytes).
+    pub fn fetch_messages_streaming(
+        &mut self,
+    ) -> impl Stream<Item = Result<(u64, MlsMessageIn), FetchMessagesError>> + '_ {
+        stream::unfold(StreamingFetch::Start(self), |state| async move {
+            let (client, mut body) = match state {
+                StreamingFetch::Start(client) => match client.open_message_body().await {
+                    Ok(body) => (client, body),
+                    Err(e) => return Some((Err(e), StreamingFetch::Done)),
+                },
+                StreamingFetch::Body { client, body } => (client, body),
+                StreamingFetch::Done => return None,
+            };
+            let next_message = loop {
+                match body.next_message().await {
+                    Ok(Some(message)) => {
+                        let mut fetched = vec![message];
+                        client.drop_duplicates(&mut fetched);
+                        client.drop_stale(&mut fetched);
+                        if let Some(message) = fetched.pop() {
+                            break Ok(Some(message));
+                        }
+                    }
+                    result => break result,
+                }
+            };
+            match next_message {
+                Ok(Some(message)) => {
+                    let fetched = std::slice::from_ref(&message);
+                    if let Err(e) = client.check_sequence_numbers(fetched) {
+                        return Some((Err(e), StreamingFetch::Done));
+                    }
+                    client.observe_fetched(fetched);
+                    let sequence_number = message.sequence_number;
+                    let result = message
+                        .message
+                        .deserialize()
+                        .map(|message| (sequence_number, message))
+                        .map_err(FetchMessagesError::from);
+                    Some((result, StreamingFetch::Body { client, body }))
+                }
+                Ok(None) => None,
+                Err(e) => Some((Err(e), StreamingFetch::Done)),
+            }
+        })
+    }
+
+    async fn open_message_body(&self) -> Result<MessageBody, FetchMessagesError> {
+        let request = FetchMessagesRequest {
+            continuation_token: None,
+            ..self.build_fetch_request(100)
+        };
+        let message = MinimalDsMessageOut::FetchMessages(request);
+        let response = self.connection.send_request(&message, None).await?;
+        let endpoint = match MessageBody::open(response, self.config.max_buffer_bytes).await? {
+            OpenedBody::Messages(body) => return Ok(body),
+            OpenedBody::CanonicalEndpoint(endpoint) => endpoint,
+        };
+        self.connection.follow_redirect(&endpoint)?;
+        // Like `DsConnection::send_message`, only follow a single redirect.
+        let response = self.connection.send_request(&message, None).await?;
+        match MessageBody::open(response, self.config.max_buffer_bytes).await? {
+            OpenedBody::Messages(body) => Ok(body),
+            OpenedBody::CanonicalEndpoint(endpoint) => Err(FetchMessagesError::from(
+                SendMessageError::RedirectedToCanonicalEndpoint(
+                    String::from_utf8_lossy(&endpoint).into_owned(),
+                ),
+            )),
+        }
+    }
+}
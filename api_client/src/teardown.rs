@@ -0,0 +1,82 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Offboarding a client, i.e. removing what it left on the DS in the order
+//! the DS requires.
+
+use crate::{
+    errors::{DeleteClientError, DeleteGroupError, LogoutError, PurgeQueueError},
+    ApiClient, DsGroupId,
+};
+
+/// Options of [`ApiClient::teardown`]. By default, only the client itself is
+/// deleted.
+#[derive(Debug, Clone, Default)]
+pub struct TeardownOptions {
+    /// Drop the messages queued for the client before deleting it.
+    pub purge_queue: bool,
+    /// Groups to delete before deleting the client, e.g. the groups it
+    /// created. The DS doesn't track which client owns a group, and deleting
+    /// a group deletes it for all of its members, so the groups have to be
+    /// chosen by the caller.
+    pub delete_groups: Vec<DsGroupId>,
+}
+
+/// The outcome of each step of [`ApiClient::teardown`].
+#[derive(Debug)]
+pub struct TeardownReport {
+    /// The number of purged messages, or `None` if purging the queue wasn't
+    /// requested.
+    pub purge_queue: Option<Result<u64, PurgeQueueError>>,
+    /// The result of deleting each group given in
+    /// [`TeardownOptions::delete_groups`], in the same order.
+    pub delete_groups: Vec<(DsGroupId, Result<(), DeleteGroupError>)>,
+    pub delete_client: Result<(), DeleteClientError>,
+    /// Only attempted if deleting the client failed, so that at least its
+    /// auth token is invalidated.
+    pub logout: Option<Result<(), LogoutError>>,
+}
+
+impl TeardownReport {
+    /// Whether all requested steps succeeded.
+    pub fn is_complete(&self) -> bool {
+        matches!(self.purge_queue, None | Some(Ok(_)))
+            && self.delete_groups.iter().all(|(_, result)| result.is_ok())
+            && self.delete_client.is_ok()
+    }
+}
+
+impl ApiClient {
+    /// Remove this client from the DS and consume it. Purges the queue and
+    /// deletes the groups given in `options`, if any, then deletes the
+    /// client. Groups that don't exist (anymore) count as deleted. If
+    /// deleting the client fails, the client logs out instead, so that its
+    /// auth token can't be used anymore.
+    ///
+    /// A failing step doesn't stop the following ones. The outcome of each
+    /// step is reported in the returned [`TeardownReport`].
+    pub async fn teardown(mut self, options: TeardownOptions) -> TeardownReport {
+        let purge_queue = if options.purge_queue {
+            Some(self.purge_queue().await)
+        } else {
+            None
+        };
+        let mut delete_groups = Vec::with_capacity(options.delete_groups.len());
+        for group_id in options.delete_groups {
+            let result = self.delete_group_idempotent(group_id).await;
+            delete_groups.push((group_id, result));
+        }
+        let delete_client = self.delete_client(self.client_id.clone()).await;
+        let logout = match delete_client {
+            Ok(()) => None,
+            Err(_) => Some(self.logout().await),
+        };
+        TeardownReport {
+            purge_queue,
+            delete_groups,
+            delete_client,
+            logout,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! MLS fixtures for the unit tests of this crate.
+
+use minimal_ds_types::{AuthToken, DsClientId};
+use mls_assist::messages::AssistedMessageOut;
+use openmls::{
+    prelude::{
+        tls_codec::DeserializeBytes, Ciphersuite, CredentialWithKey, CryptoConfig, KeyPackage,
+        MlsGroup, MlsGroupConfig, MlsMessageOut, OpenMlsProvider,
+    },
+    treesync::RatchetTree,
+};
+use openmls_basic_credential::SignatureKeyPair;
+use openmls_rust_crypto::OpenMlsRustCrypto;
+
+use crate::{
+    requests::DsResponse,
+    testing::{generate_key_packages, MockTransport},
+    ApiClient, LastResortKeyPackage, OneTimeKeyPackage, UnregisteredApiClient,
+};
+
+pub(crate) const CIPHERSUITE: Ciphersuite =
+    Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+
+/// An MLS client whose credential carries a [`DsClientId`].
+pub(crate) struct TestClient {
+    pub(crate) client_id: DsClientId,
+    pub(crate) provider: OpenMlsRustCrypto,
+    pub(crate) signer: SignatureKeyPair,
+    pub(crate) credential_with_key: CredentialWithKey,
+}
+
+impl TestClient {
+    /// Create a client whose ID consists of 16 times `id_byte`.
+    pub(crate) fn new(id_byte: u8) -> Self {
+        let client_id = DsClientId::new(&[id_byte; 16]).unwrap();
+        let provider = OpenMlsRustCrypto::default();
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+        signer.store(provider.key_store()).unwrap();
+        let credential_with_key = CredentialWithKey {
+            credential: client_id.to_basic_credential(),
+            signature_key: signer.public().into(),
+        };
+        Self {
+            client_id,
+            provider,
+            signer,
+            credential_with_key,
+        }
+    }
+
+    pub(crate) fn key_package(&self) -> KeyPackage {
+        KeyPackage::builder()
+            .build(
+                CryptoConfig::with_default_version(CIPHERSUITE),
+                &self.provider,
+                &self.signer,
+                self.credential_with_key.clone(),
+            )
+            .unwrap()
+    }
+
+    pub(crate) fn key_packages(
+        &self,
+        count: usize,
+    ) -> (Vec<OneTimeKeyPackage>, LastResortKeyPackage) {
+        generate_key_packages(
+            CIPHERSUITE,
+            &self.provider,
+            &self.signer,
+            self.credential_with_key.clone(),
+            count,
+        )
+    }
+
+    /// Register this client with a DS simulated by `transport`. The
+    /// registration request is recorded by `transport` like any other.
+    pub(crate) async fn register(&self, transport: &MockTransport) -> ApiClient {
+        let (key_packages, last_resort_key_package) = self.key_packages(1);
+        let token = AuthToken::tls_deserialize_exact_bytes(&[7; 32]).unwrap();
+        transport.push_response(DsResponse::AuthToken(token));
+        UnregisteredApiClient::builder("http://localhost".parse().unwrap())
+            .transport(transport.clone())
+            .build()
+            .unwrap()
+            .register(&key_packages, &last_resort_key_package)
+            .await
+            .unwrap()
+    }
+}
+
+/// A group created by `alice`, who then added `bob`.
+pub(crate) struct TestGroup {
+    pub(crate) alice: TestClient,
+    pub(crate) group: MlsGroup,
+    /// The ratchet tree before `bob` was added.
+    pub(crate) initial_tree: RatchetTree,
+    pub(crate) commit: MlsMessageOut,
+    pub(crate) welcome: MlsMessageOut,
+}
+
+impl TestGroup {
+    pub(crate) fn new() -> Self {
+        let alice = TestClient::new(1);
+        let bob = TestClient::new(2);
+        let mut group = MlsGroup::new(
+            &alice.provider,
+            &alice.signer,
+            &MlsGroupConfig::default(),
+            alice.credential_with_key.clone(),
+        )
+        .unwrap();
+        let initial_tree = group.export_ratchet_tree();
+        let (commit, welcome, _group_info) = group
+            .add_members(&alice.provider, &alice.signer, &[bob.key_package()])
+            .unwrap();
+        group.merge_pending_commit(&alice.provider).unwrap();
+        Self {
+            alice,
+            group,
+            initial_tree,
+            commit,
+            welcome,
+        }
+    }
+
+    pub(crate) fn group_info(&self) -> MlsMessageOut {
+        self.group
+            .export_group_info(&self.alice.provider, &self.alice.signer, true)
+            .unwrap()
+    }
+
+    pub(crate) fn application_message(&mut self, content: &[u8]) -> MlsMessageOut {
+        self.group
+            .create_message(&self.alice.provider, &self.alice.signer, content)
+            .unwrap()
+    }
+
+    pub(crate) fn assisted_application_message(&mut self, content: &[u8]) -> AssistedMessageOut {
+        AssistedMessageOut::new(self.application_message(content), None).unwrap()
+    }
+}
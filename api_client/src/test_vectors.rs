@@ -0,0 +1,543 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Canonical encodings of the requests sent to the DS, so that other DS
+//! implementations can check their parsers against this client.
+
+use minimal_ds_types::{
+    requests::{
+        ClaimKeyPackageRequest, DeleteClientRequest, DeleteClientsRequest, DeleteGroupRequest,
+        DeleteGroupSoftRequest, DeleteGroupsRequest, DeliveryReceiptRequest,
+        FetchDeliveryReceiptsRequest, FetchFreshKeyPackageRequest, FetchGroupInfoRequest,
+        FetchGroupMessagesRequest, FetchKeyPackageRequest, FetchMessageRangeRequest,
+        FetchMessagesRequest, FetchMessagesSinceRequest, GroupInfoRef, ListClientsRequest,
+        ListGroupsRequest, LogoutRequest, MessagePriority, MessageRef, MyGroupsRequest,
+        ProvisionTokenRequest, PurgeQueueRequest, QueueDepthRequest, RestoreGroupRequest,
+        WhoAmIRequest,
+    },
+    tree_delta::{ChangedNode, RatchetTreeDelta},
+    AuthToken, ClientCredentials, DsClientId, DsGroupId, MlsMessageKind,
+};
+use mls_assist::messages::AssistedMessageOut;
+use openmls::{
+    framing::MlsMessageOut,
+    prelude::{
+        tls_codec::{self, Serialize},
+        DeserializeBytes,
+    },
+    treesync::RatchetTree,
+};
+
+use crate::{
+    connection::hex_encode,
+    requests::{self, MinimalDsMessageOut},
+    LastResortKeyPackage, OneTimeKeyPackage,
+};
+
+// The fixed values used for all fields that aren't MLS objects.
+const CLIENT_ID: [u8; 16] = [0x01; 16];
+const AUTH_TOKEN: [u8; 32] = [0x02; 32];
+const GROUP_ID: [u8; 16] = [0x03; 16];
+const OTHER_CLIENT_ID: [u8; 16] = [0x04; 16];
+const OTHER_GROUP_ID: [u8; 16] = [0x05; 16];
+const CONTINUATION_TOKEN: &[u8] = &[0x06; 8];
+const MESSAGE: &[u8] = b"message";
+const METADATA: &[u8] = b"metadata";
+const TIMESTAMP_MS: u64 = 1_700_000_000_000;
+const SIGNATURE: &[u8] = &[0x07; 64];
+
+/// The MLS objects the test vectors are built from. Creating them involves
+/// randomness, so they are provided by the caller, e.g. generated once and
+/// stored next to the resulting vectors.
+pub struct TestVectorInputs {
+    pub key_package: OneTimeKeyPackage,
+    pub last_resort_key_package: LastResortKeyPackage,
+    pub group_info: MlsMessageOut,
+    pub ratchet_tree: RatchetTree,
+    pub welcome: MlsMessageOut,
+    /// A group message. Using an application message avoids having to
+    /// provide the group info of a commit.
+    pub group_message: AssistedMessageOut,
+}
+
+/// The serialization of one request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    /// The name of the request variant.
+    pub name: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+/// Serialize a request of every kind the client sends, built from `inputs`
+/// and fixed values for all other fields. The result only depends on
+/// `inputs`.
+pub fn test_vectors(inputs: &TestVectorInputs) -> Result<Vec<TestVector>, tls_codec::Error> {
+    let client_id = DsClientId::new(&CLIENT_ID).map_err(|_| tls_codec::Error::InvalidInput)?;
+    let other_client_id =
+        DsClientId::new(&OTHER_CLIENT_ID).map_err(|_| tls_codec::Error::InvalidInput)?;
+    let group_id = DsGroupId::tls_deserialize_exact_bytes(&GROUP_ID)?;
+    let other_group_id = DsGroupId::tls_deserialize_exact_bytes(&OTHER_GROUP_ID)?;
+    let credentials = ClientCredentials::Token(
+        client_id.clone(),
+        AuthToken::tls_deserialize_exact_bytes(&AUTH_TOKEN)?,
+    );
+    let key_packages = std::slice::from_ref(&inputs.key_package);
+    let recipients = [client_id.clone(), other_client_id.clone()];
+    let group_messages = [requests::GroupMessageOut {
+        message: &inputs.group_message,
+        priority: MessagePriority::Bulk,
+    }];
+    let registration = || requests::RegisterClientRequestOut {
+        key_packages,
+        last_resort_key_package: &inputs.last_resort_key_package,
+    };
+    let key_package_upload = || requests::UploadKeyPackagesRequestOut {
+        credentials: &credentials,
+        key_packages,
+        last_resort_key_package: &inputs.last_resort_key_package,
+    };
+
+    let messages = [
+        (
+            "RegisterClient",
+            MinimalDsMessageOut::RegisterClient(registration()),
+        ),
+        (
+            "UploadKeyPackages",
+            MinimalDsMessageOut::UploadKeyPackages(key_package_upload()),
+        ),
+        (
+            "ListClients",
+            MinimalDsMessageOut::ListClients(ListClientsRequest {
+                credentials: credentials.clone(),
+                offset: 10,
+                limit: 20,
+                prefix: Some(CLIENT_ID[..4].to_vec()),
+            }),
+        ),
+        (
+            "CreateGroup",
+            MinimalDsMessageOut::CreateGroup(requests::CreateGroupRequestOut {
+                credentials: &credentials,
+                group_info: &inputs.group_info,
+                ratchet_tree: &inputs.ratchet_tree,
+            }),
+        ),
+        (
+            "FetchKeyPackage",
+            MinimalDsMessageOut::FetchKeyPackage(FetchKeyPackageRequest {
+                client_id: other_client_id.clone(),
+            }),
+        ),
+        (
+            "DistributeGroupMessage",
+            MinimalDsMessageOut::DistributeGroupMessage(
+                requests::DistributeGroupMessageRequestOut {
+                    credentials: &credentials,
+                    message: (&inputs.group_message).into(),
+                    priority: MessagePriority::Bulk,
+                    metadata: METADATA,
+                },
+            ),
+        ),
+        (
+            "DistributeWelcome",
+            MinimalDsMessageOut::DistributeWelcome(requests::DistributeWelcomeRequestOut {
+                message: &inputs.welcome,
+            }),
+        ),
+        (
+            "FetchMessages",
+            MinimalDsMessageOut::FetchMessages(FetchMessagesRequest {
+                credentials: credentials.clone(),
+                last_seen_sequence_number: 42,
+                number_of_messages: 100,
+                wait_ms: 30_000,
+                continuation_token: Some(CONTINUATION_TOKEN.to_vec()),
+                kinds: vec![MlsMessageKind::Application, MlsMessageKind::Commit],
+                exclude_own: true,
+            }),
+        ),
+        (
+            "DeleteGroup",
+            MinimalDsMessageOut::DeleteGroup(DeleteGroupRequest {
+                credentials: credentials.clone(),
+                group_id,
+            }),
+        ),
+        (
+            "DeleteClient",
+            MinimalDsMessageOut::DeleteClient(DeleteClientRequest {
+                credentials: credentials.clone(),
+                client_id: client_id.clone(),
+            }),
+        ),
+        (
+            "DeleteGroups",
+            MinimalDsMessageOut::DeleteGroups(DeleteGroupsRequest {
+                credentials: credentials.clone(),
+                group_ids: vec![group_id, other_group_id],
+            }),
+        ),
+        (
+            "DeleteClients",
+            MinimalDsMessageOut::DeleteClients(DeleteClientsRequest {
+                credentials: credentials.clone(),
+                client_ids: recipients.to_vec(),
+            }),
+        ),
+        (
+            "WhoAmI",
+            MinimalDsMessageOut::WhoAmI(WhoAmIRequest {
+                credentials: credentials.clone(),
+            }),
+        ),
+        (
+            "DistributeGroupMessageTo",
+            MinimalDsMessageOut::DistributeGroupMessageTo(
+                requests::DistributeGroupMessageToRequestOut {
+                    credentials: &credentials,
+                    message: &inputs.group_message,
+                    recipients: &recipients,
+                    priority: MessagePriority::Bulk,
+                },
+            ),
+        ),
+        (
+            "CreateGroupFromRef",
+            MinimalDsMessageOut::CreateGroupFromRef(requests::CreateGroupFromRefRequestOut {
+                credentials: &credentials,
+                group_info: &inputs.group_info,
+                group_info_ref: GroupInfoRef { group_id, epoch: 7 },
+            }),
+        ),
+        (
+            "RotateToken",
+            MinimalDsMessageOut::RotateToken(requests::RotateTokenRequestOut {
+                credentials: &credentials,
+                key_packages,
+                last_resort_key_package: &inputs.last_resort_key_package,
+            }),
+        ),
+        (
+            "MyGroups",
+            MinimalDsMessageOut::MyGroups(MyGroupsRequest {
+                credentials: credentials.clone(),
+            }),
+        ),
+        (
+            "UploadKeyPackagesDurable",
+            MinimalDsMessageOut::UploadKeyPackagesDurable(key_package_upload()),
+        ),
+        (
+            "DeleteGroupSoft",
+            MinimalDsMessageOut::DeleteGroupSoft(DeleteGroupSoftRequest {
+                credentials: credentials.clone(),
+                group_id,
+                retain_ms: 86_400_000,
+            }),
+        ),
+        (
+            "RestoreGroup",
+            MinimalDsMessageOut::RestoreGroup(RestoreGroupRequest {
+                credentials: credentials.clone(),
+                group_id,
+            }),
+        ),
+        (
+            "DistributeWelcomeTo",
+            MinimalDsMessageOut::DistributeWelcomeTo(requests::DistributeWelcomeToRequestOut {
+                credentials: &credentials,
+                message: &inputs.welcome,
+                recipients: &recipients,
+            }),
+        ),
+        ("Capabilities", MinimalDsMessageOut::Capabilities),
+        (
+            "PurgeQueue",
+            MinimalDsMessageOut::PurgeQueue(PurgeQueueRequest {
+                credentials: credentials.clone(),
+            }),
+        ),
+        (
+            "QueueDepth",
+            MinimalDsMessageOut::QueueDepth(QueueDepthRequest {
+                credentials: credentials.clone(),
+                last_seen_sequence_number: 42,
+            }),
+        ),
+        (
+            "DistributeGroupMessages",
+            MinimalDsMessageOut::DistributeGroupMessages(
+                requests::DistributeGroupMessagesRequestOut {
+                    credentials: &credentials,
+                    messages: &group_messages,
+                },
+            ),
+        ),
+        (
+            "Logout",
+            MinimalDsMessageOut::Logout(LogoutRequest {
+                credentials: credentials.clone(),
+            }),
+        ),
+        (
+            "FetchMessagesSince",
+            MinimalDsMessageOut::FetchMessagesSince(FetchMessagesSinceRequest {
+                credentials: credentials.clone(),
+                since_ms: 1_700_000_000_000,
+                number_of_messages: 100,
+            }),
+        ),
+        (
+            "ProvisionToken",
+            MinimalDsMessageOut::ProvisionToken(ProvisionTokenRequest {
+                credentials: credentials.clone(),
+            }),
+        ),
+        (
+            "FetchMessageRange",
+            MinimalDsMessageOut::FetchMessageRange(FetchMessageRangeRequest {
+                credentials: credentials.clone(),
+                from: 10,
+                to: 20,
+            }),
+        ),
+        (
+            "ResetKeyPackages",
+            MinimalDsMessageOut::ResetKeyPackages(key_package_upload()),
+        ),
+        (
+            "FetchGroupInfo",
+            MinimalDsMessageOut::FetchGroupInfo(FetchGroupInfoRequest {
+                credentials: credentials.clone(),
+                group_id,
+            }),
+        ),
+        (
+            "UpdateGroupInfo",
+            MinimalDsMessageOut::UpdateGroupInfo(requests::UpdateGroupInfoRequestOut {
+                credentials: &credentials,
+                group_id,
+                group_info: &inputs.group_info,
+                ratchet_tree: &inputs.ratchet_tree,
+            }),
+        ),
+        (
+            "UpdateGroupInfoDelta",
+            MinimalDsMessageOut::UpdateGroupInfoDelta(requests::UpdateGroupInfoDeltaRequestOut {
+                credentials: &credentials,
+                group_id,
+                group_info: &inputs.group_info,
+                base_epoch: 7,
+                ratchet_tree_delta: RatchetTreeDelta {
+                    node_count: 3,
+                    changed_nodes: vec![ChangedNode {
+                        index: 2,
+                        node: vec![0].into(),
+                    }],
+                },
+            }),
+        ),
+        (
+            "SendDeliveryReceipt",
+            MinimalDsMessageOut::SendDeliveryReceipt(DeliveryReceiptRequest {
+                credentials: credentials.clone(),
+                group_id,
+                sequence_number: 42,
+            }),
+        ),
+        (
+            "FetchDeliveryReceipts",
+            MinimalDsMessageOut::FetchDeliveryReceipts(FetchDeliveryReceiptsRequest {
+                credentials: credentials.clone(),
+                group_id,
+                message_ref: MessageRef::from_bytes(MESSAGE),
+            }),
+        ),
+        (
+            "FetchGroupMessages",
+            MinimalDsMessageOut::FetchGroupMessages(FetchGroupMessagesRequest {
+                credentials: credentials.clone(),
+                group_id,
+                last_seen_sequence_number: 42,
+                number_of_messages: 100,
+            }),
+        ),
+        (
+            "ListGroups",
+            MinimalDsMessageOut::ListGroups(ListGroupsRequest {
+                credentials: credentials.clone(),
+                offset: 10,
+                limit: 20,
+            }),
+        ),
+        (
+            "FetchFreshKeyPackage",
+            MinimalDsMessageOut::FetchFreshKeyPackage(FetchFreshKeyPackageRequest {
+                client_id: other_client_id.clone(),
+                min_remaining_seconds: 86400,
+            }),
+        ),
+        (
+            "ClaimKeyPackage",
+            MinimalDsMessageOut::ClaimKeyPackage(ClaimKeyPackageRequest {
+                client_id: other_client_id.clone(),
+            }),
+        ),
+        (
+            "UploadKeyPackageChunk",
+            MinimalDsMessageOut::UploadKeyPackageChunk(key_package_upload()),
+        ),
+        (
+            "UpdateGroupInfoIfEpoch",
+            MinimalDsMessageOut::UpdateGroupInfoIfEpoch(
+                requests::UpdateGroupInfoIfEpochRequestOut {
+                    credentials: &credentials,
+                    group_id,
+                    group_info: &inputs.group_info,
+                    ratchet_tree: &inputs.ratchet_tree,
+                    expected_epoch: 7,
+                },
+            ),
+        ),
+        (
+            "DistributeGroupMessageIfEpoch",
+            MinimalDsMessageOut::DistributeGroupMessageIfEpoch(
+                requests::DistributeGroupMessageIfEpochRequestOut {
+                    credentials: &credentials,
+                    message: &inputs.group_message,
+                    priority: MessagePriority::Control,
+                    expected_epoch: 7,
+                },
+            ),
+        ),
+        (
+            "DistributeGroupMessageIdempotent",
+            MinimalDsMessageOut::DistributeGroupMessageIdempotent(
+                requests::DistributeGroupMessageIdempotentRequestOut {
+                    credentials: &credentials,
+                    message: (&inputs.group_message).into(),
+                    priority: MessagePriority::Bulk,
+                    idempotency_key: MessageRef::from_bytes(METADATA),
+                },
+            ),
+        ),
+        (
+            "RotateTokenSigned",
+            MinimalDsMessageOut::RotateTokenSigned(requests::SignedRotateTokenRequestOut {
+                content: requests::RotateTokenContentOut {
+                    key_packages,
+                    last_resort_key_package: &inputs.last_resort_key_package,
+                    timestamp_ms: TIMESTAMP_MS,
+                },
+                signature: SIGNATURE.to_vec(),
+            }),
+        ),
+    ];
+
+    messages
+        .into_iter()
+        .map(|(name, message)| {
+            Ok(TestVector {
+                name,
+                bytes: message.tls_serialize_detached()?,
+            })
+        })
+        .collect()
+}
+
+/// Encode `vectors` as a JSON array of objects with the name of each request
+/// and its hex-encoded serialization.
+pub fn to_json(vectors: &[TestVector]) -> String {
+    let entries = vectors
+        .iter()
+        .map(|vector| {
+            format!(
+                "  {{\"name\": \"{}\", \"bytes\": \"{}\"}}",
+                vector.name,
+                hex_encode(&vector.bytes)
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[\n{}\n]\n", entries.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{TestClient, TestGroup};
+
+    // The wire tags of all requests. Changing a tag breaks compatibility with
+    // existing DS implementations.
+    const REQUEST_TAGS: [(&str, u8); 44] = [
+        ("RegisterClient", 0),
+        ("UploadKeyPackages", 1),
+        ("ListClients", 2),
+        ("CreateGroup", 3),
+        ("FetchKeyPackage", 4),
+        ("DistributeGroupMessage", 5),
+        ("DistributeWelcome", 6),
+        ("FetchMessages", 7),
+        ("DeleteGroup", 8),
+        ("DeleteClient", 9),
+        ("DeleteGroups", 10),
+        ("DeleteClients", 11),
+        ("WhoAmI", 12),
+        ("DistributeGroupMessageTo", 13),
+        ("CreateGroupFromRef", 14),
+        ("RotateToken", 15),
+        ("MyGroups", 16),
+        ("UploadKeyPackagesDurable", 17),
+        ("DeleteGroupSoft", 18),
+        ("RestoreGroup", 19),
+        ("DistributeWelcomeTo", 20),
+        ("Capabilities", 21),
+        ("PurgeQueue", 22),
+        ("QueueDepth", 23),
+        ("DistributeGroupMessages", 24),
+        ("Logout", 25),
+        ("FetchMessagesSince", 26),
+        ("ProvisionToken", 27),
+        ("FetchMessageRange", 28),
+        ("ResetKeyPackages", 29),
+        ("FetchGroupInfo", 30),
+        ("UpdateGroupInfo", 31),
+        ("UpdateGroupInfoDelta", 32),
+        ("SendDeliveryReceipt", 33),
+        ("FetchDeliveryReceipts", 34),
+        ("FetchGroupMessages", 35),
+        ("ListGroups", 36),
+        ("FetchFreshKeyPackage", 37),
+        ("ClaimKeyPackage", 38),
+        ("UploadKeyPackageChunk", 39),
+        ("UpdateGroupInfoIfEpoch", 40),
+        ("DistributeGroupMessageIfEpoch", 41),
+        ("DistributeGroupMessageIdempotent", 42),
+        ("RotateTokenSigned", 43),
+    ];
+
+    fn inputs() -> TestVectorInputs {
+        let mut group = TestGroup::new();
+        let (mut key_packages, last_resort_key_package) = TestClient::new(3).key_packages(1);
+        TestVectorInputs {
+            key_package: key_packages.remove(0),
+            last_resort_key_package,
+            group_info: group.group_info(),
+            ratchet_tree: group.group.export_ratchet_tree(),
+            welcome: group.welcome.clone(),
+            group_message: group.assisted_application_message(MESSAGE),
+        }
+    }
+
+    #[test]
+    fn request_tags_are_stable() {
+        let vectors = test_vectors(&inputs()).unwrap();
+        let tags = vectors
+            .iter()
+            .map(|vector| (vector.name, vector.bytes[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(tags, REQUEST_TAGS);
+    }
+}
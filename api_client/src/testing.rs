@@ -0,0 +1,160 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Helpers for tests that interact with the DS.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use futures::future::{self, BoxFuture};
+use openmls::prelude::{
+    tls_codec::Serialize, Ciphersuite, CredentialWithKey, CryptoConfig, Extension, Extensions,
+    KeyPackage, LastResortExtension, OpenMlsProvider, Signer,
+};
+
+use crate::{
+    clock::Clock,
+    errors::SendMessageError,
+    requests::DsResponse,
+    transport::{Transport, TransportRequest},
+    LastResortKeyPackage, OneTimeKeyPackage,
+};
+
+/// Generate `count` one-time key packages and a last resort key package for
+/// the given credential, in the form expected by
+/// [`UnregisteredApiClient::register`](crate::UnregisteredApiClient::register)
+/// and [`ApiClient::upload_key_packages`](crate::ApiClient::upload_key_packages).
+///
+/// # Panics
+///
+/// Panics if a key package can't be created.
+pub fn generate_key_packages(
+    ciphersuite: Ciphersuite,
+    provider: &impl OpenMlsProvider,
+    signer: &impl Signer,
+    credential_with_key: CredentialWithKey,
+    count: usize,
+) -> (Vec<OneTimeKeyPackage>, LastResortKeyPackage) {
+    let crypto_config = CryptoConfig::with_default_version(ciphersuite);
+    let key_packages = (0..count)
+        .map(|_| {
+            let key_package = KeyPackage::builder()
+                .build(crypto_config, provider, signer, credential_with_key.clone())
+                .expect("error creating key package");
+            OneTimeKeyPackage::new(key_package.into()).expect("error creating key package")
+        })
+        .collect();
+    let last_resort_key_package = KeyPackage::builder()
+        .key_package_extensions(Extensions::single(Extension::LastResort(
+            LastResortExtension::default(),
+        )))
+        .build(crypto_config, provider, signer, credential_with_key)
+        .expect("error creating last resort key package");
+    let last_resort_key_package = LastResortKeyPackage::new(last_resort_key_package.into())
+        .expect("error creating last resort key package");
+    (key_packages, last_resort_key_package)
+}
+
+/// A [`Transport`] that records all requests and answers them with queued
+/// responses, in order. Clones share the same queue and records, so a clone
+/// can be passed to
+/// [`ApiClientBuilder::transport`](crate::builder::ApiClientBuilder::transport)
+/// while the original is used to drive the test.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockTransportState>>,
+}
+
+#[derive(Default)]
+struct MockTransportState {
+    responses: VecDeque<Result<Vec<u8>, SendMessageError>>,
+    requests: Vec<TransportRequest>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `response` as the answer to a future request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the response can't be serialized.
+    pub fn push_response(&self, response: DsResponse) {
+        let response = response
+            .tls_serialize_detached()
+            .expect("error serializing response");
+        self.state.lock().unwrap().responses.push_back(Ok(response));
+    }
+
+    /// Queue `error` as the answer to a future request.
+    pub fn push_error(&self, error: SendMessageError) {
+        self.state.lock().unwrap().responses.push_back(Err(error));
+    }
+
+    /// All requests sent so far.
+    pub fn requests(&self) -> Vec<TransportRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<Vec<u8>, SendMessageError>> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(request);
+        let response = state.responses.pop_front().unwrap_or_else(|| {
+            Err(SendMessageError::MinimalDsError(
+                "No response queued in MockTransport.".to_owned(),
+            ))
+        });
+        Box::pin(future::ready(response))
+    }
+}
+
+/// A [`Clock`] that only advances when told to, so that tests of token expiry
+/// and polling don't have to wait. Pending sleeps complete once the clock was
+/// advanced past their end. Clones share the same time, so a clone can be
+/// passed to
+/// [`ApiClientBuilder::clock`](crate::builder::ApiClientBuilder::clock) while
+/// the original is used to drive the test.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<tokio::sync::watch::Sender<SystemTime>>,
+}
+
+impl ManualClock {
+    /// Create a clock that starts at `now`.
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Arc::new(tokio::sync::watch::channel(now).0),
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = self.now() + duration;
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // Fails only once all clones of the clock are dropped, in which
+            // case the deadline is never reached.
+            if now.wait_for(|now| *now >= deadline).await.is_err() {
+                future::pending::<()>().await;
+            }
+        })
+    }
+}
@@ -0,0 +1,50 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Pluggable transports to talk to the DS without HTTP.
+
+use futures::future::BoxFuture;
+
+use crate::{errors::SendMessageError, requests::Operation};
+
+/// A transport that delivers serialized requests to the DS and returns its
+/// serialized responses. By default, requests are sent via HTTP. A custom
+/// transport can be set via
+/// [`ApiClientBuilder::transport`](crate::builder::ApiClientBuilder::transport),
+/// e.g. to talk to an in-process DS in tests.
+///
+/// Since a custom transport only sees the [`TransportRequest`], HTTP-specific
+/// settings of the builder, such as headers and routes, don't apply to it.
+pub trait Transport: Send + Sync {
+    /// Send the serialized request and return the serialized
+    /// [`DsResponse`](crate::requests::DsResponse). Errors should be mapped to
+    /// the [`SendMessageError`] an HTTP transport would return, e.g.
+    /// [`SendMessageError::Unauthorized`] if the credentials are rejected.
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<Vec<u8>, SendMessageError>>;
+}
+
+/// A request handed to a [`Transport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportRequest {
+    pub operation: Operation,
+    /// The serialized request.
+    pub body: Vec<u8>,
+    /// The signature of `body` if request signing is enabled via
+    /// [`ApiClient::enable_request_signing`](crate::ApiClient::enable_request_signing).
+    /// The transport must deliver it to the DS along with the body, just like
+    /// the HTTP transport sends it in the
+    /// [`REQUEST_SIGNATURE_HEADER`](crate::REQUEST_SIGNATURE_HEADER) and
+    /// [`REQUEST_TIMESTAMP_HEADER`](crate::REQUEST_TIMESTAMP_HEADER) headers.
+    pub signature: Option<RequestSignature>,
+}
+
+/// The signature of a request, see
+/// [`REQUEST_SIGNATURE_LABEL`](minimal_ds_types::requests::REQUEST_SIGNATURE_LABEL)
+/// for what is signed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSignature {
+    /// When the request was signed, in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u64,
+    pub signature: Vec<u8>,
+}
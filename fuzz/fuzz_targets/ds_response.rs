@@ -0,0 +1,21 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Feeds arbitrary bytes into the parsers that process data received from the
+//! DS. The DS is untrusted, so they must return errors instead of panicking.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use minimal_ds_types::{requests::DsResponse, MlsMessageHeader};
+use tls_codec::DeserializeBytes;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(DsResponse::FetchMessages(response)) = DsResponse::tls_deserialize_exact_bytes(data) {
+        for message in response.messages {
+            let _ = message.message.header();
+        }
+    }
+    let _ = MlsMessageHeader::parse(data);
+});
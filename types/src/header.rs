@@ -0,0 +1,200 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use tls_codec::{DeserializeBytes, TlsDeserializeBytes, TlsSerialize, TlsSize, VLBytes};
+
+/// The protocol version of MLS 1.0 as defined in RFC 9420, currently the only
+/// version.
+pub const PROTOCOL_VERSION_MLS10: u16 = 1;
+
+// Wire format, sender type and content type values as defined in RFC 9420.
+const WIRE_FORMAT_PUBLIC_MESSAGE: u16 = 1;
+const WIRE_FORMAT_PRIVATE_MESSAGE: u16 = 2;
+const WIRE_FORMAT_WELCOME: u16 = 3;
+const WIRE_FORMAT_GROUP_INFO: u16 = 4;
+const WIRE_FORMAT_KEY_PACKAGE: u16 = 5;
+const SENDER_TYPE_MEMBER: u8 = 1;
+const SENDER_TYPE_EXTERNAL: u8 = 2;
+const SENDER_TYPE_NEW_MEMBER_PROPOSAL: u8 = 3;
+const SENDER_TYPE_NEW_MEMBER_COMMIT: u8 = 4;
+const CONTENT_TYPE_APPLICATION: u8 = 1;
+const CONTENT_TYPE_PROPOSAL: u8 = 2;
+const CONTENT_TYPE_COMMIT: u8 = 3;
+
+/// The kind of an MLS message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum MlsMessageKind {
+    Application,
+    Proposal,
+    Commit,
+    Welcome,
+    GroupInfo,
+    KeyPackage,
+}
+
+/// Read the protocol version of the serialized MLS message in `bytes`, which
+/// precedes all other fields, so that messages of unknown versions can be
+/// skipped before attempting to parse them.
+pub fn protocol_version(bytes: &[u8]) -> Result<u16, tls_codec::Error> {
+    Ok(u16::tls_deserialize_bytes(bytes)?.0)
+}
+
+/// The metadata contained in the unencrypted header of a serialized MLS
+/// message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MlsMessageHeader {
+    pub kind: MlsMessageKind,
+    /// The group ID. Only present for public and private messages.
+    pub group_id: Option<Vec<u8>>,
+    /// The epoch. Only present for public and private messages.
+    pub epoch: Option<u64>,
+    /// The leaf index of the sender. Only present for public messages sent
+    /// by a group member, since private messages encrypt the sender.
+    pub sender_leaf_index: Option<u32>,
+}
+
+impl MlsMessageHeader {
+    /// Parse the header of the serialized MLS message in `bytes` without
+    /// deserializing the full message.
+    pub fn parse(bytes: &[u8]) -> Result<Self, tls_codec::Error> {
+        let (_version, rest) = u16::tls_deserialize_bytes(bytes)?;
+        let (wire_format, rest) = u16::tls_deserialize_bytes(rest)?;
+        let (group_id, epoch, sender_leaf_index, content_type) = match wire_format {
+            WIRE_FORMAT_PUBLIC_MESSAGE => {
+                let (group_id, rest) = VLBytes::tls_deserialize_bytes(rest)?;
+                let (epoch, rest) = u64::tls_deserialize_bytes(rest)?;
+                let (sender_type, rest) = u8::tls_deserialize_bytes(rest)?;
+                let (sender_leaf_index, rest) = match sender_type {
+                    SENDER_TYPE_MEMBER => {
+                        let (leaf_index, rest) = u32::tls_deserialize_bytes(rest)?;
+                        (Some(leaf_index), rest)
+                    }
+                    SENDER_TYPE_EXTERNAL => (None, u32::tls_deserialize_bytes(rest)?.1),
+                    SENDER_TYPE_NEW_MEMBER_PROPOSAL | SENDER_TYPE_NEW_MEMBER_COMMIT => (None, rest),
+                    other => return Err(tls_codec::Error::UnknownValue(other.into())),
+                };
+                let (_authenticated_data, rest) = VLBytes::tls_deserialize_bytes(rest)?;
+                let (content_type, _) = u8::tls_deserialize_bytes(rest)?;
+                (group_id, epoch, sender_leaf_index, content_type)
+            }
+            WIRE_FORMAT_PRIVATE_MESSAGE => {
+                let (group_id, rest) = VLBytes::tls_deserialize_bytes(rest)?;
+                let (epoch, rest) = u64::tls_deserialize_bytes(rest)?;
+                let (content_type, _) = u8::tls_deserialize_bytes(rest)?;
+                (group_id, epoch, None, content_type)
+            }
+            WIRE_FORMAT_WELCOME => return Ok(Self::without_group(MlsMessageKind::Welcome)),
+            WIRE_FORMAT_GROUP_INFO => return Ok(Self::without_group(MlsMessageKind::GroupInfo)),
+            WIRE_FORMAT_KEY_PACKAGE => return Ok(Self::without_group(MlsMessageKind::KeyPackage)),
+            other => return Err(tls_codec::Error::UnknownValue(other.into())),
+        };
+        let kind = match content_type {
+            CONTENT_TYPE_APPLICATION => MlsMessageKind::Application,
+            CONTENT_TYPE_PROPOSAL => MlsMessageKind::Proposal,
+            CONTENT_TYPE_COMMIT => MlsMessageKind::Commit,
+            other => return Err(tls_codec::Error::UnknownValue(other.into())),
+        };
+        Ok(Self {
+            kind,
+            group_id: Some(group_id.into()),
+            epoch: Some(epoch),
+            sender_leaf_index,
+        })
+    }
+
+    fn without_group(kind: MlsMessageKind) -> Self {
+        Self {
+            kind,
+            group_id: None,
+            epoch: None,
+            sender_leaf_index: None,
+        }
+    }
+}
+
+/// How a fetched message should be processed, determined from its header.
+/// Welcomes and group messages arrive interleaved in the same queue, but
+/// welcomes need to go through the join path, since they create the local
+/// state of a group that isn't known yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageRoute {
+    /// A welcome to a new group. The group ID and epoch are part of the
+    /// encrypted group info, so they are only known once the welcome was
+    /// decrypted using the private key of the referenced key package.
+    Welcome(WelcomeHeader),
+    /// A public or private message of an existing group.
+    Group {
+        kind: MlsMessageKind,
+        group_id: Vec<u8>,
+        epoch: u64,
+    },
+    /// A group info or key package, which isn't part of any group's message
+    /// flow.
+    Other(MlsMessageKind),
+}
+
+impl MessageRoute {
+    /// Determine the route of the serialized MLS message in `bytes` without
+    /// deserializing the full message.
+    pub fn parse(bytes: &[u8]) -> Result<Self, tls_codec::Error> {
+        let header = MlsMessageHeader::parse(bytes)?;
+        match (header.kind, header.group_id, header.epoch) {
+            (MlsMessageKind::Welcome, _, _) => Ok(Self::Welcome(WelcomeHeader::parse(bytes)?)),
+            (kind, Some(group_id), Some(epoch)) => Ok(Self::Group {
+                kind,
+                group_id,
+                epoch,
+            }),
+            (kind, _, _) => Ok(Self::Other(kind)),
+        }
+    }
+}
+
+/// The unencrypted parts of a welcome.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WelcomeHeader {
+    pub cipher_suite: u16,
+    /// The references of the key packages of the new members the welcome is
+    /// encrypted to. A client can look up which of its key packages to use
+    /// for joining by its reference.
+    pub new_members: Vec<Vec<u8>>,
+}
+
+impl WelcomeHeader {
+    /// Parse the unencrypted parts of the serialized welcome in `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, tls_codec::Error> {
+        let (_version, rest) = u16::tls_deserialize_bytes(bytes)?;
+        let (wire_format, rest) = u16::tls_deserialize_bytes(rest)?;
+        if wire_format != WIRE_FORMAT_WELCOME {
+            return Err(tls_codec::Error::UnknownValue(wire_format.into()));
+        }
+        let (cipher_suite, rest) = u16::tls_deserialize_bytes(rest)?;
+        let (secrets, _) = Vec::<EncryptedGroupSecrets>::tls_deserialize_bytes(rest)?;
+        Ok(Self {
+            cipher_suite,
+            new_members: secrets
+                .into_iter()
+                .map(|secrets| secrets.new_member.into())
+                .collect(),
+        })
+    }
+
+    /// Whether the welcome is encrypted to the key package with the given
+    /// reference.
+    pub fn is_for(&self, key_package_ref: &[u8]) -> bool {
+        self.new_members
+            .iter()
+            .any(|new_member| new_member == key_package_ref)
+    }
+}
+
+// The encrypted group secrets of a welcome for a single new member, as
+// defined in RFC 9420. The HPKE ciphertext is only skipped.
+#[derive(TlsSize, TlsDeserializeBytes)]
+struct EncryptedGroupSecrets {
+    new_member: VLBytes,
+    _kem_output: VLBytes,
+    _ciphertext: VLBytes,
+}
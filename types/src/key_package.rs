@@ -0,0 +1,77 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Reading the lifetime and extensions of a serialized key package without
+//! deserializing and validating it.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tls_codec::{DeserializeBytes, VLBytes};
+
+use crate::tree_delta::{skip_leaf_node_prefix, skip_vectors, LEAF_NODE_SOURCE_KEY_PACKAGE};
+
+// The extension type of the last resort extension, see RFC 9420.
+const EXTENSION_TYPE_LAST_RESORT: u16 = 0x000a;
+
+/// The period in which a key package is valid, in seconds since the UNIX
+/// epoch, as defined in RFC 9420.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPackageLifetime {
+    pub not_before: u64,
+    pub not_after: u64,
+}
+
+impl KeyPackageLifetime {
+    /// Read the lifetime of the serialized key package in `bytes`.
+    pub fn parse(bytes: &[u8]) -> Result<Self, tls_codec::Error> {
+        Ok(parse_lifetime(bytes)?.0)
+    }
+
+    /// The time left until the key package expires at `now`, which is zero
+    /// if it already expired.
+    pub fn remaining(&self, now: SystemTime) -> Duration {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Duration::from_secs(self.not_after).saturating_sub(now)
+    }
+}
+
+/// Whether the serialized key package in `bytes` has the last resort
+/// extension.
+pub fn has_last_resort_extension(bytes: &[u8]) -> Result<bool, tls_codec::Error> {
+    let (_, rest) = parse_lifetime(bytes)?;
+    // The extensions and the signature of the leaf node.
+    let rest = skip_vectors(rest, 2)?;
+    let (extensions, _) = VLBytes::tls_deserialize_bytes(rest)?;
+    let mut extensions = extensions.as_slice();
+    while !extensions.is_empty() {
+        let (extension_type, rest) = u16::tls_deserialize_bytes(extensions)?;
+        if extension_type == EXTENSION_TYPE_LAST_RESORT {
+            return Ok(true);
+        }
+        extensions = skip_vectors(rest, 1)?;
+    }
+    Ok(false)
+}
+
+/// Read the lifetime of the serialized key package in `bytes` and return it
+/// together with the bytes following it.
+fn parse_lifetime(bytes: &[u8]) -> Result<(KeyPackageLifetime, &[u8]), tls_codec::Error> {
+    // Protocol version and cipher suite, followed by the init key.
+    let rest = bytes.get(4..).ok_or(tls_codec::Error::EndOfStream)?;
+    let rest = skip_vectors(rest, 1)?;
+    let rest = skip_leaf_node_prefix(rest)?;
+    let (leaf_node_source, rest) = u8::tls_deserialize_bytes(rest)?;
+    if leaf_node_source != LEAF_NODE_SOURCE_KEY_PACKAGE {
+        return Err(tls_codec::Error::UnknownValue(leaf_node_source.into()));
+    }
+    let (not_before, rest) = u64::tls_deserialize_bytes(rest)?;
+    let (not_after, rest) = u64::tls_deserialize_bytes(rest)?;
+    Ok((
+        KeyPackageLifetime {
+            not_before,
+            not_after,
+        },
+        rest,
+    ))
+}
@@ -2,9 +2,19 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use std::ops::Deref;
+use std::{collections::HashSet, ops::Deref, str::FromStr};
 
-use openmls::{framing::MlsMessageIn, group::GroupId};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use openmls::{
+    credentials::{BasicCredential, Credential, CredentialType},
+    framing::{MlsMessageIn, MlsMessageOut},
+    group::GroupId,
+};
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tls_codec::{
     DeserializeBytes as TlsDeserializeBytesTrait, Serialize as TlsSerializeTrait, Size,
@@ -15,7 +25,14 @@ use uuid::Uuid;
 #[cfg(feature = "rusqlite")]
 use rusqlite::{types::FromSql, ToSql};
 
+pub mod header;
+pub mod key_package;
 pub mod requests;
+pub mod state;
+pub mod tree_delta;
+
+pub use header::{MessageRoute, MlsMessageHeader, MlsMessageKind, WelcomeHeader};
+pub use state::ClientState;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct TlsUuid {
@@ -45,8 +62,7 @@ impl TlsDeserializeBytesTrait for TlsUuid {
             .try_into()?;
         let rest = bytes
             .get(UUID_LENGTH..)
-            .ok_or(tls_codec::Error::EndOfStream)
-            .unwrap();
+            .ok_or(tls_codec::Error::EndOfStream)?;
         let id = Uuid::from_bytes(id_bytes);
         Ok((Self { id }, rest))
     }
@@ -96,29 +112,177 @@ impl DsClientId {
         Self::try_from(identity.as_slice())
     }
 
+    /// Extract the client ID from the given credential, e.g. the credential
+    /// of a leaf node. The credential must be a [`BasicCredential`] whose
+    /// identity is the client ID.
+    pub fn from_credential(credential: &Credential) -> Result<Self, DsClientIdError> {
+        if credential.credential_type() != CredentialType::Basic {
+            return Err(DsClientIdError::NotBasicCredential);
+        }
+        Self::from_serialized_credential(credential.serialized_content())
+    }
+
+    /// Create a [`BasicCredential`] with this client ID as its identity. This
+    /// is the inverse of [`Self::from_credential`].
+    pub fn to_basic_credential(&self) -> Credential {
+        BasicCredential::new(self.id.clone()).into()
+    }
+
+    /// Create a [`DsClientId`] from the bytes of a UUID. Unlike
+    /// [`Self::new`], this requires `bytes` to be exactly 16 bytes long.
+    pub fn new_uuid(bytes: &[u8]) -> Result<Self, DsClientIdError> {
+        let id =
+            Uuid::from_slice(bytes).map_err(|_| DsClientIdError::InvalidUuidLength(bytes.len()))?;
+        Ok(Self::from_uuid(id))
+    }
+
+    /// Create a [`DsClientId`] from the given UUID. The resulting client ID
+    /// consists of the 16 bytes of the UUID, which is the same form that
+    /// [`Self::from_serialized_credential`] produces for a UUID identity.
+    pub fn from_uuid(id: Uuid) -> Self {
+        Self {
+            id: id.as_bytes().to_vec(),
+        }
+    }
+
+    /// Interpret this client ID as a UUID. Returns `None` if the client ID is
+    /// not exactly 16 bytes long.
+    pub fn as_uuid(&self) -> Option<Uuid> {
+        Uuid::from_slice(&self.id).ok()
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         &self.id
     }
+
+    /// The length of the client ID in bytes.
+    pub fn len(&self) -> usize {
+        self.id.len()
+    }
+
+    /// Whether the client ID is empty. Client IDs created via
+    /// [`Self::new`] are never empty, but deserialized ones may be.
+    pub fn is_empty(&self) -> bool {
+        self.id.is_empty()
+    }
+
+    /// The SHA-256 hash of the client ID. Can be used to display and compare
+    /// client IDs without revealing the raw value.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        Sha256::digest(&self.id).into()
+    }
+
+    /// The canonical key under which the client ID is stored, i.e. its raw
+    /// bytes. This is also how the `rusqlite` feature stores client IDs, as a
+    /// blob, so the key can be compared with stored values directly. The
+    /// format is stable.
+    pub fn storage_key(&self) -> Vec<u8> {
+        self.id.clone()
+    }
 }
 
+/// Formats the client ID as lowercase hex, which can be parsed again using
+/// [`FromStr`].
 impl std::fmt::Display for DsClientId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.id)
+        for byte in &self.id {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
     }
 }
 
+/// Parses a client ID from hex or from a hyphenated UUID.
+impl FromStr for DsClientId {
+    type Err = DsClientIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = Uuid::try_parse(s) {
+            return Ok(Self::from_uuid(id));
+        }
+        if s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(DsClientIdError::InvalidHex);
+        }
+        let bytes = s
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let digits = std::str::from_utf8(pair).map_err(|_| DsClientIdError::InvalidHex)?;
+                u8::from_str_radix(digits, 16).map_err(|_| DsClientIdError::InvalidHex)
+            })
+            .collect::<Result<Vec<u8>, _>>()?;
+        Self::new(&bytes)
+    }
+}
+
+impl TryFrom<&str> for DsClientId {
+    type Error = DsClientIdError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The difference between two lists of group members, see [`diff_members`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemberDiff {
+    /// Members in the new list, but not in the old one.
+    pub added: Vec<DsClientId>,
+    /// Members in the old list, but not in the new one.
+    pub removed: Vec<DsClientId>,
+}
+
+impl MemberDiff {
+    /// Whether both lists contain the same members.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Compute which members were added and removed between the lists `old` and
+/// `new`, e.g. to reconcile the local state of a group with the members known
+/// to the DS. The order of the lists doesn't matter and duplicates are
+/// ignored. Both results are in the order of the list they are taken from.
+pub fn diff_members(old: &[DsClientId], new: &[DsClientId]) -> MemberDiff {
+    let old_set: HashSet<&DsClientId> = old.iter().collect();
+    let new_set: HashSet<&DsClientId> = new.iter().collect();
+    let mut seen = HashSet::new();
+    let added = new
+        .iter()
+        .filter(|id| !old_set.contains(id) && seen.insert(*id))
+        .cloned()
+        .collect();
+    let removed = old
+        .iter()
+        .filter(|id| !new_set.contains(id) && seen.insert(*id))
+        .cloned()
+        .collect();
+    MemberDiff { added, removed }
+}
+
 #[derive(Debug, Error)]
 pub enum DsClientIdError {
     #[error("Invalid Credential: {0}")]
     InvalidCredential(#[from] tls_codec::Error),
     #[error("Too many bytes in the input. Expected 32 bytes.")]
     TooManyBytes,
+    #[error("Invalid UUID length: expected 16 bytes, got {0}.")]
+    InvalidUuidLength(usize),
+    #[error("Invalid client ID string. Expected hex or a UUID.")]
+    InvalidHex,
+    #[error("The credential is not a BasicCredential.")]
+    NotBasicCredential,
+    #[error("The client ID is empty.")]
+    Empty,
 }
 
 impl TryFrom<&[u8]> for DsClientId {
     type Error = DsClientIdError;
 
     fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.is_empty() {
+            return Err(DsClientIdError::Empty);
+        }
         if bytes.len() >= 1000 {
             return Err(DsClientIdError::TooManyBytes);
         }
@@ -127,6 +291,8 @@ impl TryFrom<&[u8]> for DsClientId {
     }
 }
 
+/// Stores the client ID as a blob of its bytes, see
+/// [`DsClientId::storage_key`].
 #[cfg(feature = "rusqlite")]
 impl ToSql for DsClientId {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
@@ -144,6 +310,8 @@ impl FromSql for DsClientId {
     }
 }
 
+/// Stores the group ID as a blob of the 16 bytes of its UUID, see
+/// [`DsGroupId::storage_key`].
 #[cfg(feature = "rusqlite")]
 impl ToSql for DsGroupId {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
@@ -159,7 +327,19 @@ impl FromSql for DsGroupId {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
 pub struct DsGroupId {
     id: TlsUuid,
 }
@@ -186,9 +366,61 @@ impl DsGroupId {
         }
     }
 
+    /// Create a new group ID from a time-ordered (version 7) UUID, such that
+    /// group IDs sort by their creation time.
+    pub fn new_v7() -> Self {
+        Self {
+            id: Uuid::now_v7().into(),
+        }
+    }
+
+    /// Create a new (version 4) group ID from the randomness of the given
+    /// `rng`, e.g. to obtain reproducible group IDs in tests.
+    pub fn from_rng(rng: &mut impl RngCore) -> Self {
+        let mut bytes = [0u8; 16];
+        rng.fill_bytes(&mut bytes);
+        Self {
+            id: uuid::Builder::from_random_bytes(bytes).into_uuid().into(),
+        }
+    }
+
     pub fn as_slice(&self) -> &[u8; 16] {
         self.id.as_bytes()
     }
+
+    /// The canonical key under which the group ID is stored, i.e. the 16
+    /// bytes of its UUID. This is also how the `rusqlite` feature stores
+    /// group IDs, as a blob, so the key can be compared with stored values
+    /// directly, like that of [`DsClientId::storage_key`]. The format is
+    /// stable.
+    pub fn storage_key(&self) -> Vec<u8> {
+        self.as_slice().to_vec()
+    }
+}
+
+/// Formats the group ID as a hyphenated UUID, which can be parsed again using
+/// [`FromStr`].
+impl std::fmt::Display for DsGroupId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", *self.id)
+    }
+}
+
+impl FromStr for DsGroupId {
+    type Err = DsGroupIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = Uuid::try_parse(s)?.into();
+        Ok(Self { id })
+    }
+}
+
+impl TryFrom<&str> for DsGroupId {
+    type Error = DsGroupIdError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
 }
 
 #[derive(Debug, Error)]
@@ -197,20 +429,134 @@ pub enum DsGroupIdError {
     InvalidUuid(#[from] uuid::Error),
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
 pub struct AuthToken {
     token: [u8; 32],
 }
 
-#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
-pub struct ClientCredentials {
-    pub client_id: DsClientId,
-    pub token: AuthToken,
+// The token is a secret, so we don't want it to end up in logs. This also
+// covers types containing it, such as `ClientCredentials`.
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AuthToken(<redacted>)")
+    }
+}
+
+// Binds sealed tokens to their purpose, so that other ciphertexts produced
+// with the same key can't be passed off as a sealed token.
+const AUTH_TOKEN_SEAL_LABEL: &[u8] = b"minimal-ds auth token";
+const AUTH_TOKEN_NONCE_LEN: usize = 12;
+
+impl AuthToken {
+    /// Encrypt the token with ChaCha20-Poly1305 under `key`, e.g. to persist
+    /// it. The result consists of a random nonce followed by the ciphertext
+    /// and can be decrypted using [`Self::open`].
+    pub fn seal(&self, key: &[u8; 32]) -> Vec<u8> {
+        let cipher = ChaCha20Poly1305::new(key.into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &self.token,
+            aad: AUTH_TOKEN_SEAL_LABEL,
+        };
+        // Encryption only fails for plaintexts exceeding the maximum length.
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .expect("Failed to encrypt the auth token.");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt a token sealed with [`Self::seal`] under the same `key`.
+    pub fn open(sealed: &[u8], key: &[u8; 32]) -> Result<Self, AuthTokenError> {
+        if sealed.len() < AUTH_TOKEN_NONCE_LEN {
+            return Err(AuthTokenError::InvalidLength);
+        }
+        let (nonce, ciphertext) = sealed.split_at(AUTH_TOKEN_NONCE_LEN);
+        let cipher = ChaCha20Poly1305::new(key.into());
+        let payload = Payload {
+            msg: ciphertext,
+            aad: AUTH_TOKEN_SEAL_LABEL,
+        };
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| AuthTokenError::DecryptionFailed)?;
+        let token = plaintext
+            .try_into()
+            .map_err(|_| AuthTokenError::InvalidLength)?;
+        Ok(Self { token })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AuthTokenError {
+    #[error("The sealed auth token has an invalid length.")]
+    InvalidLength,
+    #[error("Failed to decrypt the sealed auth token.")]
+    DecryptionFailed,
+}
+
+/// Authenticates a request as sent by a client.
+///
+/// The token variant is encoded without a tag, exactly like the former
+/// `{ client_id, token }` struct, so the encoding of existing requests is
+/// unchanged. Further auth schemes are introduced with new request variants
+/// carrying their own credentials type rather than by extending this enum.
+#[derive(Debug, Clone)]
+pub enum ClientCredentials {
+    /// The auth token issued to the client by the DS.
+    Token(DsClientId, AuthToken),
 }
 
 impl ClientCredentials {
     pub fn client_id(&self) -> DsClientId {
-        self.client_id.clone()
+        match self {
+            Self::Token(client_id, _) => client_id.clone(),
+        }
+    }
+
+    pub fn token(&self) -> &AuthToken {
+        match self {
+            Self::Token(_, token) => token,
+        }
+    }
+}
+
+impl Size for ClientCredentials {
+    fn tls_serialized_len(&self) -> usize {
+        match self {
+            Self::Token(client_id, token) => {
+                client_id.tls_serialized_len() + token.tls_serialized_len()
+            }
+        }
+    }
+}
+
+impl TlsSerializeTrait for ClientCredentials {
+    fn tls_serialize<W: std::io::Write>(&self, writer: &mut W) -> Result<usize, tls_codec::Error> {
+        match self {
+            Self::Token(client_id, token) => {
+                let written = client_id.tls_serialize(writer)?;
+                Ok(written + token.tls_serialize(writer)?)
+            }
+        }
+    }
+}
+
+impl TlsDeserializeBytesTrait for ClientCredentials {
+    fn tls_deserialize_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), tls_codec::Error> {
+        let (client_id, bytes) = DsClientId::tls_deserialize_bytes(bytes)?;
+        let (token, bytes) = AuthToken::tls_deserialize_bytes(bytes)?;
+        Ok((Self::Token(client_id, token), bytes))
     }
 }
 
@@ -228,13 +574,196 @@ impl DsQueueMessage {
         Self { message: bytes }
     }
 
+    /// Serialize the given message into a [`DsQueueMessage`]. `openmls` only
+    /// supports serializing outgoing messages, so received messages that
+    /// need to be persisted or forwarded losslessly should be kept in their
+    /// [`DsQueueMessage`] form instead of being deserialized.
+    pub fn try_from_mls_message(message: &MlsMessageOut) -> Result<Self, tls_codec::Error> {
+        let message = message.tls_serialize_detached()?;
+        Ok(Self { message })
+    }
+
     pub fn deserialize(&self) -> Result<MlsMessageIn, tls_codec::Error> {
         MlsMessageIn::tls_deserialize_exact_bytes(self.as_slice())
     }
+
+    /// The MLS protocol version of the message, read without deserializing
+    /// it. Messages whose version isn't [`header::PROTOCOL_VERSION_MLS10`]
+    /// likely fail to deserialize and can be skipped or handled separately.
+    pub fn protocol_version(&self) -> Result<u16, tls_codec::Error> {
+        header::protocol_version(self.as_slice())
+    }
+
+    /// Parse the header of the message without fully deserializing it.
+    pub fn header(&self) -> Result<MlsMessageHeader, tls_codec::Error> {
+        MlsMessageHeader::parse(self.as_slice())
+    }
+
+    /// Classify the message by inspecting only its header, without fully
+    /// deserializing it.
+    pub fn message_kind(&self) -> Result<MlsMessageKind, tls_codec::Error> {
+        Ok(self.header()?.kind)
+    }
+
+    /// Determine how the message should be processed, e.g. to route welcomes
+    /// into the join path, without fully deserializing it.
+    pub fn route(&self) -> Result<MessageRoute, tls_codec::Error> {
+        MessageRoute::parse(self.as_slice())
+    }
 }
 
 #[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
 pub struct NumberedDsQueueMessage {
     pub message: DsQueueMessage,
     pub sequence_number: u64,
+    /// Opaque metadata the sender attached when distributing the message,
+    /// relayed verbatim by the DS. It is not authenticated by MLS, so it can
+    /// be altered by the DS.
+    pub metadata: Vec<u8>,
+}
+
+/// Like [`NumberedDsQueueMessage`], but additionally carries the time at
+/// which the DS enqueued the message.
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct TimestampedDsQueueMessage {
+    pub message: DsQueueMessage,
+    pub sequence_number: u64,
+    /// Enqueue time in milliseconds since the UNIX epoch.
+    pub enqueued_at: u64,
+    /// See [`NumberedDsQueueMessage::metadata`].
+    pub metadata: Vec<u8>,
+}
+
+/// A message fetched from the DS queue, independent of whether the DS
+/// provided an enqueue timestamp.
+#[derive(Debug)]
+pub struct QueuedMessage {
+    pub message: DsQueueMessage,
+    pub sequence_number: u64,
+    /// Enqueue time in milliseconds since the UNIX epoch, if provided by the
+    /// DS.
+    pub enqueued_at: Option<u64>,
+    /// See [`NumberedDsQueueMessage::metadata`].
+    pub metadata: Vec<u8>,
+}
+
+impl From<NumberedDsQueueMessage> for QueuedMessage {
+    fn from(message: NumberedDsQueueMessage) -> Self {
+        Self {
+            message: message.message,
+            sequence_number: message.sequence_number,
+            enqueued_at: None,
+            metadata: message.metadata,
+        }
+    }
+}
+
+impl From<TimestampedDsQueueMessage> for QueuedMessage {
+    fn from(message: TimestampedDsQueueMessage) -> Self {
+        Self {
+            message: message.message,
+            sequence_number: message.sequence_number,
+            enqueued_at: Some(message.enqueued_at),
+            metadata: message.metadata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_client_id_is_rejected() {
+        let empty: &[u8] = &[];
+        assert!(matches!(
+            DsClientId::try_from(empty),
+            Err(DsClientIdError::Empty)
+        ));
+        assert!(matches!(DsClientId::new(&[]), Err(DsClientIdError::Empty)));
+
+        let client_id = DsClientId::new(&[1]).unwrap();
+        assert_eq!(client_id.len(), 1);
+        assert!(!client_id.is_empty());
+    }
+
+    fn ids(bytes: &[u8]) -> Vec<DsClientId> {
+        bytes
+            .iter()
+            .map(|byte| DsClientId::new(&[*byte]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn diff_members_of_identical_lists_is_empty() {
+        let diff = diff_members(&ids(&[1, 2, 3]), &ids(&[1, 2, 3]));
+        assert!(diff.is_empty());
+        assert_eq!(diff, MemberDiff::default());
+    }
+
+    #[test]
+    fn diff_members_ignores_order() {
+        let diff = diff_members(&ids(&[1, 2, 3]), &ids(&[3, 1, 2]));
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn diff_members_ignores_duplicates() {
+        let diff = diff_members(&ids(&[1, 1, 2]), &ids(&[2, 3, 3, 2]));
+        assert_eq!(
+            diff,
+            MemberDiff {
+                added: ids(&[3]),
+                removed: ids(&[1]),
+            }
+        );
+    }
+
+    #[test]
+    fn diff_members_of_disjoint_lists() {
+        let diff = diff_members(&ids(&[1, 2]), &ids(&[4, 3]));
+        assert_eq!(
+            diff,
+            MemberDiff {
+                added: ids(&[4, 3]),
+                removed: ids(&[1, 2]),
+            }
+        );
+    }
+
+    /// Store `value` in an in-memory database and read it back, both as the
+    /// same type and as a raw blob.
+    #[cfg(feature = "rusqlite")]
+    fn sql_round_trip<T: ToSql + FromSql>(value: &T) -> (T, Vec<u8>) {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection
+            .execute("CREATE TABLE ids (id BLOB NOT NULL)", [])
+            .unwrap();
+        connection
+            .execute("INSERT INTO ids (id) VALUES (?1)", rusqlite::params![value])
+            .unwrap();
+        connection
+            .query_row("SELECT id, id FROM ids", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap()
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn client_id_sql_round_trip() {
+        let client_id = DsClientId::new(b"client").unwrap();
+        let (stored, blob) = sql_round_trip(&client_id);
+        assert_eq!(stored, client_id);
+        assert_eq!(blob, client_id.storage_key());
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn group_id_sql_round_trip() {
+        let group_id = DsGroupId::new();
+        let (stored, blob) = sql_round_trip(&group_id);
+        assert_eq!(stored, group_id);
+        assert_eq!(blob, group_id.storage_key());
+    }
 }
@@ -2,9 +2,126 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+use openmls::{
+    framing::{MlsMessageIn, MlsMessageOut},
+    key_packages::KeyPackageIn,
+    treesync::RatchetTreeIn,
+};
+use sha2::{Digest, Sha256};
+use tls_codec::{DeserializeBytes, Serialize, TlsDeserializeBytes, TlsSerialize, TlsSize};
 
-use crate::{ClientCredentials, DsClientId, DsGroupId, NumberedDsQueueMessage};
+use crate::{
+    AuthToken, ClientCredentials, DsClientId, DsGroupId, MlsMessageKind, NumberedDsQueueMessage,
+    TimestampedDsQueueMessage,
+};
+
+/// A response of the DS to any request. This is what is sent in the body of
+/// the HTTP response, and can be used by alternative transports or mock DS
+/// implementations to produce and consume responses.
+///
+/// The discriminants are the tags of the responses on the wire. They must not
+/// change, and new variants must use the next free value and update
+/// [`DsResponse::MAX_TAG`].
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum DsResponse {
+    Ok = 0,
+    AuthToken(AuthToken) = 1,
+    KeyPackageOption(Option<KeyPackageIn>) = 2,
+    FetchMessages(FetchMessagesResponse) = 3,
+    ListClients(ListClientsResponse) = 4,
+    BatchResults(Vec<BatchItemResult>) = 5,
+    WhoAmI(ClientInfo) = 6,
+    /// Returned when the DS doesn't store the ratchet tree a request refers
+    /// to, i.e. the tree referenced when creating a group, or the base tree
+    /// of a tree delta.
+    UnknownGroupInfoRef = 7,
+    AlreadyRegistered = 8,
+    FetchMessagesWithTimestamps(FetchMessagesWithTimestampsResponse) = 9,
+    GroupExists(GroupExistsResponse) = 10,
+    GroupIds(Vec<DsGroupId>) = 11,
+    Persisted = 12,
+    Distributed(DistributionReport) = 13,
+    FetchMessagesWithToken(FetchMessagesWithTokenResponse) = 14,
+    FetchMessagesFiltered(FetchMessagesFilteredResponse) = 15,
+    Registration(RegistrationResponse) = 16,
+    /// Returned when restoring a group that isn't marked for deletion.
+    NotMarkedForDeletion = 17,
+    /// Returned when a client sends a message to a group it isn't a member
+    /// of (anymore).
+    NotGroupMember = 18,
+    Capabilities(DsCapabilities) = 19,
+    QueuePurged(PurgeQueueResponse) = 20,
+    QueueDepth(u64) = 21,
+    /// Returned instead of [`DsResponse::Registration`] by DS backends that
+    /// allocate client IDs themselves.
+    AssignedRegistration(AssignedRegistrationResponse) = 22,
+    /// The number of key packages the DS stores for the requesting client
+    /// after replacing them.
+    KeyPackageCounts(KeyPackageCounts) = 23,
+    /// Returned instead of processing the request if the client should use
+    /// a different endpoint, e.g. the shard of its tenant. Contains the
+    /// UTF-8 encoded URL of the canonical endpoint.
+    CanonicalEndpoint(Vec<u8>) = 24,
+    /// The latest group info and ratchet tree of a group, or `None` if the
+    /// DS doesn't know the group.
+    GroupInfo(Option<GroupInfoResponse>) = 25,
+    /// Returned when updating the group info of a group with one whose epoch
+    /// isn't newer than that of the stored one. Contains the epoch of the
+    /// stored group info.
+    StaleGroupInfo(u64) = 26,
+    DeliveryReceipts(Vec<DeliveryReceipt>) = 27,
+    /// Returned when the expected epoch given with a group-mutating request
+    /// doesn't match the current epoch of the group, which it contains.
+    PreconditionFailed(u64) = 28,
+    ListGroups(ListGroupsResponse) = 29,
+    /// Returned when the group a request refers to doesn't exist, e.g.
+    /// because it was already deleted.
+    GroupNotFound = 30,
+}
+
+impl DsResponse {
+    /// The largest wire tag of a response variant.
+    pub const MAX_TAG: u8 = 30;
+
+    /// The name of the variant, e.g. for error messages about unexpected
+    /// responses.
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            Self::Ok => "Ok",
+            Self::AuthToken(_) => "AuthToken",
+            Self::KeyPackageOption(_) => "KeyPackageOption",
+            Self::FetchMessages(_) => "FetchMessages",
+            Self::ListClients(_) => "ListClients",
+            Self::BatchResults(_) => "BatchResults",
+            Self::WhoAmI(_) => "WhoAmI",
+            Self::UnknownGroupInfoRef => "UnknownGroupInfoRef",
+            Self::AlreadyRegistered => "AlreadyRegistered",
+            Self::FetchMessagesWithTimestamps(_) => "FetchMessagesWithTimestamps",
+            Self::GroupExists(_) => "GroupExists",
+            Self::GroupIds(_) => "GroupIds",
+            Self::Persisted => "Persisted",
+            Self::Distributed(_) => "Distributed",
+            Self::FetchMessagesWithToken(_) => "FetchMessagesWithToken",
+            Self::FetchMessagesFiltered(_) => "FetchMessagesFiltered",
+            Self::Registration(_) => "Registration",
+            Self::NotMarkedForDeletion => "NotMarkedForDeletion",
+            Self::NotGroupMember => "NotGroupMember",
+            Self::Capabilities(_) => "Capabilities",
+            Self::QueuePurged(_) => "QueuePurged",
+            Self::QueueDepth(_) => "QueueDepth",
+            Self::AssignedRegistration(_) => "AssignedRegistration",
+            Self::KeyPackageCounts(_) => "KeyPackageCounts",
+            Self::CanonicalEndpoint(_) => "CanonicalEndpoint",
+            Self::GroupInfo(_) => "GroupInfo",
+            Self::StaleGroupInfo(_) => "StaleGroupInfo",
+            Self::DeliveryReceipts(_) => "DeliveryReceipts",
+            Self::PreconditionFailed(_) => "PreconditionFailed",
+            Self::ListGroups(_) => "ListGroups",
+            Self::GroupNotFound => "GroupNotFound",
+        }
+    }
+}
 
 #[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
 pub struct DeleteClientRequest {
@@ -18,19 +135,595 @@ pub struct DeleteGroupRequest {
     pub group_id: DsGroupId,
 }
 
+/// Marks a group for deletion once `retain_ms` milliseconds have passed.
+/// Until then, the group can be restored using a [`RestoreGroupRequest`].
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct DeleteGroupSoftRequest {
+    pub credentials: ClientCredentials,
+    pub group_id: DsGroupId,
+    pub retain_ms: u64,
+}
+
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct RestoreGroupRequest {
+    pub credentials: ClientCredentials,
+    pub group_id: DsGroupId,
+}
+
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct DeleteClientsRequest {
+    pub credentials: ClientCredentials,
+    pub client_ids: Vec<DsClientId>,
+}
+
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct DeleteGroupsRequest {
+    pub credentials: ClientCredentials,
+    pub group_ids: Vec<DsGroupId>,
+}
+
+/// The outcome of a single item of a batch request. `Error` contains a
+/// UTF-8 encoded error message.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+#[repr(u8)]
+pub enum BatchItemResult {
+    Ok,
+    Error(Vec<u8>),
+}
+
 #[derive(TlsSize, TlsSerialize, TlsDeserializeBytes)]
 pub struct FetchKeyPackageRequest {
     pub client_id: DsClientId,
 }
 
+/// Asks the DS for a key package of the given client that is valid for at
+/// least `min_remaining_seconds` more seconds. The DS answers with
+/// [`DsResponse::KeyPackageOption`], which is empty if no key package
+/// qualifies.
+#[derive(TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchFreshKeyPackageRequest {
+    pub client_id: DsClientId,
+    pub min_remaining_seconds: u64,
+}
+
+/// Asks the DS for a key package of the given client like
+/// [`FetchKeyPackageRequest`], but the DS deletes a returned one-time key
+/// package as part of the same operation, so that concurrent requests never
+/// get the same one-time key package. The DS answers with
+/// [`DsResponse::KeyPackageOption`].
+#[derive(TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ClaimKeyPackageRequest {
+    pub client_id: DsClientId,
+}
+
 #[derive(TlsSize, TlsSerialize, TlsDeserializeBytes)]
 pub struct FetchMessagesRequest {
     pub credentials: ClientCredentials,
     pub last_seen_sequence_number: u64,
     pub number_of_messages: u32,
+    /// How long the DS may hold the request open, in milliseconds, if no
+    /// messages are queued. The DS responds as soon as a message arrives.
+    pub wait_ms: u32,
+    /// An opaque continuation token previously returned by the DS. If
+    /// present, the DS resumes from the token and ignores
+    /// `last_seen_sequence_number`.
+    pub continuation_token: Option<Vec<u8>>,
+    /// If not empty, the DS only returns messages of these kinds. Messages of
+    /// other kinds are skipped and count as fetched.
+    pub kinds: Vec<MlsMessageKind>,
+    /// If true, the DS skips messages it recorded as sent by the requesting
+    /// client, e.g. its own commits. Skipped messages count as fetched.
+    pub exclude_own: bool,
 }
 
-#[derive(TlsSize, TlsSerialize, TlsDeserializeBytes)]
+/// Asks the DS for the messages queued for the requesting client that were
+/// enqueued at or after `since_ms`, independent of any cursor. The DS responds
+/// with [`DsResponse::FetchMessagesWithTimestamps`], ordered by sequence
+/// number.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchMessagesSinceRequest {
+    pub credentials: ClientCredentials,
+    /// Milliseconds since the UNIX epoch.
+    pub since_ms: u64,
+    pub number_of_messages: u32,
+}
+
+/// Asks the DS for the messages queued for the requesting client with a
+/// sequence number in `[from, to]`, independent of any cursor. The DS responds
+/// with [`DsResponse::FetchMessages`], ordered by sequence number.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchMessageRangeRequest {
+    pub credentials: ClientCredentials,
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Asks the DS for the messages queued for the requesting client that belong
+/// to the given group, with a sequence number greater than
+/// `last_seen_sequence_number`. Sequence numbers are those of the client's
+/// queue, so messages of other groups leave gaps. The DS responds with
+/// [`DsResponse::FetchMessages`], ordered by sequence number.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchGroupMessagesRequest {
+    pub credentials: ClientCredentials,
+    pub group_id: DsGroupId,
+    pub last_seen_sequence_number: u64,
+    pub number_of_messages: u32,
+}
+
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
 pub struct FetchMessagesResponse {
     pub messages: Vec<NumberedDsQueueMessage>,
 }
+
+/// Version of [`FetchMessagesResponse`] for DS backends that paginate using
+/// opaque cursors rather than sequence numbers. The client replays the
+/// `continuation_token` in its next [`FetchMessagesRequest`].
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchMessagesWithTokenResponse {
+    pub messages: Vec<NumberedDsQueueMessage>,
+    pub continuation_token: Option<Vec<u8>>,
+}
+
+/// Response to a [`FetchMessagesRequest`] with a filter on the message kinds
+/// or with `exclude_own` set.
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchMessagesFilteredResponse {
+    pub messages: Vec<NumberedDsQueueMessage>,
+    /// The sequence number of the last message the DS considered, including
+    /// messages that were skipped because of the filter.
+    pub last_scanned_sequence_number: u64,
+}
+
+/// Version of [`FetchMessagesResponse`] in which messages carry an enqueue
+/// timestamp.
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchMessagesWithTimestampsResponse {
+    pub messages: Vec<TimestampedDsQueueMessage>,
+}
+
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ListClientsRequest {
+    pub credentials: ClientCredentials,
+    pub offset: u32,
+    pub limit: u32,
+    pub prefix: Option<Vec<u8>>,
+}
+
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ListClientsResponse {
+    pub client_ids: Vec<DsClientId>,
+    pub total: u32,
+}
+
+/// Asks the DS for a page of all groups it stores. The DS may restrict this
+/// to privileged clients and reject others as unauthorized.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ListGroupsRequest {
+    pub credentials: ClientCredentials,
+    pub offset: u64,
+    pub limit: u32,
+}
+
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ListGroupsResponse {
+    pub group_ids: Vec<DsGroupId>,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct WhoAmIRequest {
+    pub credentials: ClientCredentials,
+}
+
+/// The optional features supported by a DS. Clients should only use a
+/// feature if the DS supports it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, TlsSize, TlsSerialize, TlsDeserializeBytes,
+)]
+pub struct DsCapabilities {
+    /// Holding fetch requests open until messages arrive, see
+    /// [`FetchMessagesRequest::wait_ms`].
+    pub long_polling: bool,
+    /// Paginating fetched messages using continuation tokens.
+    pub continuation_tokens: bool,
+    /// Filtering fetched messages by kind or sender.
+    pub message_filters: bool,
+    /// Requests operating on several items at once, e.g. deleting several
+    /// groups.
+    pub batch_requests: bool,
+    /// Confirming that uploaded key packages were persisted.
+    pub durable_key_packages: bool,
+    /// Soft-deleting and restoring groups.
+    pub soft_delete: bool,
+}
+
+/// Asks the DS for the number of messages queued for the requesting client
+/// with a sequence number greater than `last_seen_sequence_number`.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct QueueDepthRequest {
+    pub credentials: ClientCredentials,
+    pub last_seen_sequence_number: u64,
+}
+
+/// Asks the DS to drop all messages queued for the requesting client.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct PurgeQueueRequest {
+    pub credentials: ClientCredentials,
+}
+
+/// Asks the DS to issue an additional auth token for the client in
+/// `credentials`, e.g. for a second device. The DS responds with
+/// [`DsResponse::AuthToken`].
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ProvisionTokenRequest {
+    pub credentials: ClientCredentials,
+}
+
+/// Prefixed to the signed content of a token rotation by a client without an
+/// auth token. The client signs this label followed by the TLS encoding of
+/// the key packages, the last resort key package and the timestamp of the
+/// request, i.e. the request body up to the signature.
+///
+/// Key packages are public and can be replayed by anyone, so the DS must
+/// never issue a token merely because it was presented key packages. It must
+/// only accept such a rotation if
+/// - the signature verifies under the signature key the client registered
+///   with, not merely under the key in the presented key packages,
+/// - the timestamp is within [`ROTATE_TOKEN_MAX_SKEW_MS`] of its own time,
+///   and
+/// - it didn't accept the same signature before.
+pub const ROTATE_TOKEN_SIGNATURE_LABEL: &[u8] = b"minimal-ds rotate token";
+
+/// The maximum difference between the timestamp of a signed token rotation
+/// and the time of the DS, in milliseconds, see
+/// [`ROTATE_TOKEN_SIGNATURE_LABEL`].
+pub const ROTATE_TOKEN_MAX_SKEW_MS: u64 = 5 * 60 * 1000;
+
+/// Prefixed to the signed content of a request signature, which clients send
+/// alongside the request if request signing is enabled. The signed content is
+/// this label, followed by the timestamp of the request in milliseconds since
+/// the UNIX epoch as a big-endian `u64`, followed by the request body, see
+/// [`request_signature_content`].
+///
+/// The body alone doesn't tell when the request was made, so the DS must
+/// reject a signed request to prevent replays if
+/// - its timestamp is not within [`REQUEST_SIGNATURE_MAX_SKEW_MS`] of the time
+///   of the DS, or
+/// - it accepted the same signature before. Signatures only need to be
+///   remembered until their timestamp falls out of the window.
+pub const REQUEST_SIGNATURE_LABEL: &[u8] = b"minimal-ds request";
+
+/// The maximum difference between the timestamp of a signed request and the
+/// time of the DS, in milliseconds, see [`REQUEST_SIGNATURE_LABEL`].
+pub const REQUEST_SIGNATURE_MAX_SKEW_MS: u64 = 5 * 60 * 1000;
+
+/// The content signed by a request signature for a request made at
+/// `timestamp_ms` with body `body`, see [`REQUEST_SIGNATURE_LABEL`].
+pub fn request_signature_content(timestamp_ms: u64, body: &[u8]) -> Vec<u8> {
+    let mut content = Vec::with_capacity(REQUEST_SIGNATURE_LABEL.len() + 8 + body.len());
+    content.extend_from_slice(REQUEST_SIGNATURE_LABEL);
+    content.extend_from_slice(&timestamp_ms.to_be_bytes());
+    content.extend_from_slice(body);
+    content
+}
+
+/// Asks the DS to invalidate the auth token in `credentials`, without deleting
+/// the client.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct LogoutRequest {
+    pub credentials: ClientCredentials,
+}
+
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct PurgeQueueResponse {
+    /// The number of messages that were dropped.
+    pub purged_count: u64,
+    /// The sequence number of the last message ever queued for the client.
+    /// Subsequent fetches start after it.
+    pub high_water_mark: u64,
+}
+
+/// The DS's view of a registered client.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ClientInfo {
+    pub client_id: DsClientId,
+    /// Registration time in milliseconds since the UNIX epoch.
+    pub registered_at: u64,
+    pub key_package_counts: KeyPackageCounts,
+    pub group_ids: Vec<DsGroupId>,
+}
+
+/// The number of key packages the DS stores for a client, by type.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, TlsSize, TlsSerialize, TlsDeserializeBytes,
+)]
+pub struct KeyPackageCounts {
+    /// Key packages that are handed out once.
+    pub one_time: u32,
+    /// Last resort key packages, handed out once no one-time key packages
+    /// are left.
+    pub last_resort: u32,
+}
+
+/// A reference to a ratchet tree stored by the DS for the given group and
+/// epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct GroupInfoRef {
+    pub group_id: DsGroupId,
+    pub epoch: u64,
+}
+
+/// Identifies a distributed message by the SHA-256 hash of its
+/// serialization, so that its sender can refer to the message queued for the
+/// recipients, e.g. to query delivery receipts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct MessageRef {
+    hash: [u8; 32],
+}
+
+impl MessageRef {
+    /// The reference of the serialized MLS message in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            hash: Sha256::digest(bytes).into(),
+        }
+    }
+
+    /// The reference of `message`, as distributed to the recipients.
+    pub fn for_message(message: &MlsMessageOut) -> Result<Self, tls_codec::Error> {
+        Ok(Self::from_bytes(&message.tls_serialize_detached()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.hash
+    }
+}
+
+/// Tells the DS that the requesting client processed the message with the
+/// given sequence number in its queue, which belongs to the given group.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct DeliveryReceiptRequest {
+    pub credentials: ClientCredentials,
+    pub group_id: DsGroupId,
+    pub sequence_number: u64,
+}
+
+/// Asks the DS for the delivery receipts of a message the requesting client
+/// sent to the given group.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchDeliveryReceiptsRequest {
+    pub credentials: ClientCredentials,
+    pub group_id: DsGroupId,
+    pub message_ref: MessageRef,
+}
+
+/// A recipient's confirmation that it processed a message.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct DeliveryReceipt {
+    pub client_id: DsClientId,
+    /// The time the DS received the receipt, in milliseconds since the UNIX
+    /// epoch.
+    pub received_at_ms: u64,
+}
+
+/// Returned instead of `Ok` when creating a group whose ID is already taken.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct GroupExistsResponse {
+    /// Whether the existing group was created by the requesting client.
+    pub owner_is_self: bool,
+    pub current_epoch: u64,
+}
+
+/// Asks the DS for the latest group info and ratchet tree of a group.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct FetchGroupInfoRequest {
+    pub credentials: ClientCredentials,
+    pub group_id: DsGroupId,
+}
+
+/// The group info and ratchet tree of a group, both TLS-serialized. They are
+/// kept serialized since `openmls` can't serialize incoming messages, see
+/// [`Self::group_info`] and [`Self::ratchet_tree`] to deserialize them.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct GroupInfoResponse {
+    pub group_info: Vec<u8>,
+    pub ratchet_tree: Vec<u8>,
+}
+
+impl GroupInfoResponse {
+    pub fn group_info(&self) -> Result<MlsMessageIn, tls_codec::Error> {
+        MlsMessageIn::tls_deserialize_exact_bytes(&self.group_info)
+    }
+
+    pub fn ratchet_tree(&self) -> Result<RatchetTreeIn, tls_codec::Error> {
+        RatchetTreeIn::tls_deserialize_exact_bytes(&self.ratchet_tree)
+    }
+}
+
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct MyGroupsRequest {
+    pub credentials: ClientCredentials,
+}
+
+/// The outcome of distributing a group message, reported by the DS.
+#[derive(Debug, Clone, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct DistributionReport {
+    /// The number of clients the message was enqueued for.
+    pub recipient_count: u32,
+    /// Recipients that were skipped because they are not registered with the
+    /// DS (anymore), e.g. because they deleted their client. Such clients can
+    /// be removed from the group.
+    pub unknown_recipients: Vec<DsClientId>,
+}
+
+/// Delivery priority of a group message. The DS delivers queued control
+/// messages before bulk messages, so that recipients process handshake
+/// messages before application messages of the resulting epoch.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Default, TlsSize, TlsSerialize, TlsDeserializeBytes,
+)]
+#[repr(u8)]
+pub enum MessagePriority {
+    /// Handshake messages, i.e. commits and proposals.
+    Control,
+    /// Application messages.
+    #[default]
+    Bulk,
+}
+
+impl MessagePriority {
+    /// The default priority of messages of the given kind.
+    pub fn for_kind(kind: MlsMessageKind) -> Self {
+        match kind {
+            MlsMessageKind::Commit | MlsMessageKind::Proposal => Self::Control,
+            _ => Self::Bulk,
+        }
+    }
+}
+
+/// Response to a registration or token rotation, carrying the issued auth
+/// token together with its lifetime.
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct RegistrationResponse {
+    pub token: AuthToken,
+    /// Expiry of the token in milliseconds since the UNIX epoch, if it
+    /// expires.
+    pub expires_at: Option<u64>,
+    /// The time of the DS when issuing the token, in milliseconds since the
+    /// UNIX epoch.
+    pub server_time: u64,
+}
+
+/// Response to a registration with a DS that assigns client IDs, carrying the
+/// authoritative client ID together with the issued auth token.
+#[derive(Debug, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct AssignedRegistrationResponse {
+    pub client_id: DsClientId,
+    pub registration: RegistrationResponse,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_tags_are_stable() {
+        let client_id = DsClientId::new(&[1; 16]).unwrap();
+        let token = AuthToken { token: [2; 32] };
+        let registration = || RegistrationResponse {
+            token,
+            expires_at: None,
+            server_time: 0,
+        };
+        let responses = [
+            (0, DsResponse::Ok),
+            (1, DsResponse::AuthToken(token)),
+            (2, DsResponse::KeyPackageOption(None)),
+            (
+                3,
+                DsResponse::FetchMessages(FetchMessagesResponse { messages: vec![] }),
+            ),
+            (
+                4,
+                DsResponse::ListClients(ListClientsResponse {
+                    client_ids: vec![client_id.clone()],
+                    total: 1,
+                }),
+            ),
+            (5, DsResponse::BatchResults(vec![BatchItemResult::Ok])),
+            (
+                6,
+                DsResponse::WhoAmI(ClientInfo {
+                    client_id: client_id.clone(),
+                    registered_at: 0,
+                    key_package_counts: KeyPackageCounts::default(),
+                    group_ids: vec![],
+                }),
+            ),
+            (7, DsResponse::UnknownGroupInfoRef),
+            (8, DsResponse::AlreadyRegistered),
+            (
+                9,
+                DsResponse::FetchMessagesWithTimestamps(FetchMessagesWithTimestampsResponse {
+                    messages: vec![],
+                }),
+            ),
+            (
+                10,
+                DsResponse::GroupExists(GroupExistsResponse {
+                    owner_is_self: true,
+                    current_epoch: 1,
+                }),
+            ),
+            (11, DsResponse::GroupIds(vec![DsGroupId::new()])),
+            (12, DsResponse::Persisted),
+            (
+                13,
+                DsResponse::Distributed(DistributionReport {
+                    recipient_count: 1,
+                    unknown_recipients: vec![],
+                }),
+            ),
+            (
+                14,
+                DsResponse::FetchMessagesWithToken(FetchMessagesWithTokenResponse {
+                    messages: vec![],
+                    continuation_token: None,
+                }),
+            ),
+            (
+                15,
+                DsResponse::FetchMessagesFiltered(FetchMessagesFilteredResponse {
+                    messages: vec![],
+                    last_scanned_sequence_number: 0,
+                }),
+            ),
+            (16, DsResponse::Registration(registration())),
+            (17, DsResponse::NotMarkedForDeletion),
+            (18, DsResponse::NotGroupMember),
+            (19, DsResponse::Capabilities(DsCapabilities::default())),
+            (
+                20,
+                DsResponse::QueuePurged(PurgeQueueResponse {
+                    purged_count: 0,
+                    high_water_mark: 0,
+                }),
+            ),
+            (21, DsResponse::QueueDepth(0)),
+            (
+                22,
+                DsResponse::AssignedRegistration(AssignedRegistrationResponse {
+                    client_id,
+                    registration: registration(),
+                }),
+            ),
+            (
+                23,
+                DsResponse::KeyPackageCounts(KeyPackageCounts::default()),
+            ),
+            (
+                24,
+                DsResponse::CanonicalEndpoint(b"https://ds.example".to_vec()),
+            ),
+            (25, DsResponse::GroupInfo(None)),
+            (26, DsResponse::StaleGroupInfo(1)),
+            (27, DsResponse::DeliveryReceipts(vec![])),
+            (28, DsResponse::PreconditionFailed(1)),
+            (
+                29,
+                DsResponse::ListGroups(ListGroupsResponse {
+                    group_ids: vec![],
+                    total: 0,
+                }),
+            ),
+            (30, DsResponse::GroupNotFound),
+        ];
+        assert_eq!(responses.len(), usize::from(DsResponse::MAX_TAG) + 1);
+        for (tag, response) in responses {
+            let bytes = response.tls_serialize_detached().unwrap();
+            assert_eq!(bytes[0], tag, "{}", response.variant_name());
+        }
+        assert!(DsResponse::tls_deserialize_exact_bytes(&[DsResponse::MAX_TAG + 1]).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! A snapshot of the full state of a registered client.
+
+use tls_codec::{TlsDeserializeBytes, TlsSerialize, TlsSize};
+
+use crate::{AuthToken, DsClientId, DsGroupId};
+
+/// The full state of a registered client, e.g. to back it up or to migrate it
+/// to another host. Unlike a session, this includes the tracked epochs, the
+/// deduplication window and the cursors of per-group fetches.
+///
+/// States are versioned via `version`, and importing a state with a version
+/// newer than [`ClientState::CURRENT_VERSION`] fails. Later versions only add
+/// optional fields that default to `None`, so that states exported via serde
+/// by older versions remain readable. The TLS encoding has no such defaults,
+/// so TLS-encoded states can only be decoded by a version of this crate with
+/// the same [`ClientState::CURRENT_VERSION`].
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct ClientState {
+    pub version: u16,
+    pub client_id: DsClientId,
+    pub auth_token: AuthToken,
+    pub last_seen_sequence_number: u64,
+    pub continuation_token: Option<Vec<u8>>,
+    /// The last known epoch of each group, if epoch tracking is enabled.
+    pub epochs: Option<Vec<GroupEpoch>>,
+    /// The deduplication window, if deduplication is enabled.
+    pub recent_sequence_numbers: Option<RecentSequenceNumbersState>,
+    /// The cursors of the groups whose messages were fetched per group.
+    /// Added in version 2.
+    pub group_cursors: Option<Vec<GroupCursor>>,
+}
+
+impl ClientState {
+    /// The version of states created by this version of the crate.
+    pub const CURRENT_VERSION: u16 = 2;
+}
+
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct GroupEpoch {
+    pub group_id: DsGroupId,
+    pub epoch: u64,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct GroupCursor {
+    pub group_id: DsGroupId,
+    /// The sequence number of the last message fetched for the group.
+    pub last_seen_sequence_number: u64,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    TlsSize,
+    TlsSerialize,
+    TlsDeserializeBytes,
+)]
+pub struct RecentSequenceNumbersState {
+    /// The size of the window.
+    pub window: u64,
+    /// The remembered sequence numbers, oldest first.
+    pub sequence_numbers: Vec<u64>,
+}
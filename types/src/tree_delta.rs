@@ -0,0 +1,199 @@
+// SPDX-FileCopyrightText: 2023 Phoenix R&D GmbH <hello@phnx.im>
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Deltas between serialized ratchet trees, so that updating the tree stored
+//! on the DS only requires uploading the nodes that changed.
+
+use tls_codec::{DeserializeBytes, Serialize, TlsDeserializeBytes, TlsSerialize, TlsSize, VLBytes};
+
+// Node types, leaf node sources and credential types as defined in RFC 9420.
+const NODE_TYPE_LEAF: u8 = 1;
+const NODE_TYPE_PARENT: u8 = 2;
+pub(crate) const LEAF_NODE_SOURCE_KEY_PACKAGE: u8 = 1;
+const LEAF_NODE_SOURCE_UPDATE: u8 = 2;
+const LEAF_NODE_SOURCE_COMMIT: u8 = 3;
+const CREDENTIAL_TYPE_BASIC: u16 = 1;
+const CREDENTIAL_TYPE_X509: u16 = 2;
+
+// The encoding of a blank node, i.e. an absent `optional<Node>`.
+const BLANK_NODE: &[u8] = &[0];
+
+/// The nodes of a ratchet tree that differ from those of a base tree. Both
+/// trees are handled in their serialized form, so that the DS can apply a
+/// delta without validating the tree.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct RatchetTreeDelta {
+    /// The number of nodes of the new tree.
+    pub node_count: u32,
+    pub changed_nodes: Vec<ChangedNode>,
+}
+
+/// A node of the new tree that differs from the node at the same index in the
+/// base tree, or that lies beyond the end of the base tree.
+#[derive(Debug, Clone, PartialEq, Eq, TlsSize, TlsSerialize, TlsDeserializeBytes)]
+pub struct ChangedNode {
+    pub index: u32,
+    /// The serialized `optional<Node>`.
+    pub node: VLBytes,
+}
+
+impl RatchetTreeDelta {
+    /// Compute the delta that turns the serialized ratchet tree `base` into
+    /// the serialized ratchet tree `new`.
+    pub fn diff(base: &[u8], new: &[u8]) -> Result<Self, tls_codec::Error> {
+        let base_nodes = split_nodes(base)?;
+        let new_nodes = split_nodes(new)?;
+        let changed_nodes = new_nodes
+            .iter()
+            .enumerate()
+            .filter(|(index, node)| base_nodes.get(*index) != Some(*node))
+            .map(|(index, node)| ChangedNode {
+                index: index as u32,
+                node: node.to_vec().into(),
+            })
+            .collect();
+        Ok(Self {
+            node_count: new_nodes.len() as u32,
+            changed_nodes,
+        })
+    }
+
+    /// Apply the delta to the serialized ratchet tree `base` and return the
+    /// serialized new tree. Fails if the new tree would be longer than both
+    /// the base tree and the changed nodes, or if it ends with a blank node,
+    /// which RFC 9420 forbids.
+    pub fn apply(&self, base: &[u8]) -> Result<Vec<u8>, tls_codec::Error> {
+        let mut nodes = split_nodes(base)?;
+        // A longer tree would only be padded with blank nodes. Checking this
+        // before resizing keeps a malicious `node_count` from causing huge
+        // allocations.
+        let max_node_count = self
+            .changed_nodes
+            .iter()
+            .map(|changed_node| changed_node.index as usize + 1)
+            .max()
+            .unwrap_or(0)
+            .max(nodes.len());
+        let node_count = self.node_count as usize;
+        if node_count > max_node_count {
+            return Err(tls_codec::Error::InvalidInput);
+        }
+        nodes.resize(node_count, BLANK_NODE);
+        for changed_node in &self.changed_nodes {
+            let node = nodes
+                .get_mut(changed_node.index as usize)
+                .ok_or(tls_codec::Error::InvalidInput)?;
+            *node = changed_node.node.as_slice();
+        }
+        if nodes.last() == Some(&BLANK_NODE) {
+            return Err(tls_codec::Error::InvalidInput);
+        }
+        VLBytes::from(nodes.concat()).tls_serialize_detached()
+    }
+}
+
+/// Split the serialized ratchet tree in `bytes` into its serialized nodes.
+fn split_nodes(bytes: &[u8]) -> Result<Vec<&[u8]>, tls_codec::Error> {
+    let (tree, rest) = VLBytes::tls_deserialize_bytes(bytes)?;
+    if !rest.is_empty() {
+        return Err(tls_codec::Error::TrailingData);
+    }
+    let mut nodes = Vec::new();
+    // Borrow from `bytes` rather than from the deserialized copy.
+    let mut remaining = &bytes[bytes.len() - tree.as_slice().len()..];
+    while !remaining.is_empty() {
+        let rest = skip_optional_node(remaining)?;
+        nodes.push(&remaining[..remaining.len() - rest.len()]);
+        remaining = rest;
+    }
+    Ok(nodes)
+}
+
+fn skip_optional_node(bytes: &[u8]) -> Result<&[u8], tls_codec::Error> {
+    let (present, rest) = u8::tls_deserialize_bytes(bytes)?;
+    match present {
+        0 => Ok(rest),
+        1 => skip_node(rest),
+        other => Err(tls_codec::Error::UnknownValue(other.into())),
+    }
+}
+
+fn skip_node(bytes: &[u8]) -> Result<&[u8], tls_codec::Error> {
+    let (node_type, rest) = u8::tls_deserialize_bytes(bytes)?;
+    match node_type {
+        NODE_TYPE_LEAF => skip_leaf_node(rest),
+        NODE_TYPE_PARENT => {
+            // Encryption key, parent hash and unmerged leaves.
+            skip_vectors(rest, 3)
+        }
+        other => Err(tls_codec::Error::UnknownValue(other.into())),
+    }
+}
+
+fn skip_leaf_node(bytes: &[u8]) -> Result<&[u8], tls_codec::Error> {
+    let rest = skip_leaf_node_prefix(bytes)?;
+    let (leaf_node_source, rest) = u8::tls_deserialize_bytes(rest)?;
+    let rest = match leaf_node_source {
+        // The lifetime, i.e. two `uint64`s.
+        LEAF_NODE_SOURCE_KEY_PACKAGE => rest.get(16..).ok_or(tls_codec::Error::EndOfStream)?,
+        LEAF_NODE_SOURCE_UPDATE => rest,
+        // The parent hash.
+        LEAF_NODE_SOURCE_COMMIT => skip_vectors(rest, 1)?,
+        other => return Err(tls_codec::Error::UnknownValue(other.into())),
+    };
+    // Extensions and signature.
+    skip_vectors(rest, 2)
+}
+
+/// Skip the fields of a leaf node that precede its leaf node source.
+pub(crate) fn skip_leaf_node_prefix(bytes: &[u8]) -> Result<&[u8], tls_codec::Error> {
+    // Encryption key and signature key.
+    let rest = skip_vectors(bytes, 2)?;
+    // Credential. Both credential types consist of a single vector.
+    let (credential_type, rest) = u16::tls_deserialize_bytes(rest)?;
+    let rest = match credential_type {
+        CREDENTIAL_TYPE_BASIC | CREDENTIAL_TYPE_X509 => skip_vectors(rest, 1)?,
+        other => return Err(tls_codec::Error::UnknownValue(other.into())),
+    };
+    // Capabilities: versions, cipher suites, extensions, proposals and
+    // credentials.
+    skip_vectors(rest, 5)
+}
+
+pub(crate) fn skip_vectors(mut bytes: &[u8], count: usize) -> Result<&[u8], tls_codec::Error> {
+    for _ in 0..count {
+        bytes = VLBytes::tls_deserialize_bytes(bytes)?.1;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_tree() -> Vec<u8> {
+        VLBytes::from(Vec::new()).tls_serialize_detached().unwrap()
+    }
+
+    #[test]
+    fn oversized_delta_is_rejected() {
+        let delta = RatchetTreeDelta {
+            node_count: u32::MAX,
+            changed_nodes: Vec::new(),
+        };
+        assert!(delta.apply(&empty_tree()).is_err());
+    }
+
+    #[test]
+    fn trailing_blank_node_is_rejected() {
+        let delta = RatchetTreeDelta {
+            node_count: 1,
+            changed_nodes: vec![ChangedNode {
+                index: 0,
+                node: BLANK_NODE.to_vec().into(),
+            }],
+        };
+        assert!(delta.apply(&empty_tree()).is_err());
+    }
+}